@@ -1,5 +1,12 @@
+use crate::{
+    Error,
+    default_jobs,
+    definition_paths,
+    parallel_map,
+};
 use serde::{Deserialize, Serialize};
 use std::{
+    env,
     fs,
     io,
     path::Path,
@@ -7,65 +14,200 @@ use std::{
     str,
 };
 
-#[derive(Deserialize, Serialize)]
+// Feature name as reported by /proc/cpuinfo, description, and instruction set it enables
+static FEATURE_INFO: &[(&str, &str, &str)] = &[
+    ("3dnowprefetch", "Prefetch for write hints", "PREFETCHW"),
+    ("adx", "Multi-precision add-carry instructions", "ADX"),
+    ("aes", "Hardware AES encryption and decryption", "AES-NI"),
+    ("avx", "256-bit floating point vector operations", "AVX"),
+    ("avx2", "256-bit integer vector operations", "AVX2"),
+    ("avx512bw", "512-bit byte and word vector operations", "AVX-512 BW"),
+    ("avx512cd", "512-bit conflict detection", "AVX-512 CD"),
+    ("avx512dq", "512-bit doubleword and quadword vector operations", "AVX-512 DQ"),
+    ("avx512f", "512-bit vector foundation", "AVX-512 F"),
+    ("avx512vl", "AVX-512 operations on 128-bit and 256-bit vectors", "AVX-512 VL"),
+    ("bmi1", "Bit manipulation, such as trailing zero count", "BMI1"),
+    ("bmi2", "Advanced bit manipulation, such as parallel bit deposit", "BMI2"),
+    ("clflushopt", "Optimized cache line flush", "CLFLUSHOPT"),
+    ("f16c", "Half precision floating point conversion", "F16C"),
+    ("fma", "Fused multiply-add of floating point values", "FMA3"),
+    ("fsgsbase", "User mode access to FS and GS base registers", "FSGSBASE"),
+    ("mmx", "64-bit integer vector operations", "MMX"),
+    ("movbe", "Load and store with byte swap", "MOVBE"),
+    ("pclmulqdq", "Carry-less multiplication, used by CRC and GCM", "PCLMULQDQ"),
+    ("pni", "Horizontal and complex floating point operations", "SSE3"),
+    ("popcnt", "Population count of set bits", "POPCNT"),
+    ("rdrand", "Hardware random number generator", "RDRAND"),
+    ("rdseed", "Hardware random seed generator", "RDSEED"),
+    ("sse", "128-bit single precision floating point vector operations", "SSE"),
+    ("sse2", "128-bit integer and double precision vector operations", "SSE2"),
+    ("sse4_1", "Blending, rounding, and dot product vector operations", "SSE4.1"),
+    ("sse4_2", "String comparison and CRC32 instructions", "SSE4.2"),
+    ("ssse3", "Shuffle, absolute value, and horizontal integer operations", "SSSE3"),
+    ("xsavec", "Compacted saving of processor state", "XSAVEC"),
+    ("xsaves", "Supervisor saving of processor state", "XSAVES"),
+    // aarch64, as reported in the Features line
+    ("asimd", "128-bit integer and floating point vector operations", "Advanced SIMD"),
+    ("asimddp", "Vector dot product of 8-bit integers", "DotProd"),
+    ("asimdhp", "Half precision vector arithmetic", "FP16"),
+    ("asimdrdm", "Rounding doubling multiply accumulate", "RDM"),
+    ("atomics", "Large system atomic operations", "LSE"),
+    ("bf16", "Brain floating point operations", "BF16"),
+    ("crc32", "CRC32 instructions", "CRC32"),
+    ("fp", "Scalar floating point operations", "FP"),
+    ("i8mm", "8-bit integer matrix multiplication", "I8MM"),
+    ("lrcpc", "Release consistent processor consistent loads", "RCPC"),
+    ("pmull", "Polynomial multiplication, used by GCM", "PMULL"),
+    ("sha1", "Hardware SHA-1 hashing", "SHA1"),
+    ("sha2", "Hardware SHA-256 hashing", "SHA2"),
+    ("sha512", "Hardware SHA-512 hashing", "SHA512"),
+    ("sve", "Scalable vector operations", "SVE"),
+    ("sve2", "Scalable vector operations, second version", "SVE2"),
+];
+
+pub fn feature_info(feature: &str) -> Option<(&'static str, &'static str)> {
+    FEATURE_INFO.iter()
+        .find(|x| x.0 == feature)
+        .map(|x| (x.1, x.2))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Arch {
+    // Ordering of the archs by capability, used in the version suffix and to select the highest
     pub level: u32,
     pub name: String,
     pub wiki: String,
     pub features: Vec<String>,
+    // Overrides name for the gcc -march and -mtune flags
+    #[serde(default)]
+    pub gcc_march: Option<String>,
+    // Overrides name for the rustc target-cpu codegen option
+    #[serde(default)]
+    pub rust_target_cpu: Option<String>,
+    // Appended to the flags of this level, such as further tuning options
+    #[serde(default)]
+    pub extra_cflags: Vec<String>,
+    #[serde(default)]
+    pub extra_cxxflags: Vec<String>,
+    #[serde(default)]
+    pub extra_rustflags: Vec<String>,
+    // Machine architecture family, from the directory the definition was loaded from
+    #[serde(skip)]
+    pub family: String,
+}
+
+// Machine architecture family of the host, such as x86_64 or aarch64
+pub fn host_family() -> &'static str {
+    env::consts::ARCH
 }
 
 impl Arch {
-    pub fn load<P: AsRef<Path>>(p: P) -> io::Result<Self> {
-        let data = fs::read_to_string(p)?;
-        toml::from_str(&data).map_err(|err| io::Error::new(
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self, Error> {
+        let data = fs::read_to_string(&p)?;
+        let mut arch: Self = toml::from_str(&data).map_err(|err| io::Error::new(
             io::ErrorKind::InvalidData,
             err,
-        ))
+        ))?;
+        arch.family = p.as_ref().parent()
+            .and_then(|x| x.file_name())
+            .map_or_else(String::new, |x| x.to_string_lossy().to_string());
+        Ok(arch)
     }
 
-    pub fn load_all<P: AsRef<Path>>(p: P) -> io::Result<Vec<Self>> {
-        let mut entries = Vec::new();
-        for entry_res in fs::read_dir(p)? {
-            entries.push(entry_res?.path());
-        }
-        entries.sort();
+    pub fn load_all<P: AsRef<Path>>(p: P) -> Result<Vec<Self>, Error> {
+        let entries = definition_paths(p, "toml", false)?;
+
+        // Parsing is done in parallel, but results keep the sorted path order
+        parallel_map(&entries, default_jobs(), |entry| Self::load(entry)).into_iter().collect()
+    }
+
+    pub fn gcc_march(&self) -> &str {
+        self.gcc_march.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn rust_target_cpu(&self) -> &str {
+        self.rust_target_cpu.as_deref().unwrap_or(&self.name)
+    }
 
-        let mut archs = Vec::new();
-        for entry in entries.iter() {
-            archs.push(Self::load(entry)?);
+    // On aarch64, -march takes architecture versions, such as armv8.2-a, which -mtune does not accept
+    fn gcc_flags(&self) -> Vec<String> {
+        if self.family == "aarch64" {
+            vec![
+                format!("-march={}", self.gcc_march()),
+                format!("-O3"),
+            ]
+        } else {
+            vec![
+                format!("-march={}", self.gcc_march()),
+                format!("-mtune={}", self.gcc_march()),
+                format!("-O3"),
+            ]
         }
-        Ok(archs)
     }
 
     pub fn cflags(&self) -> Vec<String> {
-        vec![
-            format!("-march={}", self.name),
-            format!("-mtune={}", self.name),
-            format!("-O3"),
-        ]
+        let mut flags = self.gcc_flags();
+        flags.extend(self.extra_cflags.iter().cloned());
+        flags
     }
 
+    // Kept separate from cflags so C++ only flags, such as -faligned-new, do not reach C builds
     pub fn cxxflags(&self) -> Vec<String> {
-        vec![
-            format!("-march={}", self.name),
-            format!("-mtune={}", self.name),
-            format!("-O3"),
-        ]
+        let mut flags = self.gcc_flags();
+        flags.extend(self.extra_cxxflags.iter().cloned());
+        flags
     }
 
     pub fn rustflags(&self) -> Vec<String> {
-        vec![
-            format!("--codegen"), format!("target-cpu={}", self.name),
+        let mut flags = vec![
+            format!("--codegen"), format!("target-cpu={}", self.rust_target_cpu()),
             format!("--codegen"), format!("opt-level=3"),
-        ]
+        ];
+        flags.extend(self.extra_rustflags.iter().cloned());
+        flags
+    }
+
+    // Rust flags with target features removed, also disabling them where implied by target-cpu
+    pub fn rustflags_without(&self, remove: &[String]) -> Vec<String> {
+        let flags = self.rustflags();
+        if remove.is_empty() {
+            return flags;
+        }
+
+        let removed = |feature: &str| {
+            let name = feature.trim_start_matches(['+', '-']);
+            remove.iter().any(|x| x == name)
+        };
+
+        let mut filtered: Vec<String> = Vec::new();
+        for flag in flags {
+            if let Some(features) = flag.strip_prefix("target-feature=") {
+                let kept: Vec<&str> = features.split(',').filter(|x| ! removed(x)).collect();
+                if kept.is_empty() {
+                    // Drop the preceding --codegen as well
+                    filtered.pop();
+                } else {
+                    filtered.push(format!("target-feature={}", kept.join(",")));
+                }
+            } else {
+                filtered.push(flag);
+            }
+        }
+
+        let disabled: Vec<String> = remove.iter().map(|x| format!("-{}", x)).collect();
+        filtered.push("--codegen".to_string());
+        filtered.push(format!("target-feature={}", disabled.join(",")));
+        filtered
     }
 
-    pub fn cpu_features() -> io::Result<Vec<String>> {
+    pub fn cpu_features() -> Result<Vec<String>, Error> {
+        // Features are listed as flags on x86 and as Features on aarch64
+        let field = if env::consts::ARCH == "aarch64" { "Features" } else { "flags" };
+
         //TODO: smarter check for features
         let output = process::Command::new("bash")
             .arg("-c")
-            .arg("grep '^flags' /proc/cpuinfo | head -n 1 | sed 's/^flags.*: //'")
+            .arg(format!("grep '^{0}' /proc/cpuinfo | head -n 1 | sed 's/^{0}.*: //'", field))
             .output()?;
         let stdout = str::from_utf8(&output.stdout).map_err(|err| io::Error::new(
             io::ErrorKind::InvalidData,
@@ -79,13 +221,283 @@ impl Arch {
         )
     }
 
-    pub fn check_features(&self, cpu_features: &[String]) -> Result<(), Vec<String>> {
+    // One line per required feature with its description and instruction set
+    pub fn explain(&self) -> Vec<String> {
+        self.features.iter().map(|feature| match feature_info(feature) {
+            Some((description, instruction_set)) => format!(
+                "{}: {} ({})", feature, description, instruction_set
+            ),
+            None => format!("{}: no description available", feature),
+        }).collect()
+    }
+
+    // Treats features as present or absent regardless of what the CPU reports
+    pub fn override_features(mut cpu_features: Vec<String>, present: &[String], absent: &[String]) -> Vec<String> {
+        for feature in present.iter() {
+            if ! cpu_features.contains(feature) {
+                cpu_features.push(feature.clone());
+            }
+        }
+        cpu_features.retain(|x| ! absent.contains(x));
+        cpu_features
+    }
+
+    pub fn check_features(&self, cpu_features: &[String]) -> Result<(), Error> {
         let mut missing = self.features.clone();
         missing.retain(|x| !cpu_features.contains(x));
         if missing.is_empty() {
             Ok(())
         } else {
-            Err(missing)
+            Err(Error::MissingFeatures(missing))
+        }
+    }
+}
+
+// Arch selected for the host, with its flags, for use by build scripts
+#[derive(Clone, Debug)]
+pub struct DetectedArch {
+    pub arch: Arch,
+    pub cflags: Vec<String>,
+    pub cxxflags: Vec<String>,
+    pub rustflags: Vec<String>,
+    // Features reported by the CPU
+    pub features: Vec<String>,
+}
+
+impl DetectedArch {
+    // Highest level of the archs that the features support
+    pub fn from_features(archs: Vec<Arch>, features: Vec<String>) -> Option<Self> {
+        let arch = archs.into_iter()
+            .filter(|x| x.check_features(&features).is_ok())
+            .max_by_key(|x| x.level)?;
+        Some(Self {
+            cflags: arch.cflags(),
+            cxxflags: arch.cxxflags(),
+            rustflags: arch.rustflags(),
+            arch,
+            features,
+        })
+    }
+}
+
+// Detects the highest arch supported by the host, from the definitions in a directory
+pub fn detect_from<P: AsRef<Path>>(arch_dir: P) -> Result<DetectedArch, Error> {
+    let features = Arch::cpu_features()?;
+    DetectedArch::from_features(Arch::load_all(arch_dir)?, features).ok_or_else(|| Error::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        "no optimization level found"
+    )))
+}
+
+pub fn detect() -> Result<DetectedArch, Error> {
+    detect_from(Path::new("arch").join(host_family()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(flags: &str) -> Vec<String> {
+        flags.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    fn arch(level: u32, name: &str, family: &str) -> Arch {
+        Arch {
+            level,
+            name: name.to_string(),
+            wiki: String::new(),
+            features: Vec::new(),
+            gcc_march: None,
+            rust_target_cpu: None,
+            extra_cflags: Vec::new(),
+            extra_cxxflags: Vec::new(),
+            extra_rustflags: Vec::new(),
+            family: family.to_string(),
+        }
+    }
+
+    // The definitions shipped in arch/x86_64
+    fn x86_64_archs() -> Vec<Arch> {
+        Arch::load_all(Path::new(env!("CARGO_MANIFEST_DIR")).join("arch/x86_64")).unwrap()
+    }
+
+    #[test]
+    fn target_names_default_to_name() {
+        let archs = x86_64_archs();
+        assert!(! archs.is_empty());
+        for arch in archs.iter() {
+            assert_eq!(arch.family, "x86_64");
+            assert_eq!(arch.cflags(), vec![
+                format!("-march={}", arch.name),
+                format!("-mtune={}", arch.name),
+                "-O3".to_string(),
+            ], "{}", arch.name);
+            assert_eq!(arch.rustflags(), vec![
+                "--codegen".to_string(), format!("target-cpu={}", arch.name),
+                "--codegen".to_string(), "opt-level=3".to_string(),
+            ], "{}", arch.name);
+        }
+    }
+
+    #[test]
+    fn target_names_overridden() {
+        let mut arch = arch(4, "haswell", "x86_64");
+        arch.gcc_march = Some("x86-64-v3".to_string());
+        arch.rust_target_cpu = Some("x86-64-v3".to_string());
+        assert_eq!(arch.cflags(), flags("-march=x86-64-v3 -mtune=x86-64-v3 -O3"));
+        assert_eq!(arch.rustflags(), flags("--codegen target-cpu=x86-64-v3 --codegen opt-level=3"));
+
+        // Each override applies to its own compiler only
+        arch.rust_target_cpu = None;
+        assert_eq!(arch.rustflags(), flags("--codegen target-cpu=haswell --codegen opt-level=3"));
+    }
+
+    #[test]
+    fn target_names_aarch64() {
+        let mut arch = arch(2, "neoverse-n1", "aarch64");
+        arch.gcc_march = Some("armv8.2-a".to_string());
+        // -mtune is not given, as it does not accept architecture versions
+        assert_eq!(arch.cflags(), flags("-march=armv8.2-a -O3"));
+        assert_eq!(arch.rustflags(), flags("--codegen target-cpu=neoverse-n1 --codegen opt-level=3"));
+    }
+
+    fn remove(features: &[&str]) -> Vec<String> {
+        features.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn rustflags_without_removed_feature() {
+        let mut arch = arch(4, "haswell", "x86_64");
+        arch.extra_rustflags = flags("--codegen target-feature=+avx2,+fma");
+        assert_eq!(arch.rustflags_without(&remove(&["avx2"])), flags(
+            "--codegen target-cpu=haswell --codegen opt-level=3 \
+            --codegen target-feature=+fma --codegen target-feature=-avx2"
+        ));
+
+        // Emptied feature lists are dropped with their --codegen
+        arch.extra_rustflags = flags("--codegen target-feature=+avx2");
+        assert_eq!(arch.rustflags_without(&remove(&["avx2"])), flags(
+            "--codegen target-cpu=haswell --codegen opt-level=3 --codegen target-feature=-avx2"
+        ));
+    }
+
+    #[test]
+    fn rustflags_without_missing_feature() {
+        // Features implied by target-cpu are still disabled
+        let arch = arch(6, "skylake", "x86_64");
+        assert_eq!(arch.rustflags_without(&remove(&["avx512f", "avx2"])), flags(
+            "--codegen target-cpu=skylake --codegen opt-level=3 --codegen target-feature=-avx512f,-avx2"
+        ));
+        assert_eq!(arch.rustflags_without(&[]), arch.rustflags());
+    }
+
+    #[test]
+    fn rustflags_without_other_flags() {
+        // Only target-feature values are filtered, and names must match exactly
+        let mut arch = arch(4, "haswell", "x86_64");
+        arch.extra_rustflags = flags("--codegen link-arg=-Wl,-z,avx2 --codegen target-feature=-avx2,+avx512f,+fma");
+        assert_eq!(arch.rustflags_without(&remove(&["avx2", "avx512"])), flags(
+            "--codegen target-cpu=haswell --codegen opt-level=3 --codegen link-arg=-Wl,-z,avx2 \
+            --codegen target-feature=+avx512f,+fma --codegen target-feature=-avx2,-avx512"
+        ));
+    }
+
+    #[test]
+    fn override_features_add() {
+        let features = Arch::override_features(flags("sse sse2 avx"), &flags("avx avx2"), &[]);
+        // Present features are added once
+        assert_eq!(features, flags("sse sse2 avx avx2"));
+    }
+
+    #[test]
+    fn override_features_remove() {
+        let features = Arch::override_features(flags("sse sse2 avx avx2"), &[], &flags("avx2 avx512f"));
+        assert_eq!(features, flags("sse sse2 avx"));
+    }
+
+    #[test]
+    fn override_features_absent_wins() {
+        // A feature given as both present and absent is treated as absent
+        let features = Arch::override_features(flags("sse sse2"), &flags("avx avx2"), &flags("avx2 sse2"));
+        assert_eq!(features, flags("sse avx"));
+    }
+
+    #[test]
+    fn from_features_each_level() {
+        let archs = x86_64_archs();
+        for arch in archs.iter() {
+            let detected = DetectedArch::from_features(archs.clone(), arch.features.clone()).unwrap();
+            assert_eq!(detected.arch.name, arch.name);
+            assert_eq!(detected.cflags, arch.cflags());
+            assert_eq!(detected.cxxflags, arch.cxxflags());
+            assert_eq!(detected.rustflags, arch.rustflags());
+            assert_eq!(detected.features, arch.features);
+        }
+    }
+
+    #[test]
+    fn from_features_highest_level() {
+        // Extra features do not matter, and the highest supported level wins whatever the order
+        let mut archs = x86_64_archs();
+        archs.reverse();
+        let mut features = archs.iter().find(|x| x.name == "haswell").unwrap().features.clone();
+        features.push("avx512f".to_string());
+        let detected = DetectedArch::from_features(archs, features).unwrap();
+        assert_eq!(detected.arch.name, "haswell");
+    }
+
+    #[test]
+    fn from_features_unsupported() {
+        assert!(DetectedArch::from_features(x86_64_archs(), flags("sse sse2")).is_none());
+        assert!(DetectedArch::from_features(Vec::new(), flags("sse sse2 avx avx2")).is_none());
+    }
+
+    #[test]
+    fn cxxflags_each_arch() {
+        for arch in x86_64_archs().iter() {
+            assert_eq!(arch.cxxflags(), vec![
+                format!("-march={}", arch.name),
+                format!("-mtune={}", arch.name),
+                "-O3".to_string(),
+            ], "{}", arch.name);
+            assert_eq!(arch.cxxflags(), arch.cflags(), "{}", arch.name);
+        }
+    }
+
+    #[test]
+    fn cxxflags_separate_from_cflags() {
+        let mut arch = arch(4, "haswell", "x86_64");
+        arch.extra_cxxflags = flags("-faligned-new");
+        assert_eq!(arch.cxxflags(), flags("-march=haswell -mtune=haswell -O3 -faligned-new"));
+        assert_eq!(arch.cflags(), flags("-march=haswell -mtune=haswell -O3"));
+    }
+
+    #[test]
+    fn extra_flags_appended() {
+        let mut arch: Arch = toml::from_str(r#"
+            level = 6
+            name = "skylake"
+            wiki = ""
+            features = ["avx2"]
+            extra_cflags = ["-fno-semantic-interposition"]
+            extra_cxxflags = ["-faligned-new"]
+            extra_rustflags = ["--codegen", "target-feature=+avx2"]
+        "#).unwrap();
+        arch.family = "x86_64".to_string();
+        assert_eq!(arch.cflags(), flags("-march=skylake -mtune=skylake -O3 -fno-semantic-interposition"));
+        assert_eq!(arch.cxxflags(), flags("-march=skylake -mtune=skylake -O3 -faligned-new"));
+        assert_eq!(arch.rustflags(), flags(
+            "--codegen target-cpu=skylake --codegen opt-level=3 --codegen target-feature=+avx2"
+        ));
+    }
+
+    #[test]
+    fn extra_flags_default_empty() {
+        // The shipped definitions have no extra flags
+        for arch in x86_64_archs().iter() {
+            assert!(arch.extra_cflags.is_empty() && arch.extra_cxxflags.is_empty() && arch.extra_rustflags.is_empty());
+            assert_eq!(arch.cflags().len(), 3, "{}", arch.name);
+            assert_eq!(arch.rustflags().len(), 4, "{}", arch.name);
         }
     }
 }
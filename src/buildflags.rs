@@ -0,0 +1,121 @@
+use crate::status_err;
+use std::{
+    io,
+    process,
+    str,
+};
+
+// Flags where only the last occurrence takes effect, so ours must replace the baseline's
+fn flag_group(flag: &str) -> Option<&'static str> {
+    if flag.starts_with("-O") {
+        Some("-O")
+    } else if flag.starts_with("-march=") {
+        Some("-march=")
+    } else if flag.starts_with("-mtune=") {
+        Some("-mtune=")
+    } else if flag.starts_with("-mcpu=") {
+        Some("-mcpu=")
+    } else {
+        None
+    }
+}
+
+// Queries the distribution's default flags, such as CFLAGS, inside a chroot
+pub fn query(chroot: &str, name: &str) -> io::Result<Vec<String>> {
+    let output = process::Command::new("schroot")
+        .arg("--chroot").arg(chroot)
+        .arg("--directory").arg("/")
+        .arg("--")
+        .arg("dpkg-buildflags")
+        .arg("--get").arg(name)
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("schroot", output.status)?;
+    let stdout = str::from_utf8(&output.stdout).map_err(|err| io::Error::new(
+        io::ErrorKind::InvalidData,
+        err
+    ))?;
+    Ok(stdout.split_whitespace().map(|x| x.to_string()).collect())
+}
+
+// Merges our flags into the baseline, returning the merged flags and any conflicts found
+pub fn merge(baseline: &[String], ours: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut merged = Vec::new();
+    let mut conflicts = Vec::new();
+    for flag in baseline.iter() {
+        let replacement = flag_group(flag).and_then(|group| {
+            ours.iter().find(|x| x.starts_with(group))
+        });
+        match replacement {
+            Some(ours) => if ours != flag {
+                conflicts.push(format!("'{}' replaces '{}'", ours, flag));
+            },
+            None => merged.push(flag.clone()),
+        }
+    }
+    for flag in ours.iter() {
+        if ! merged.contains(flag) {
+            merged.push(flag.clone());
+        }
+    }
+
+    let fortify = merged.iter().any(|x| x.starts_with("-D_FORTIFY_SOURCE"));
+    let optimization = merged.iter().rev().find(|x| x.starts_with("-O"));
+    if fortify && optimization.is_none_or(|x| x == "-O0") {
+        conflicts.push("_FORTIFY_SOURCE requires optimization to be enabled".to_string());
+    }
+
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(flags: &str) -> Vec<String> {
+        flags.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    // CFLAGS of dpkg-buildflags on Ubuntu with hardening enabled
+    const BASELINE: &str = "-g -O2 -fstack-protector-strong -Wformat -Werror=format-security -D_FORTIFY_SOURCE=2";
+
+    #[test]
+    fn merge_append() {
+        let (merged, conflicts) = merge(&flags(BASELINE), &flags("-march=haswell -mtune=haswell"));
+        assert_eq!(merged, flags(
+            "-g -O2 -fstack-protector-strong -Wformat -Werror=format-security -D_FORTIFY_SOURCE=2 \
+            -march=haswell -mtune=haswell"
+        ));
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_override() {
+        let (merged, conflicts) = merge(&flags("-g -O2 -march=x86-64 -fPIC"), &flags("-O3 -march=haswell"));
+        // Replaced flags are dropped from the baseline, and ours follow it
+        assert_eq!(merged, flags("-g -fPIC -O3 -march=haswell"));
+        assert_eq!(conflicts, vec![
+            "'-O3' replaces '-O2'".to_string(),
+            "'-march=haswell' replaces '-march=x86-64'".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn merge_dedup() {
+        let (merged, conflicts) = merge(&flags("-g -O2 -Wformat"), &flags("-Wformat -O2 -march=haswell -march=haswell"));
+        // Equal flags are not conflicts, and each flag appears once in first-seen order
+        assert_eq!(merged, flags("-g -Wformat -O2 -march=haswell"));
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merge_fortify_without_optimization() {
+        let (merged, conflicts) = merge(&flags(BASELINE), &flags("-O0"));
+        assert_eq!(merged.iter().filter(|x| x.starts_with("-O")).collect::<Vec<_>>(), vec!["-O0"]);
+        assert_eq!(conflicts, vec![
+            "'-O0' replaces '-O2'".to_string(),
+            "_FORTIFY_SOURCE requires optimization to be enabled".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,67 @@
+// Parser for Debian control files, such as .dsc, .changes, Packages, and Release
+
+#[derive(Clone, Debug, Default)]
+pub struct Paragraph {
+    // Fields in file order, continuation lines are joined with newlines
+    pub fields: Vec<(String, String)>,
+}
+
+impl Paragraph {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Non-empty lines of a multiline field, such as Files or Checksums-Sha256
+    pub fn lines(&self, key: &str) -> Vec<&str> {
+        self.get(key).map_or_else(Vec::new, |value| {
+            value.lines()
+                .map(|x| x.trim())
+                .filter(|x| ! x.is_empty())
+                .collect()
+        })
+    }
+}
+
+pub fn parse(data: &str) -> Vec<Paragraph> {
+    let mut paragraphs = Vec::new();
+    let mut paragraph = Paragraph::default();
+    let mut lines = data.lines();
+    while let Some(line) = lines.next() {
+        // Skip the armor of clearsigned files
+        if line.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+            for header in lines.by_ref() {
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            continue;
+        } else if line.starts_with("-----BEGIN PGP SIGNATURE-----") {
+            break;
+        }
+
+        if line.trim().is_empty() {
+            if ! paragraph.fields.is_empty() {
+                paragraphs.push(paragraph);
+                paragraph = Paragraph::default();
+            }
+        } else if line.starts_with('#') {
+            continue;
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = paragraph.fields.last_mut() {
+                value.push('\n');
+                value.push_str(&line[1..]);
+            }
+        } else if let Some(colon) = line.find(':') {
+            paragraph.fields.push((
+                line[..colon].trim().to_string(),
+                line[colon + 1..].trim().to_string()
+            ));
+        }
+    }
+    if ! paragraph.fields.is_empty() {
+        paragraphs.push(paragraph);
+    }
+    paragraphs
+}
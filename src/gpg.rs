@@ -0,0 +1,140 @@
+use crate::{
+    RunCommand,
+    status_err,
+};
+use std::{
+    io,
+    path::Path,
+    process,
+    str,
+};
+
+fn gpg() -> process::Command {
+    let mut command = process::Command::new("gpg");
+    command.arg("--batch").arg("--with-colons");
+    command
+}
+
+fn output(command: &mut process::Command) -> io::Result<String> {
+    let output = command
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("gpg", output.status)?;
+    str::from_utf8(&output.stdout).map(|x| x.to_string()).map_err(|err| io::Error::new(
+        io::ErrorKind::InvalidData,
+        err
+    ))
+}
+
+// Primary key fingerprints from gpg --with-colons output, ignoring subkeys
+pub fn parse_fingerprints(colons: &str) -> Vec<String> {
+    let mut fingerprints = Vec::new();
+    let mut primary = false;
+    for line in colons.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" | "sec" => primary = true,
+            "sub" | "ssb" => primary = false,
+            "fpr" if primary => {
+                if let Some(fingerprint) = fields.get(9) {
+                    fingerprints.push(fingerprint.to_string());
+                }
+                primary = false;
+            },
+            _ => (),
+        }
+    }
+    fingerprints
+}
+
+pub fn secret_fingerprints() -> io::Result<Vec<String>> {
+    output(gpg().arg("--list-secret-keys")).map(|x| parse_fingerprints(&x))
+}
+
+pub fn key_file_fingerprints<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    output(
+        gpg()
+            .arg("--import-options").arg("show-only")
+            .arg("--import").arg(path.as_ref())
+    ).map(|x| parse_fingerprints(&x))
+}
+
+// The first recorded fingerprint that has a secret key available
+pub fn select_key(recorded: &[String], available: &[String]) -> Option<String> {
+    recorded.iter()
+        .find(|x| available.iter().any(|y| y.eq_ignore_ascii_case(x)))
+        .cloned()
+}
+
+// Imports keys, including secret keys, from an armored or binary key file
+pub fn import_key<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    process::Command::new("gpg")
+        .arg("--batch")
+        .arg("--import").arg(path.as_ref())
+        .run()
+}
+
+pub fn export_key<P: AsRef<Path>>(fingerprint: &str, path: P) -> io::Result<()> {
+    process::Command::new("gpg")
+        .arg("--batch").arg("--yes")
+        .arg("--armor")
+        .arg("--output").arg(path.as_ref())
+        .arg("--export").arg(fingerprint)
+        .run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // gpg --batch --with-colons --list-secret-keys, with a signing subkey
+    const SECRET_KEYS: &str = "\
+sec:u:4096:1:0123456789ABCDEF:1600000000:::u:::scESC:::+:::23::0:
+fpr:::::::::AAAAAAAAAAAAAAAAAAAAAAAA0123456789ABCDEF:
+grp:::::::::1111111111111111111111111111111111111111:
+uid:u::::1600000000::2222222222222222222222222222222222222222::Pop!_OS Opt <opt@example.com>::::::::::0:
+ssb:u:4096:1:FEDCBA9876543210:1600000000::::::s:::+:::23:
+fpr:::::::::BBBBBBBBBBBBBBBBBBBBBBBBFEDCBA9876543210:
+grp:::::::::3333333333333333333333333333333333333333:
+sec:u:255:22:1122334455667788:1700000000:::u:::scESC:::+:::ed25519:::0:
+fpr:::::::::CCCCCCCCCCCCCCCCCCCCCCCC1122334455667788:
+";
+
+    #[test]
+    fn parse_fingerprints_primary_only() {
+        assert_eq!(parse_fingerprints(SECRET_KEYS), vec![
+            "AAAAAAAAAAAAAAAAAAAAAAAA0123456789ABCDEF".to_string(),
+            "CCCCCCCCCCCCCCCCCCCCCCCC1122334455667788".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn parse_fingerprints_empty() {
+        assert!(parse_fingerprints("").is_empty());
+        // A subkey fingerprint without a primary key before it is ignored
+        assert!(parse_fingerprints("ssb:u:4096:1:FEDCBA9876543210:::::\nfpr:::::::::BBBB:\n").is_empty());
+    }
+
+    #[test]
+    fn select_key_first_available() {
+        let available = parse_fingerprints(SECRET_KEYS);
+        let recorded = vec![
+            "DDDDDDDDDDDDDDDDDDDDDDDD9999999999999999".to_string(),
+            "cccccccccccccccccccccccc1122334455667788".to_string(),
+            "AAAAAAAAAAAAAAAAAAAAAAAA0123456789ABCDEF".to_string(),
+        ];
+        // Recorded order wins, and fingerprints compare without case
+        assert_eq!(
+            select_key(&recorded, &available),
+            Some("cccccccccccccccccccccccc1122334455667788".to_string())
+        );
+    }
+
+    #[test]
+    fn select_key_none_available() {
+        let recorded = vec!["DDDDDDDDDDDDDDDDDDDDDDDD9999999999999999".to_string()];
+        assert_eq!(select_key(&recorded, &parse_fingerprints(SECRET_KEYS)), None);
+        assert_eq!(select_key(&[], &parse_fingerprints(SECRET_KEYS)), None);
+    }
+}
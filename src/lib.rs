@@ -1,18 +1,154 @@
+use log::{Level, debug, log_enabled};
 use std::{
+    env,
+    error,
+    fmt,
     fs,
     io,
     path,
     process,
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
 };
 
-pub use self::arch::Arch;
+pub use self::arch::{Arch, DetectedArch, detect, detect_from, feature_info, host_family};
 mod arch;
 
-pub use self::pkg::Pkg;
+pub mod buildflags;
+
+pub mod control;
+
+pub mod gpg;
+
+pub mod manifest;
+
+pub use self::pkg::{BuildConfig, BuildEvent, BuildOptions, DEFAULT_MIRROR, DEFAULT_VERSION_SUFFIX, Patch, PatchMode, Pkg, SHARE_PARENT_DIR, SOURCE_CACHE_DIR, SbuildResult, ShareDir, StartedBuild, is_mirror_url, is_snapshot_timestamp, partial_dir, version_suffix};
 mod pkg;
 
+pub mod pdiff;
+
+pub mod progress;
+pub use self::progress::Progress;
+
+pub mod translation;
+
+pub mod version;
+
+// Errors with enough structure to be handled by library users. Converts to and from io::Error,
+// so io::Result call sites can use ? on either
+#[derive(Debug)]
+pub enum Error {
+    // A command exited unsuccessfully
+    CommandFailed {
+        command: String,
+        status: process::ExitStatus,
+    },
+    // A source package's DSC file was not found after downloading or building it
+    MissingDsc(path::PathBuf),
+    // The archive returned a different source package than requested
+    SourceMismatch {
+        requested: String,
+        found: String,
+    },
+    // Features an arch requires that the CPU does not report
+    MissingFeatures(Vec<String>),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CommandFailed { command, status } => write!(f, "{} exited with status {}", command, status),
+            Error::MissingDsc(path) => write!(f, "failed to find DSC file '{}'", path.display()),
+            Error::SourceMismatch { requested, found } => write!(
+                f, "requested source '{}' does not match source '{}'", requested, found
+            ),
+            Error::MissingFeatures(features) => write!(f, "missing features: {}", features.join(", ")),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    // Recovers an Error that was converted to an io::Error
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|x| x.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::MissingDsc(_) => io::Error::new(io::ErrorKind::NotFound, err),
+            _ => io::Error::other(err),
+        }
+    }
+}
+
+// Commands that change state are printed instead of run, while queries still run
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::SeqCst);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::SeqCst)
+}
+
+// Standard output for commands, discarded with interim output while errors still reach stderr
+pub fn command_stdout() -> process::Stdio {
+    if log_enabled!(Level::Info) {
+        process::Stdio::inherit()
+    } else {
+        process::Stdio::null()
+    }
+}
+
+pub trait RunCommand {
+    // Runs the command, failing unless it exits successfully. Only printed in dry-run mode
+    fn run(&mut self) -> io::Result<()>;
+}
+
+impl RunCommand for process::Command {
+    fn run(&mut self) -> io::Result<()> {
+        if dry_run() {
+            match self.get_current_dir() {
+                Some(dir) => println!("- would run in {}: {:?}", dir.display(), self),
+                None => println!("- would run: {:?}", self),
+            }
+            return Ok(());
+        }
+        debug!(target: "pop_opt::command", "- running {:?}", self);
+        let status = self.status()?;
+        status_err(&self.get_program().to_string_lossy(), status).map_err(io::Error::from)
+    }
+}
+
 pub fn ensure_dir<P: AsRef<path::Path>>(path: P) -> io::Result<path::PathBuf> {
     if ! path.as_ref().is_dir() {
+        if dry_run() {
+            println!("- would create {}", path.as_ref().display());
+            return env::current_dir().map(|x| x.join(path));
+        }
         fs::create_dir_all(&path)?;
     }
     fs::canonicalize(&path)
@@ -20,18 +156,289 @@ pub fn ensure_dir<P: AsRef<path::Path>>(path: P) -> io::Result<path::PathBuf> {
 
 pub fn ensure_dir_clean<P: AsRef<path::Path>>(path: P) -> io::Result<path::PathBuf> {
     if path.as_ref().is_dir() {
+        if dry_run() {
+            println!("- would clean {}", path.as_ref().display());
+            return fs::canonicalize(&path);
+        }
         fs::remove_dir_all(&path)?;
     }
     ensure_dir(&path)
 }
 
-pub fn status_err(status: process::ExitStatus) -> io::Result<()> {
+pub fn write_file<P: AsRef<path::Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
+    if dry_run() {
+        println!("- would write {}", path.as_ref().display());
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
+
+// Hard links when possible, falling back to a copy, such as when crossing filesystems
+pub fn link_or_copy<P: AsRef<path::Path>, Q: AsRef<path::Path>>(src: P, dst: Q) -> io::Result<()> {
+    if fs::hard_link(&src, &dst).is_err() {
+        fs::copy(&src, &dst)?;
+    }
+    Ok(())
+}
+
+// Sorted definition files in a directory, skipping files without the expected extension
+pub fn definition_paths<P: AsRef<path::Path>>(dir: P, extension: &str, recursive: bool) -> io::Result<Vec<path::PathBuf>> {
+    fn walk(dir: &path::Path, extension: &str, recursive: bool, paths: &mut Vec<path::PathBuf>) -> io::Result<()> {
+        for entry_res in fs::read_dir(dir)? {
+            let path = entry_res?.path();
+            if path.is_dir() {
+                if recursive {
+                    walk(&path, extension, recursive, paths)?;
+                }
+            } else if path.extension() == Some(extension.as_ref()) {
+                paths.push(path);
+            } else {
+                debug!("skipping {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    let mut paths = Vec::new();
+    walk(dir.as_ref(), extension, recursive, &mut paths)?;
+    paths.sort();
+    Ok(paths)
+}
+
+// Parses shell-style patterns with *, ?, and [...] character classes, such as 'gst*'
+pub fn glob_patterns(patterns: &[String]) -> io::Result<Vec<glob::Pattern>> {
+    patterns.iter().map(|pattern| glob::Pattern::new(pattern).map_err(|err| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid pattern '{}': {}", pattern, err)
+    ))).collect()
+}
+
+// Compares Debian versions with dpkg, where op is one of lt, le, eq, ne, ge, gt.
+// Falls back to a native comparison when dpkg is not installed
+pub fn compare_versions(a: &str, op: &str, b: &str) -> io::Result<bool> {
+    let native = || version::relation(a, op, b).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unknown version relation '{}'", op)
+    ));
+    if version::native() {
+        return native();
+    }
+
+    let status = match process::Command::new("dpkg")
+        .arg("--compare-versions")
+        .arg(a)
+        .arg(op)
+        .arg(b)
+        .status()
+    {
+        Ok(status) => status,
+        // Hosts without dpkg, such as build orchestrators on other distributions
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return native(),
+        Err(err) => return Err(err),
+    };
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(io::Error::other(
+            format!("failed to compare versions '{}' {} '{}': exited with status {}", a, op, b, status)
+        )),
+    }
+}
+
+// Two space indented JSON with a trailing newline, as written to files and printed
+pub fn to_json<T: serde::Serialize>(value: &T) -> io::Result<String> {
+    let mut json = serde_json::to_string_pretty(value)?;
+    json.push('\n');
+    Ok(json)
+}
+
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |x| x.get())
+}
+
+// Maps items on up to `jobs` threads, returning results in the same order as the items
+pub fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+    where T: Sync, R: Send, F: Fn(&T) -> R + Sync
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<R>>> = items.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() {
+                    break;
+                }
+                let result = f(&items[i]);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+    results.into_iter()
+        .map(|x| x.into_inner().unwrap().expect("parallel_map item not processed"))
+        .collect()
+}
+
+// Bounded pool of job slots shared by every source preparation and sbuild, so work from all
+// packages and archs runs as soon as a slot is free
+#[derive(Clone, Debug)]
+pub struct Jobs(Arc<(Mutex<usize>, Condvar)>);
+
+impl Jobs {
+    pub fn new(jobs: usize) -> Self {
+        Self(Arc::new((Mutex::new(jobs.max(1)), Condvar::new())))
+    }
+
+    // Waits for a free slot, which is released when the returned slot is dropped
+    pub fn acquire(&self) -> JobSlot {
+        let (available, condvar) = &*self.0;
+        let mut available = available.lock().unwrap();
+        while *available == 0 {
+            available = condvar.wait(available).unwrap();
+        }
+        *available -= 1;
+        JobSlot(self.clone())
+    }
+}
+
+pub struct JobSlot(Jobs);
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        let (available, condvar) = &*(self.0).0;
+        *available.lock().unwrap() += 1;
+        condvar.notify_one();
+    }
+}
+
+pub fn sha256<P: AsRef<path::Path>>(path: P) -> io::Result<String> {
+    let output = process::Command::new("sha256sum")
+        .arg(path.as_ref())
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("sha256sum", output.status)?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|x| x.to_string())
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("failed to hash '{}'", path.as_ref().display())
+        ))
+}
+
+pub fn status_err(command: &str, status: process::ExitStatus) -> Result<(), Error> {
     if status.success() {
         Ok(())
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("exited with status {}", status)
-        ))
+        Err(Error::CommandFailed {
+            command: command.to_string(),
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(dir: &path::Path, file: &str) {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn definition_paths_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "b.toml");
+        touch(dir.path(), "group/a.toml");
+        touch(dir.path(), "group/nested/c.toml");
+
+        let relative = |paths: Vec<path::PathBuf>| -> Vec<String> {
+            paths.iter()
+                .map(|x| x.strip_prefix(dir.path()).unwrap().display().to_string())
+                .collect()
+        };
+        assert_eq!(
+            relative(definition_paths(dir.path(), "toml", true).unwrap()),
+            vec!["b.toml", "group/a.toml", "group/nested/c.toml"]
+        );
+        assert_eq!(relative(definition_paths(dir.path(), "toml", false).unwrap()), vec!["b.toml"]);
+    }
+
+    #[test]
+    fn definition_paths_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        touch(dir.path(), "glibc.toml");
+        touch(dir.path(), "README.md");
+        touch(dir.path(), "glibc.toml.orig");
+        touch(dir.path(), "toml");
+
+        let paths = definition_paths(dir.path(), "toml", true).unwrap();
+        assert_eq!(paths, vec![dir.path().join("glibc.toml")]);
+    }
+
+    #[test]
+    fn definition_paths_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in ["zlib.toml", "gcc-9.toml", "a/x.toml", "glibc.toml", "Z.toml"].iter() {
+            touch(dir.path(), file);
+        }
+
+        let paths = definition_paths(dir.path(), "toml", true).unwrap();
+        let mut sorted = paths.clone();
+        sorted.sort();
+        assert_eq!(paths, sorted);
+        assert_eq!(paths.len(), 5);
+    }
+
+    fn glob_match(pattern: &str, name: &str) -> bool {
+        glob_patterns(&[pattern.to_string()]).unwrap()[0].matches(name)
+    }
+
+    #[test]
+    fn glob_match_star() {
+        assert!(glob_match("gst*", "gstreamer1.0"));
+        assert!(glob_match("gst*", "gst"));
+        assert!(glob_match("*-9", "gcc-9"));
+        assert!(glob_match("lib*ssl*", "libssl3"));
+        assert!(glob_match("*", "glibc"));
+        assert!(! glob_match("gst*", "libgstreamer"));
+    }
+
+    #[test]
+    fn glob_match_question() {
+        assert!(glob_match("gcc-?", "gcc-9"));
+        assert!(! glob_match("gcc-?", "gcc-10"));
+        assert!(! glob_match("gcc-?", "gcc-"));
+        assert!(glob_match("python3.??", "python3.10"));
+    }
+
+    #[test]
+    fn glob_match_class() {
+        assert!(glob_match("gcc-[89]", "gcc-9"));
+        assert!(! glob_match("gcc-[89]", "gcc-7"));
+        assert!(glob_match("gcc-[0-9]", "gcc-7"));
+        assert!(glob_match("gcc-[!89]", "gcc-7"));
+        assert!(! glob_match("gcc-[!89]", "gcc-8"));
+    }
+
+    #[test]
+    fn glob_match_anchored() {
+        // Patterns match whole names, not substrings
+        assert!(! glob_match("libc", "glibc"));
+        assert!(! glob_match("glib", "glibc"));
+        assert!(! glob_match("lib?", "glibc"));
+        assert!(glob_match("glibc", "glibc"));
+    }
+
+    #[test]
+    fn glob_patterns_invalid() {
+        // An unclosed class is reported instead of matching nothing
+        let err = glob_patterns(&["gst*".to_string(), "gcc-[89".to_string()]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().starts_with("invalid pattern 'gcc-[89': "), "{}", err);
     }
 }
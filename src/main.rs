@@ -1,130 +1,990 @@
 use pop_opt::{
     Arch,
+    BuildEvent,
+    BuildOptions,
+    DEFAULT_MIRROR,
+    Error,
+    Jobs,
     Pkg,
+    Progress,
+    RunCommand,
+    SHARE_PARENT_DIR,
+    SOURCE_CACHE_DIR,
+    SbuildResult,
+    ShareDir,
+    compare_versions,
+    control,
+    default_jobs,
+    definition_paths,
+    dry_run,
     ensure_dir,
     ensure_dir_clean,
+    feature_info,
+    glob_patterns,
+    gpg,
+    host_family,
+    is_mirror_url,
+    is_snapshot_timestamp,
+    link_or_copy,
+    manifest::{Changes, DebManifest, Manifest, ManifestDeb, ManifestPkg},
+    parallel_map,
+    partial_dir,
+    pdiff,
+    progress,
+    set_dry_run,
+    sha256,
     status_err,
+    to_json,
+    translation,
+    version,
+    version_suffix,
 };
+use log::{Level, LevelFilter, debug, error, info, log_enabled, warn};
+use serde::Serialize;
 use std::{
+    cmp,
     collections::BTreeMap,
     env,
     fmt::Write,
     fs,
     io,
-    path::Path,
+    ops::RangeInclusive,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
     process,
+    thread,
+    time,
 };
 
+// Log targets of each phase, so RUST_LOG can filter them, such as pop_opt::repo=debug
+const LOG_ARCH: &str = "pop_opt::arch";
+const LOG_BUILD: &str = "pop_opt::build";
+const LOG_CHROOT: &str = "pop_opt::chroot";
+const LOG_CLEAN: &str = "pop_opt::clean";
+const LOG_DPUT: &str = "pop_opt::dput";
+const LOG_REPO: &str = "pop_opt::repo";
+const LOG_VERIFY: &str = "pop_opt::verify";
+
 pub extern "C" fn interrupt(_signal: i32) {}
 
-fn build(arch: &Arch, args: &[String]) -> io::Result<()> {
-    //TODO: passed as argument and used in pkg.build
-    let sbuild_dist = "focal";
-    //TODO: get dynamically
-    let sbuild_dist_version = "20.04";
-    let sbuild_archs = ["amd64", "i386"];
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|x| x != flag);
+    args.len() != len
+}
+
+// Removes every `flag value` or `flag=value` occurrence from args, returning the values in order
+fn take_values(args: &mut Vec<String>, flag: &str) -> io::Result<Vec<String>> {
+    let prefix = format!("{}=", flag);
+    let mut values = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == flag {
+            if i + 1 >= args.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("missing value for '{}'", flag)
+                ));
+            }
+            args.remove(i);
+            values.push(args.remove(i));
+        } else if args[i].starts_with(&prefix) {
+            values.push(args.remove(i)[prefix.len()..].to_string());
+        } else {
+            i += 1;
+        }
+    }
+    Ok(values)
+}
+
+fn take_value(args: &mut Vec<String>, flag: &str) -> io::Result<Option<String>> {
+    Ok(take_values(args, flag)?.pop())
+}
+
+// Removes every --component value, defaulting to main
+fn take_components(args: &mut Vec<String>) -> io::Result<Vec<String>> {
+    let mut components = take_values(args, "--component")?;
+    if components.is_empty() {
+        components.push("main".to_string());
+    }
+    for component in components.iter() {
+        if component.is_empty() || ! component.chars().all(|x| x.is_ascii_alphanumeric() || "-_".contains(x)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid component '{}', only letters, digits, '-', and '_' are allowed", component)
+            ));
+        }
+    }
+    Ok(components)
+}
+
+fn unknown_options(args: &[String]) -> io::Result<()> {
+    match args.iter().find(|x| x.starts_with('-')) {
+        Some(arg) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown option '{}'", arg)
+        )),
+        None => Ok(()),
+    }
+}
+
+// Compression levels a compressor accepts
+fn compression_levels(compressor: &str) -> RangeInclusive<u32> {
+    match compressor {
+        "xz" => 0..=9,
+        _ => 1..=9,
+    }
+}
+
+// Parses a compression level, which every selected compressor must accept
+fn check_compression_level(level: &str, compressors: &[&str]) -> io::Result<u32> {
+    let invalid = |reason: String| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid compression level '{}': {}", level, reason)
+    );
+    let parsed = level.parse::<u32>().map_err(|err| invalid(err.to_string()))?;
+    for compressor in compressors.iter() {
+        let levels = compression_levels(compressor);
+        if ! levels.contains(&parsed) {
+            return Err(invalid(format!(
+                "{} levels are {} to {}",
+                compressor,
+                levels.start(),
+                levels.end()
+            )));
+        }
+    }
+    Ok(parsed)
+}
+
+fn compress_command(file: &Path, compressor: &str, level: Option<u32>) -> process::Command {
+    let mut command = process::Command::new(compressor);
+    command.arg("--keep");
+    if let Some(level) = level {
+        command.arg(format!("-{}", level));
+    }
+    command.arg(file);
+    command
+}
+
+// Compresses an index next to it with each compressor, such as gzip for Packages.gz
+fn compress_index(file: &Path, compressors: &[&str], level: Option<u32>) -> io::Result<()> {
+    for compressor in compressors.iter() {
+        compress_command(file, compressor, level)
+            .run()?;
+    }
+    Ok(())
+}
+
+// Compresses indices on up to jobs threads, returning once all of them are done
+fn compress_indices(files: &[PathBuf], compressors: &[&str], level: Option<u32>, jobs: usize) -> io::Result<()> {
+    for result in parallel_map(files, jobs, |x| compress_index(x, compressors, level)) {
+        result?;
+    }
+    Ok(())
+}
+
+fn utc_date(format: &str) -> io::Result<String> {
+    let output = process::Command::new("date")
+        .arg("--utc")
+        .arg(format!("+{}", format))
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("date", output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Copies the sbuild logs of finished and failed builds of a version into a run's log directory
+fn collect_logs(version_dir: &Path, logs_dir: &Path) -> io::Result<()> {
+    for entry_res in fs::read_dir(version_dir)? {
+        let sbuild_dir = entry_res?.path();
+        if ! sbuild_dir.is_dir() || ! sbuild_dir.file_name().unwrap().to_string_lossy().starts_with("sbuild-") {
+            continue;
+        }
+        for log_res in fs::read_dir(&sbuild_dir)? {
+            let log = log_res?;
+            // sbuild also creates a symlink to the latest log
+            if log.file_type()?.is_file() && log.path().extension() == Some("build".as_ref()) {
+                fs::copy(log.path(), logs_dir.join(log.file_name()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn debsigs_command(deb: &Path, policy: &str, key: Option<&str>) -> process::Command {
+    let mut command = process::Command::new("debsigs");
+    command.arg(format!("--sign={}", policy));
+    if let Some(key) = key {
+        command.arg("-k").arg(key);
+    }
+    command.arg(deb);
+    command
+}
+
+// Result of a package for one sbuild arch
+enum BuildOutcome {
+    // Number of debs built
+    Built(usize),
+    Failed(String),
+    Skipped(String),
+}
+
+struct BuildSummary {
+    name: String,
+    version: String,
+    // Arch achieved, which may be lower than the selected one with --arch-fallback
+    arch: String,
+    archs: Vec<String>,
+    success: bool,
+    outcomes: BTreeMap<String, BuildOutcome>,
+}
+
+impl BuildSummary {
+    // Archs the package is not built for are skipped from the start
+    fn new(pkg: &Pkg, version: &str, arch: &Arch, sbuild_archs: &[&str]) -> Self {
+        let mut outcomes = BTreeMap::new();
+        for sbuild_arch in sbuild_archs.iter() {
+            if ! pkg.sbuild_archs(sbuild_archs).contains(sbuild_arch) {
+                outcomes.insert(sbuild_arch.to_string(), BuildOutcome::Skipped("not in architectures".to_string()));
+            }
+        }
+        Self {
+            name: pkg.name.clone(),
+            version: version.to_string(),
+            arch: arch.name.clone(),
+            archs: Vec::new(),
+            success: true,
+            outcomes,
+        }
+    }
+}
+
+// Reports what preparing a build did, below the package and version
+fn print_build_events(events: &[BuildEvent]) {
+    for event in events.iter() {
+        match event {
+            BuildEvent::ArchiveNewer | BuildEvent::Unchanged | BuildEvent::Changed => info!(target: LOG_BUILD, "  - {}", event),
+            // Asked for with --dump-sbuild-conf, so printed at any log level
+            BuildEvent::SbuildConf { conf, .. } => {
+                println!("    - {}:", event);
+                print!("{}", conf);
+            },
+            _ => info!(target: LOG_BUILD, "    - {}", event),
+        }
+    }
+}
+
+// Waits for the builds of a package, in the order of the sbuild archs, and collects the debs
+fn join_builds(
+    name: &str,
+    threads: Vec<thread::JoinHandle<io::Result<SbuildResult>>>,
+    sbuild_archs: &[&str],
+    progress: &mut Progress,
+    summary: &mut BuildSummary,
+) -> io::Result<Vec<PathBuf>> {
+    let mut debs = Vec::new();
+    for (thread, sbuild_arch) in threads.into_iter().zip(sbuild_archs.iter()) {
+        let result = thread.join().unwrap();
+        progress.finish(result.is_ok());
+        match result {
+            Ok(result) => {
+                summary.outcomes.insert(result.arch.clone(), BuildOutcome::Built(result.debs.len()));
+                summary.archs.push(result.arch);
+                debs.extend(result.debs);
+            },
+            Err(err) => {
+                summary.success = false;
+                summary.outcomes.insert(sbuild_arch.to_string(), BuildOutcome::Failed(err.to_string()));
+                error!(target: LOG_BUILD, "- {}: {}", name, err);
+            }
+        }
+    }
+    Ok(debs)
+}
+
+// Modification time of a file in seconds since the epoch
+fn mtime(path: &Path) -> io::Result<u64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(time::UNIX_EPOCH).map_or(0, |x| x.as_secs()))
+}
+
+// Describes a deb in the pool with its control fields, size, modification time, and hash
+fn manifest_deb(pool_deb: &Path, file: String, dist: &str) -> io::Result<ManifestDeb> {
+    let fields = command_stdout(
+        process::Command::new("dpkg-deb")
+            .arg("--field").arg(pool_deb)
+            .arg("Package").arg("Version").arg("Architecture")
+    )?;
+    let paragraph = control::parse(&fields).into_iter().next().unwrap_or_default();
+    let field = |name: &str| paragraph.get(name).map(|x| x.to_string()).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("'{}' has no {} field", pool_deb.display(), name)
+    ));
+    Ok(ManifestDeb {
+        dist: dist.to_string(),
+        file,
+        package: field("Package")?,
+        version: field("Version")?,
+        architecture: field("Architecture")?,
+        size: fs::metadata(pool_deb)?.len(),
+        mtime: mtime(pool_deb)?,
+        sha256: sha256(pool_deb)?,
+    })
+}
+
+// Directory of a source package's debs within the suite's pool, under its component when
+// given. The by-letter layout follows Debian's, grouped by the first letter of the source, or
+// the first four when it starts with lib
+fn pool_subdir(layout: &str, component: Option<&str>, name: &str) -> PathBuf {
+    let dir = component.map_or_else(PathBuf::new, PathBuf::from);
+    match layout {
+        "by-letter" => {
+            let prefix_len = if name.starts_with("lib") && name.len() > 3 { 4 } else { 1 };
+            let prefix: String = name.chars().take(prefix_len).collect();
+            dir.join(prefix).join(name)
+        },
+        _ => dir.join(name),
+    }
+}
+
+// Archs below the selected one, from the highest level down
+fn fallback_archs<'a>(archs: &'a [Arch], arch: &Arch) -> Vec<&'a Arch> {
+    let mut fallbacks: Vec<&Arch> = archs.iter().filter(|x| x.level < arch.level).collect();
+    fallbacks.sort_by_key(|x| cmp::Reverse(x.level));
+    fallbacks
+}
+
+// One aligned line per package and sbuild arch with its version, level, debs built, and result
+fn summary_table(summaries: &[BuildSummary]) -> Vec<String> {
+    let mut rows = Vec::new();
+    for summary in summaries.iter() {
+        for (sbuild_arch, outcome) in summary.outcomes.iter() {
+            let (debs, status) = match outcome {
+                BuildOutcome::Built(debs) => (debs.to_string(), "built".to_string()),
+                BuildOutcome::Failed(err) => ("-".to_string(), format!("failed: {}", err)),
+                BuildOutcome::Skipped(reason) => ("-".to_string(), format!("skipped: {}", reason)),
+            };
+            rows.push([summary.name.clone(), summary.version.clone(), summary.arch.clone(), sbuild_arch.clone(), debs, status]);
+        }
+    }
+    let mut widths = [0; 5];
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.len());
+        }
+    }
+    rows.iter().map(|row| format!(
+        "{:w0$}  {:w1$}  {:w2$}  {:w3$}  {:>w4$}  {}",
+        row[0], row[1], row[2], row[3], row[4], row[5],
+        w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4],
+    )).collect()
+}
+
+// Ubuntu archive mirror used by chroots and builds
+fn take_mirror(args: &mut Vec<String>) -> io::Result<String> {
+    let mirror = take_value(args, "--mirror")?.unwrap_or_else(|| DEFAULT_MIRROR.to_string());
+    if ! is_mirror_url(&mirror) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid mirror '{}', expected an http or https URL", mirror)
+        ));
+    }
+    Ok(mirror)
+}
+
+// Credentials for private mirrors, in apt's auth.conf format
+fn take_apt_auth(args: &mut Vec<String>) -> io::Result<Option<PathBuf>> {
+    match take_value(args, "--apt-auth")? {
+        Some(file) => Ok(Some(fs::canonicalize(&file).map_err(|err| io::Error::new(
+            err.kind(),
+            format!("failed to find apt auth file '{}': {}", file, err)
+        ))?)),
+        None => Ok(None),
+    }
+}
+
+// Directories the arch and package definitions are read from
+struct DefinitionDirs {
+    arch_dir: PathBuf,
+    pkg_dir: PathBuf,
+}
+
+fn build(arch: &Arch, dirs: &DefinitionDirs, sbuild_dist: &str, sbuild_dist_version: &str, sbuild_archs: &[&str], gpg_key: Option<&str>, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let mut options = BuildOptions::default();
+    if let Some(name) = take_value(&mut args, "--sbuild-profile")? {
+        let profile = Path::new("sbuild").join(format!("{}.conf", name));
+        options.sbuild_profile = Some(fs::canonicalize(&profile).map_err(|err| io::Error::new(
+            err.kind(),
+            format!("failed to find sbuild profile '{}': {}", profile.display(), err)
+        ))?);
+    }
+    let export_dir = match take_value(&mut args, "--export-debs")? {
+        Some(dir) => Some(ensure_dir(dir)?),
+        None => None,
+    };
+    options.merge_buildflags = take_flag(&mut args, "--merge-buildflags");
+    options.detect_flags_only = take_flag(&mut args, "--detect-flags-only");
+    options.newer_than_archive = take_flag(&mut args, "--newer-than-archive");
+    options.dump_sbuild_conf = take_flag(&mut args, "--dump-sbuild-conf");
+    options.rustup_toolchain = take_value(&mut args, "--rustup-toolchain")?;
+    options.shared_source = take_flag(&mut args, "--shared-source");
+    options.changelog_metadata = take_flag(&mut args, "--changelog-metadata");
+    options.only_source_changed = take_flag(&mut args, "--only-source-changed");
+    options.no_cache = take_flag(&mut args, "--no-cache");
+    options.apt_auth = take_apt_auth(&mut args)?;
+    options.download_retries = match take_value(&mut args, "--download-retries")? {
+        Some(retries) => Some(retries.parse::<u32>().map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid download retries '{}': {}", retries, err)
+        ))?),
+        None => None,
+    };
+    options.version_suffix = take_value(&mut args, "--version-suffix")?;
+    if let Some(template) = &options.version_suffix {
+        // Checked with a sample version, so mistakes are reported before any source is prepared
+        let sample = format!("1.0-1{}", version_suffix(template, arch));
+        if template.replace("{level}", "").replace("{arch}", "").contains(['{', '}'])
+            || ! version::is_valid(&sample)
+            || ! compare_versions(&sample, "gt", "1.0-1")?
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid version suffix '{}', expected text with {{level}} and {{arch}} placeholders that sorts above the source version", template)
+            ));
+        }
+    }
+    options.chroot_setup_commands = take_values(&mut args, "--chroot-setup-command")?;
+    options.tmpdir = match take_value(&mut args, "--tmpdir")? {
+        Some(dir) => Some(ensure_dir(dir)?),
+        None => None,
+    };
+    options.mirror = Some(take_mirror(&mut args)?);
+    options.snapshot = take_value(&mut args, "--snapshot")?;
+    if let Some(snapshot) = &options.snapshot {
+        if ! is_snapshot_timestamp(snapshot) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid snapshot timestamp '{}', expected YYYYMMDDTHHMMSSZ", snapshot)
+            ));
+        }
+    }
+    let require_all = take_flag(&mut args, "--require-all");
+    // Packages to build, in addition to any given as positional arguments
+    let only = take_values(&mut args, "--only")?;
+    args.extend(only);
+    // Packages to build by glob pattern, such as 'gst*'
+    let patterns = glob_patterns(&take_values(&mut args, "--match")?)?;
+    // Packages --match never selects, such as toolchains that take hours to build. They are
+    // still built when named
+    let blocklist = glob_patterns(&take_values(&mut args, "--match-exclude")?)?;
+    let output_dir = take_value(&mut args, "--output-dir")?.unwrap_or_else(|| "repo".to_string());
+    let force = take_flag(&mut args, "--force");
+    let pdiff = take_flag(&mut args, "--pdiff");
+    // Embed a debsig signature of this type in each .deb
+    let debsig_sign = take_value(&mut args, "--debsig-sign")?;
+    // Warn about built packages this machine will not upgrade to
+    let check_held = take_flag(&mut args, "--check-held");
+    // Pool directory layout, either grouped by source or by-letter
+    let pool_layout = take_value(&mut args, "--pool-layout")?.unwrap_or_else(|| "source".to_string());
+    if ! ["source", "by-letter"].contains(&pool_layout.as_str()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid pool layout '{}', expected source or by-letter", pool_layout)
+        ));
+    }
+    // Components to publish, packages without a component are published in the first
+    let components = take_components(&mut args)?;
+    // Debs are only grouped by component in the pool when scanning needs it, so a single
+    // component keeps the flat source layout
+    let pool_components = pool_layout == "by-letter" || components.len() > 1;
+    // Succeed even when some packages fail to build
+    let keep_going = take_flag(&mut args, "--keep-going");
+    // Retry packages that fail to build at lower levels
+    let arch_fallback = take_flag(&mut args, "--arch-fallback");
+    // Identifies this run in the Release and log directory, defaulting to the start time
+    let build_id = take_value(&mut args, "--build-id")?;
+    if let Some(build_id) = &build_id {
+        if build_id.is_empty() || ! build_id.chars().all(|x| x.is_ascii_alphanumeric() || "._-".contains(x)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid build id '{}', only letters, digits, '.', '_', and '-' are allowed", build_id)
+            ));
+        }
+    }
+    let all_archs = if arch_fallback {
+        Arch::load_all(&dirs.arch_dir)?
+    } else {
+        Vec::new()
+    };
+    if let Some(policy) = &debsig_sign {
+        if ! ["origin", "maint", "archive"].contains(&policy.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid debsig signature type '{}', expected origin, maint, or archive", policy)
+            ));
+        }
+    }
+    // Concurrent source preparations and sbuilds across all packages and archs, one per CPU
+    // by default. Each sbuild also runs parallel compilers, so lower values may be faster
+    let jobs = match take_value(&mut args, "--jobs")? {
+        Some(jobs) => jobs.parse::<usize>().ok().filter(|x| *x > 0).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid jobs '{}', expected a positive number", jobs)
+        ))?,
+        None => default_jobs(),
+    };
+    options.jobs = Some(Jobs::new(jobs));
+    let index_jobs = match take_value(&mut args, "--index-jobs")? {
+        Some(jobs) => jobs.parse::<usize>().map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid index jobs '{}': {}", jobs, err)
+        ))?,
+        None => default_jobs(),
+    };
+    let compression_level = take_value(&mut args, "--compression-level")?;
+    // Compressed indices to publish, the uncompressed ones are always published
+    let compress = take_value(&mut args, "--compress")?.unwrap_or_else(|| "gzip".to_string());
+    let compressors: &[&str] = match compress.as_str() {
+        "gzip" => &["gzip"],
+        "xz" => &["xz"],
+        "both" => &["gzip", "xz"],
+        "none" => &[],
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid compression '{}', expected gzip, xz, both, or none", compress)
+        )),
+    };
+    // Checked against each selected compressor, as their ranges differ
+    let compression_level = match compression_level {
+        Some(level) => Some(check_compression_level(&level, compressors)?),
+        None => None,
+    };
+    unknown_options(&args)?;
+
+    // Names that match no package are most likely typos, so fail before anything is built
+    let pkgs = Pkg::load_all(&dirs.pkg_dir)?;
+    let unknown: Vec<&str> = args.iter()
+        .filter(|x| ! pkgs.iter().any(|pkg| &pkg.name == *x))
+        .map(|x| x.as_str())
+        .collect();
+    if ! unknown.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown packages: {}", unknown.join(", "))
+        ));
+    }
+
+    let mut tools = vec![
+        "apt-ftparchive", "cp", "date", "dch", "diff", "dpkg-deb", "dpkg-query", "dpkg-source",
+        "gpg", "md5sum", "patch", "sbuild", "schroot", "sha256sum",
+    ];
+    tools.extend(compressors.iter());
+    if options.snapshot.is_some() {
+        tools.push("dget");
+    }
+    if pkgs.iter().any(|x| x.source_dir.is_some()) {
+        tools.push("dpkg-parsechangelog");
+    }
+    if debsig_sign.is_some() {
+        tools.push("debsigs");
+    }
+    if check_held {
+        tools.extend(["apt-cache", "apt-mark"]);
+    }
+    require_tools(&tools)?;
+
+    // The build host must run code built for the target, such as compiler tests
+    if let Err(Error::MissingFeatures(missing)) = arch.check_features(&Arch::cpu_features()?) {
+        if force {
+            warn!(target: LOG_BUILD, "- warning: build host is missing {} features: {}", arch.name, missing.join(", "));
+        } else {
+            return Err(io::Error::other(format!(
+                "build host is missing {} features: {} (use --force to build anyway)",
+                arch.name,
+                missing.join(", ")
+            )));
+        }
+    }
+
+    let missing_chroots = missing_chroots(sbuild_dist, sbuild_archs)?;
+    if ! missing_chroots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("missing chroots: {} (create them with the chroot subcommand)", missing_chroots.join(", "))
+        ));
+    }
+
+    // Chroots may not work with different sbuild and schroot versions than created them
+    let current_tools = tool_versions()?;
+    for sbuild_arch in sbuild_archs.iter() {
+        let stamp = Path::new("/srv/chroot")
+            .join(format!("{}-{}-popopt", sbuild_dist, sbuild_arch))
+            .join(TOOL_STAMP);
+        if let Ok(recorded_tools) = fs::read_to_string(&stamp) {
+            for mismatch in tool_mismatches(&recorded_tools, &current_tools) {
+                warn!(target: LOG_BUILD, "- warning: {}-{}-popopt: {}", sbuild_dist, sbuild_arch, mismatch);
+            }
+        }
+    }
 
     let build_parent_dir = ensure_dir("build")?;
     let sbuild_arch_dir = ensure_dir(build_parent_dir.join(&arch.name))?;
     let build_dir = ensure_dir(sbuild_arch_dir.join(sbuild_dist))?;
 
-    let repo_parent_dir = ensure_dir("repo")?;
-    let repo_dir = ensure_dir_clean(repo_parent_dir.join(&arch.name))?;
+    let build_id = match build_id {
+        Some(build_id) => build_id,
+        None => utc_date("%Y%m%dT%H%M%SZ")?,
+    };
+    info!(target: LOG_BUILD, "- Build {}", build_id);
+    let logs_dir = ensure_dir(build_parent_dir.join("logs").join(&build_id))?;
+
+    let repo_parent_dir = ensure_dir(output_dir)?;
+
+    // Keep signing with the key used for previous publishes, read before the repo is cleaned
+    let key_file = repo_parent_dir.join(&arch.name).join("popopt.asc");
+    let recorded_keys = if key_file.is_file() {
+        gpg::key_file_fingerprints(&key_file)?
+    } else {
+        Vec::new()
+    };
+    let secret_keys = gpg::secret_fingerprints()?;
+    let signing_key = match gpg_key {
+        Some(key) => Some(key.to_string()),
+        None => gpg::select_key(&recorded_keys, &secret_keys),
+    };
+    if signing_key.is_none() && ! recorded_keys.is_empty() {
+        warn!(
+            target: LOG_BUILD,
+            "- warning: previous signing key {} is not available, signatures will not match published ones",
+            recorded_keys.join(", ")
+        );
+    }
+
+    // Previous indices to create pdiffs from, and the pdiffs already published for them, read
+    // before the repo is cleaned
+    let mut old_indices = BTreeMap::new();
+    if pdiff {
+        for component in components.iter() {
+            for sbuild_arch in sbuild_archs.iter() {
+                let packages_file = repo_parent_dir.join(&arch.name)
+                    .join("dists").join(sbuild_dist)
+                    .join(component).join(format!("binary-{}", sbuild_arch))
+                    .join("Packages");
+                if packages_file.is_file() {
+                    let history = pdiff::History::read(&packages_file.with_file_name("Packages.diff"))?;
+                    old_indices.insert((component.clone(), sbuild_arch.to_string()), (fs::read(&packages_file)?, history));
+                }
+            }
+        }
+    }
+
+    // The previous manifest is compared with this run's to list changes
+    let old_manifest = Manifest::load(
+        repo_parent_dir.join(&arch.name).join("dists").join(sbuild_dist).join("manifest.json")
+    )?;
+
+    // Only this suite is rebuilt, other suites published in the repo are kept
+    let repo_dir = ensure_dir(repo_parent_dir.join(&arch.name))?;
 
     let dists_parent_dir = ensure_dir(repo_dir.join("dists"))?;
-    let dists_dir = ensure_dir(dists_parent_dir.join(sbuild_dist))?;
-    let comp_dir = ensure_dir(dists_dir.join("main"))?;
+    let dists_dir = ensure_dir_clean(dists_parent_dir.join(sbuild_dist))?;
 
     let pool_parent_dir = ensure_dir(repo_dir.join("pool"))?;
-    let pool_dir = ensure_dir(pool_parent_dir.join(sbuild_dist))?;
+    let pool_dir = ensure_dir_clean(pool_parent_dir.join(sbuild_dist))?;
 
     let mut pkg_threads = BTreeMap::new();
 
-    let pkgs = Pkg::load_all("pkg")?;
-    for pkg in pkgs.iter() {
-        if ! args.is_empty() && ! args.contains(&pkg.name) {
-            println!("- skipping {}", pkg.name);
-            continue;
+    // Resolve all versions before building, so missing sources are reported together
+    let selected_pkgs = select_pkgs(&pkgs, &args, &patterns, &blocklist, &components)?;
+
+    // Each lookup queries apt in a chroot, so they run in parallel, reported in package order
+    let mut pkg_versions = BTreeMap::new();
+    let mut missing = Vec::new();
+    let versions = parallel_map(&selected_pkgs, jobs, |x| x.latest_version(sbuild_dist));
+    for (pkg, version_res) in selected_pkgs.iter().zip(versions) {
+        match version_res? {
+            Some(version) => {
+                pkg_versions.insert(pkg.name.clone(), version);
+            },
+            None => {
+                info!(target: LOG_BUILD, "- {}: source not found in archive", pkg.name);
+                missing.push(pkg.name.clone());
+            }
         }
+    }
 
-        let pkg_build_dir = ensure_dir(build_dir.join(&pkg.name))?;
-        let threads = pkg.build(arch, sbuild_dist, &sbuild_archs, &pkg_build_dir)?;
-        pkg_threads.insert(pkg.name.clone(), threads);
+    if require_all && ! missing.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("sources not found in archive: {}", missing.join(", "))
+        ));
+    }
+
+    // Later steps need the built packages, so only list what would be built
+    if dry_run() {
+        for pkg in pkgs.iter() {
+            if let Some(version) = pkg_versions.get(&pkg.name) {
+                println!("- would build {} {} for {}", pkg.name, version, pkg.sbuild_archs(sbuild_archs).join(", "));
+            }
+        }
+        return Ok(());
+    }
+
+    let total = pkgs.iter()
+        .filter(|x| pkg_versions.contains_key(&x.name))
+        .map(|x| x.sbuild_archs(sbuild_archs).len())
+        .sum();
+    let mut progress = Progress::new(total, ! log_enabled!(target: LOG_BUILD, Level::Info));
+    // Packages whose source failed to prepare, such as when a patch no longer applies. Other
+    // packages are still built, and the run fails at the end
+    let mut source_errors = BTreeMap::new();
+    for pkg in pkgs.iter() {
+        if let Some(version) = pkg_versions.get(&pkg.name) {
+            let pkg_build_dir = ensure_dir(build_dir.join(&pkg.name))?;
+            info!(target: LOG_BUILD, "- Package {} in {}", pkg.name, pkg_build_dir.display());
+            info!(target: LOG_BUILD, "  - Version {} in {}", version, pkg_build_dir.join(version).display());
+            match pkg.build(arch, sbuild_dist, version, sbuild_archs, &pkg_build_dir, &options) {
+                Ok(started) => {
+                    print_build_events(&started.events);
+                    progress.start(started.threads.len());
+                    pkg_threads.insert(pkg.name.clone(), started.threads);
+                },
+                Err(err) => {
+                    error!(target: LOG_BUILD, "- {}: {}", pkg.name, err);
+                    let count = pkg.sbuild_archs(sbuild_archs).len();
+                    progress.start(count);
+                    for _ in 0..count {
+                        progress.finish(false);
+                    }
+                    source_errors.insert(pkg.name.clone(), err.to_string());
+                },
+            }
+        }
     }
 
+    let mut summaries = Vec::new();
+    let mut built_names: Vec<String> = Vec::new();
+    // Debs linked from unchanged build output keep their entries, so only new ones are hashed
+    let deb_manifest_file = repo_dir.join("manifest.json");
+    let mut deb_manifest = DebManifest::load(&deb_manifest_file)?;
+    let mut manifest_debs = Vec::new();
+    // Published debs of every package, in build order
+    let mut pool_debs = Vec::new();
     for pkg in pkgs.iter() {
+        if let Some(err) = source_errors.get(&pkg.name) {
+            let mut summary = BuildSummary::new(pkg, &pkg_versions[&pkg.name], arch, sbuild_archs);
+            summary.success = false;
+            for sbuild_arch in pkg.sbuild_archs(sbuild_archs) {
+                summary.outcomes.insert(sbuild_arch.to_string(), BuildOutcome::Failed(format!("source: {}", err)));
+            }
+            summaries.push(summary);
+            continue;
+        }
+
         if let Some(threads) = pkg_threads.remove(&pkg.name) {
-            let mut debs = Vec::new();
-            for thread in threads {
-                match thread.join().unwrap() {
-                    Ok(sbuild_dir) => for entry_res in fs::read_dir(&sbuild_dir)? {
-                        let entry = entry_res?;
-                        if entry.file_name().to_str().unwrap_or("").ends_with(".deb") {
-                            debs.push(entry.path());
-                        }
-                    },
-                    Err(err) => {
-                        println!("- {}: {}", pkg.name, err);
+            let version = &pkg_versions[&pkg.name];
+            let pkg_sbuild_archs = pkg.sbuild_archs(sbuild_archs);
+            let mut summary = BuildSummary::new(pkg, version, arch, sbuild_archs);
+            let mut debs = join_builds(&pkg.name, threads, &pkg_sbuild_archs, &mut progress, &mut summary)?;
+            collect_logs(&build_dir.join(&pkg.name).join(version), &logs_dir)?;
+
+            // Retry the whole package at lower levels, so all archs share one version
+            if ! summary.success && arch_fallback {
+                for fallback in fallback_archs(&all_archs, arch) {
+                    info!(target: LOG_BUILD, "- {}: retrying at {}", pkg.name, fallback.name);
+                    let fallback_dir = ensure_dir(
+                        build_parent_dir.join(&fallback.name).join(sbuild_dist).join(&pkg.name)
+                    )?;
+                    let threads = match pkg.build(fallback, sbuild_dist, version, sbuild_archs, &fallback_dir, &options) {
+                        Ok(started) => {
+                            print_build_events(&started.events);
+                            started.threads
+                        },
+                        Err(err) => {
+                            error!(target: LOG_BUILD, "- {}: {}", pkg.name, err);
+                            continue;
+                        },
+                    };
+                    progress.total += threads.len();
+                    progress.start(threads.len());
+                    let mut fallback_summary = BuildSummary::new(pkg, version, fallback, sbuild_archs);
+                    let fallback_debs = join_builds(&pkg.name, threads, &pkg_sbuild_archs, &mut progress, &mut fallback_summary)?;
+                    collect_logs(&fallback_dir.join(version), &logs_dir)?;
+                    if fallback_summary.success {
+                        summary = fallback_summary;
+                        debs = fallback_debs;
+                        break;
+                    }
+                }
+            }
+            summaries.push(summary);
+
+            // Binary package names, from debs named name_version_arch.deb
+            for deb in debs.iter() {
+                let file_name = deb.file_name().unwrap().to_string_lossy();
+                if let Some(name) = file_name.split('_').next() {
+                    if ! built_names.iter().any(|x| x == name) {
+                        built_names.push(name.to_string());
                     }
                 }
             }
 
-            let pkg_pool_dir = ensure_dir(pool_dir.join(&pkg.name))?;
+            let component = pkg.component.as_deref().unwrap_or(&components[0]);
+            let pkg_pool_dir = ensure_dir(pool_dir.join(pool_subdir(
+                &pool_layout,
+                if pool_components { Some(component) } else { None },
+                &pkg.name
+            )))?;
             for deb in debs {
-                let pool_deb = pkg_pool_dir.join(&deb.file_name().unwrap());
+                let pool_deb = pkg_pool_dir.join(deb.file_name().unwrap());
                 if ! pool_deb.is_file() {
-                    fs::hard_link(&deb, &pool_deb)?;
+                    if let Some(debsig_sign) = &debsig_sign {
+                        // Signatures are embedded, so sign a copy to keep the build output unchanged
+                        fs::copy(&deb, &pool_deb)?;
+                        debsigs_command(&pool_deb, debsig_sign, signing_key.as_deref())
+                            .run()?;
+                    } else {
+                        fs::hard_link(&deb, &pool_deb)?;
+                    }
                 }
+                let file = pool_deb.strip_prefix(&repo_dir).unwrap_or(&pool_deb).display().to_string();
+                match deb_manifest.reusable(sbuild_dist, &file, fs::metadata(&pool_deb)?.len(), mtime(&pool_deb)?) {
+                    Some(entry) => manifest_debs.push(entry.clone()),
+                    None => manifest_debs.push(manifest_deb(&pool_deb, file, sbuild_dist)?),
+                }
+                pool_debs.push(pool_deb);
             }
         }
     }
+    progress.done();
 
+    if let Some(export_dir) = &export_dir {
+        export_debs(&pool_debs, export_dir)?;
+    }
 
-    for sbuild_arch in sbuild_archs.iter() {
-        let binary_dir = ensure_dir(comp_dir.join(format!("binary-{}", sbuild_arch)))?;
-
-        let output = process::Command::new("apt-ftparchive")
-            .arg("--arch").arg(sbuild_arch)
-            .arg("packages")
-            .arg(&pool_dir.strip_prefix(&repo_dir).unwrap())
-            .current_dir(&repo_dir)
-            .stdout(process::Stdio::piped())
-            .spawn()?
-            .wait_with_output()?;
-        status_err(output.status)?;
-
-        let packages_file = binary_dir.join("Packages");
-        fs::write(&packages_file, &output.stdout)?;
-
-        process::Command::new("gzip")
-            .arg("--keep")
-            .arg(packages_file)
-            .status()
-            .and_then(status_err)?;
-
-        let mut release = String::new();
-        writeln!(release, "Archive: {}", sbuild_dist).unwrap();
-        writeln!(release, "Version: {}", sbuild_dist_version).unwrap();
-        writeln!(release, "Component: main").unwrap();
-        writeln!(release, "Origin: pop-os-opt-{}", arch.name).unwrap();
-        writeln!(release, "Label: Pop!_OS Opt {}", arch.name).unwrap();
-        writeln!(release, "Architecture: {}", sbuild_arch).unwrap();
-        fs::write(binary_dir.join("Release"), &release)?;
+    // Packages without a source are also listed, as nothing was built for them
+    for pkg in pkgs.iter().filter(|x| missing.contains(&x.name)) {
+        let mut summary = BuildSummary::new(pkg, "-", arch, sbuild_archs);
+        for sbuild_arch in pkg.sbuild_archs(sbuild_archs) {
+            summary.outcomes.insert(sbuild_arch.to_string(), BuildOutcome::Skipped("source not found".to_string()));
+        }
+        summaries.push(summary);
+    }
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Printed even in summary-only mode, as the result of the run
+    println!();
+    for line in summary_table(&summaries) {
+        println!("{}", line);
+    }
+
+    if check_held {
+        let blocked = held_or_pinned(&built_names)?;
+        if ! blocked.is_empty() {
+            warn!(
+                target: LOG_BUILD,
+                "- warning: held or pinned on this machine, so built versions will not be installed: {}",
+                blocked.join(", ")
+            );
+        }
+    }
+
+    // Inventory of the published debs of every suite, replacing those of this one
+    deb_manifest.replace_dist(sbuild_dist, manifest_debs);
+    deb_manifest.save(&deb_manifest_file)?;
+
+    // Packages with any published debs, as failed builds are not in the pool
+    let manifest = Manifest {
+        build_id: build_id.clone(),
+        arch: arch.name.clone(),
+        dist: sbuild_dist.to_string(),
+        packages: summaries.iter().filter(|x| ! x.archs.is_empty()).map(|x| ManifestPkg {
+            name: x.name.clone(),
+            version: x.version.clone(),
+            arch: x.arch.clone(),
+            archs: x.archs.clone(),
+        }).collect(),
+    };
+    manifest.save(dists_dir.join("manifest.json"))?;
+
+    let changes = Changes::between(&old_manifest.unwrap_or_default(), &manifest);
+    fs::write(dists_dir.join("CHANGES.md"), changes.to_markdown(&manifest))?;
+    fs::write(dists_dir.join("CHANGES.json"), to_json(&changes)?)?;
+
+    // pdiffs are named by the time they were created
+    let pdiff_name = if pdiff {
+        utc_date("%Y-%m-%d-%H%M.%S")?
+    } else {
+        String::new()
+    };
+
+    let mut index_files = Vec::new();
+    for component in components.iter() {
+        let comp_dir = ensure_dir(dists_dir.join(component))?;
+
+        // Only the directories debs are linked into are scanned
+        let pool_scan_dir = if pool_components {
+            ensure_dir(pool_dir.join(component))?
+        } else {
+            pool_dir.clone()
+        };
+
+        let mut packages_files = Vec::new();
+        for sbuild_arch in sbuild_archs.iter() {
+            let binary_dir = ensure_dir(comp_dir.join(format!("binary-{}", sbuild_arch)))?;
+
+            let output = process::Command::new("apt-ftparchive")
+                .arg("--arch").arg(sbuild_arch)
+                .arg("packages")
+                .arg(pool_scan_dir.strip_prefix(&repo_dir).unwrap())
+                .current_dir(&repo_dir)
+                .stdout(process::Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+            status_err("apt-ftparchive", output.status)?;
+
+            let packages_file = binary_dir.join("Packages");
+            fs::write(&packages_file, &output.stdout)?;
+
+            if let Some((old_index, history)) = old_indices.get(&(component.clone(), sbuild_arch.to_string())) {
+                pdiff::write(&packages_file, old_index, history, &pdiff_name)?;
+            }
+
+            packages_files.push(packages_file);
+
+            let mut release = String::new();
+            writeln!(release, "Archive: {}", sbuild_dist).unwrap();
+            writeln!(release, "Version: {}", sbuild_dist_version).unwrap();
+            writeln!(release, "Component: {}", component).unwrap();
+            writeln!(release, "Origin: {}", opt_origin(arch)).unwrap();
+            writeln!(release, "Label: Pop!_OS Opt {}", arch.name).unwrap();
+            writeln!(release, "Architecture: {}", sbuild_arch).unwrap();
+            fs::write(binary_dir.join("Release"), &release)?;
+        }
+
+        // Descriptions are also published as translations, which apt fetches separately
+        let translation_file = translation::write(&comp_dir, &packages_files)?;
+        index_files.extend(packages_files);
+        index_files.push(translation_file);
     }
 
+    // All indices must be compressed before the dist Release hashes them
+    compress_indices(&index_files, compressors, compression_level, index_jobs)?;
+
     let output = process::Command::new("apt-ftparchive")
-        .arg("-o").arg(format!("APT::FTPArchive::Release::Origin=pop-os-opt-{}", arch.name))
+        .arg("-o").arg(format!("APT::FTPArchive::Release::Origin={}", opt_origin(arch)))
         .arg("-o").arg(format!("APT::FTPArchive::Release::Label=Pop!_OS Opt {}", arch.name))
         .arg("-o").arg(format!("APT::FTPArchive::Release::Suite={}", sbuild_dist))
         .arg("-o").arg(format!("APT::FTPArchive::Release::Version={}", sbuild_dist_version))
         .arg("-o").arg(format!("APT::FTPArchive::Release::Codename={}", sbuild_dist))
         .arg("-o").arg(format!("APT::FTPArchive::Release::Architectures={}", sbuild_archs.join(" ")))
-        .arg("-o").arg("APT::FTPArchive::Release::Components=main")
+        .arg("-o").arg(format!("APT::FTPArchive::Release::Components={}", components.join(" ")))
         .arg("-o").arg(format!(
-            "APT::FTPArchive::Release::Description=Pop!_OS Opt {} {} {}",
-            sbuild_dist,
-            sbuild_dist_version,
-            arch.name
+            "APT::FTPArchive::Release::Description={}",
+            release_description(arch, sbuild_dist, sbuild_dist_version, &build_id)
         ))
         .arg("release")
         .arg(".")
@@ -132,202 +992,1648 @@ fn build(arch: &Arch, args: &[String]) -> io::Result<()> {
         .stdout(process::Stdio::piped())
         .spawn()?
         .wait_with_output()?;
-    status_err(output.status)?;
+    status_err("apt-ftparchive", output.status)?;
 
     let release_file = dists_dir.join("Release");
     fs::write(&release_file, &output.stdout)?;
 
-    //TODO: --local-user
-    process::Command::new("gpg")
+    sign_suites(&dists_parent_dir, signing_key.as_deref())?;
+
+    // Record the public signing key, gpg uses the first secret key by default
+    let exported_key = signing_key.as_ref().or_else(|| secret_keys.first());
+    if let Some(key) = exported_key {
+        gpg::export_key(key, &key_file)?;
+    }
+
+    // Source for testing the repository locally, such as by linking it into
+    // /etc/apt/sources.list.d, with a line for every suite published in it
+    let list_option = if exported_key.is_some() {
+        format!("signed-by={}", key_file.display())
+    } else {
+        "trusted=yes".to_string()
+    };
+    let mut list = String::new();
+    for suite_dir in sub_dirs(&dists_parent_dir)? {
+        let release = match fs::read_to_string(suite_dir.join("Release")) {
+            Ok(release) => release,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        let paragraph = control::parse(&release).into_iter().next().unwrap_or_default();
+        writeln!(
+            list,
+            "deb [{}] file://{} {} {}",
+            list_option,
+            repo_dir.display(),
+            suite_dir.file_name().unwrap().to_string_lossy(),
+            paragraph.get("Components").unwrap_or("main")
+        ).unwrap();
+    }
+    fs::write(repo_dir.join("popopt.list"), list)?;
+
+    // Packages that did build are still published, but the run fails unless --keep-going
+    let failed: Vec<&str> = summaries.iter()
+        .filter(|x| x.outcomes.values().any(|x| matches!(x, BuildOutcome::Failed(_))))
+        .map(|x| x.name.as_str())
+        .collect();
+    if ! failed.is_empty() {
+        if keep_going {
+            warn!(target: LOG_BUILD, "- warning: failed to build: {}", failed.join(", "));
+        } else {
+            return Err(io::Error::other(format!("failed to build: {}", failed.join(", "))));
+        }
+    }
+
+    Ok(())
+}
+
+// Signs every suite with a Release independently, with the same key, as suites published
+// before are kept
+fn sign_suites(dists_parent_dir: &Path, signing_key: Option<&str>) -> io::Result<()> {
+    for entry in fs::read_dir(dists_parent_dir)? {
+        let suite_dir = entry?.path();
+        let suite_release = suite_dir.join("Release");
+        if suite_release.is_file() {
+            info!(target: LOG_BUILD, "- Signing {}", suite_dir.display());
+            sign_release(&suite_dir, &suite_release, signing_key)?;
+        }
+    }
+    Ok(())
+}
+
+fn sign_release(suite_dir: &Path, release_file: &Path, signing_key: Option<&str>) -> io::Result<()> {
+    let mut command = process::Command::new("gpg");
+    command
         .arg("--clearsign")
         .arg("--batch").arg("--yes")
-        .arg("--digest-algo").arg("sha512")
-        .arg("-o").arg(dists_dir.join("InRelease"))
-        .arg(&release_file)
-        .status()
-        .and_then(status_err)?;
-
-    //TODO: --local-user
-    process::Command::new("gpg")
+        .arg("--digest-algo").arg("sha512");
+    if let Some(key) = signing_key {
+        command.arg("--local-user").arg(key);
+    }
+    command
+        .arg("-o").arg(suite_dir.join("InRelease"))
+        .arg(release_file)
+        .run()
+        .map_err(|err| signing_err("InRelease", suite_dir, signing_key, err))?;
+
+    let mut command = process::Command::new("gpg");
+    command
         .arg("-abs")
         .arg("--batch").arg("--yes")
-        .arg("--digest-algo").arg("sha512")
-        .arg("-o").arg(dists_dir.join("Release.gpg"))
-        .arg(&release_file)
-        .status()
-        .and_then(status_err)?;
+        .arg("--digest-algo").arg("sha512");
+    if let Some(key) = signing_key {
+        command.arg("--local-user").arg(key);
+    }
+    command
+        .arg("-o").arg(suite_dir.join("Release.gpg"))
+        .arg(release_file)
+        .run()
+        .map_err(|err| signing_err("Release.gpg", suite_dir, signing_key, err))
+}
+
+fn signing_err(file: &str, suite_dir: &Path, signing_key: Option<&str>, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!(
+        "failed to sign {} in '{}' with {}: {}",
+        file,
+        suite_dir.display(),
+        signing_key.map_or_else(|| "the default key".to_string(), |x| format!("key {}", x)),
+        err
+    ))
+}
+
+// Fails when the host no longer supports a target arch, such as after moving to older hardware
+fn check_compat(arch_dir: &Path, cpu_features: &[String], args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let target = take_value(&mut args, "--target")?.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "missing '--target <arch>'"
+    ))?;
+    unknown_options(&args)?;
+
+    let archs = Arch::load_all(arch_dir)?;
+    let arch = archs.iter().find(|x| x.name == target).ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("unknown arch '{}'", target)
+    ))?;
+
+    let (lines, result) = compat_report(arch, cpu_features);
+    for line in lines.iter() {
+        println!("{}", line);
+    }
+    result
+}
+
+// Output lines of check-compat for an arch, and an error naming the missing features
+fn compat_report(arch: &Arch, cpu_features: &[String]) -> (Vec<String>, io::Result<()>) {
+    match arch.check_features(cpu_features) {
+        Ok(()) => (vec![format!("{}: Supported", arch.name)], Ok(())),
+        Err(Error::MissingFeatures(missing)) => (
+            missing.iter().map(|x| format!("- missing {}", x)).collect(),
+            Err(io::Error::other(format!(
+                "host is missing {} features: {}",
+                arch.name,
+                missing.join(", ")
+            )))
+        ),
+        Err(err) => (Vec::new(), Err(err.into())),
+    }
+}
+
+// Reads a file from a local repository, or downloads it when the repository is a URL.
+// Returns None when the file does not exist, as Release may list indices that are not published
+fn fetch_repo_file(repo: &str, name: &str, tmp_dir: &Path) -> io::Result<Option<PathBuf>> {
+    if ! repo.contains("://") {
+        let path = Path::new(repo).join(name);
+        return Ok(if path.is_file() { Some(path) } else { None });
+    }
+
+    let path = tmp_dir.join(name.replace('/', "_"));
+    let status = process::Command::new("curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--location")
+        .arg("--output").arg(&path)
+        .arg(format!("{}/{}", repo.trim_end_matches('/'), name))
+        .status()?;
+    // curl exits with 22 on HTTP errors, such as not found
+    match status.code() {
+        Some(22) => Ok(None),
+        _ => Ok(status_err("curl", status).map(|()| Some(path))?),
+    }
+}
+
+fn verify_release_in(key: &Path, repo: &str, tmp_dir: &Path) -> io::Result<()> {
+    let in_release = fetch_repo_file(repo, "InRelease", tmp_dir)?.ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("failed to find InRelease in '{}'", repo)
+    ))?;
+
+    // gpgv only reads binary keyrings
+    let keyring = tmp_dir.join("keyring.gpg");
+    let status = process::Command::new("gpg")
+        .arg("--batch")
+        .arg("--dearmor")
+        .arg("--output").arg(&keyring)
+        .arg(key)
+        .status()?;
+    status_err("gpg", status)?;
+
+    let release_file = tmp_dir.join("Release");
+    let status = process::Command::new("gpgv")
+        .arg("--keyring").arg(&keyring)
+        .arg("--output").arg(&release_file)
+        .arg(&in_release)
+        .status()?;
+    status_err("gpgv", status).map_err(|err| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("InRelease signature verification failed: {}", err)
+    ))?;
+    println!("- InRelease signature is valid");
+
+    let release = fs::read_to_string(&release_file)?;
+    let (verified, problems) = release_problems(&release, repo, tmp_dir)?;
+    for (name, problem) in problems.iter() {
+        println!("- {}: {}", name, problem);
+    }
+    if ! problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} of the files in Release failed verification", problems.len())
+        ));
+    }
+    println!("- {} files match Release", verified);
+    Ok(())
+}
+
+// Checks the size and SHA256 of the files listed in Release, returning the number that match
+// and the name and problem of those that do not
+fn release_problems(release: &str, repo: &str, tmp_dir: &Path) -> io::Result<(usize, Vec<(String, String)>)> {
+    let paragraph = control::parse(release).into_iter().next().unwrap_or_default();
+    let mut verified = 0;
+    let mut problems = Vec::new();
+    for line in paragraph.lines("SHA256") {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 3 {
+            problems.push((line.to_string(), "invalid SHA256 entry".to_string()));
+            continue;
+        }
+        let (hash, size, name) = (words[0], words[1], words[2]);
+        let path = match fetch_repo_file(repo, name, tmp_dir)? {
+            Some(path) => path,
+            None => continue,
+        };
+        let actual_size = fs::metadata(&path)?.len();
+        if size.parse::<u64>().ok() != Some(actual_size) {
+            problems.push((name.to_string(), format!("size {} does not match {}", actual_size, size)));
+        } else if sha256(&path)? != hash {
+            problems.push((name.to_string(), "SHA256 does not match".to_string()));
+        } else {
+            verified += 1;
+        }
+    }
+    Ok((verified, problems))
+}
+
+// Verifies a suite's InRelease with a key, and the indices with the hashes it contains
+fn verify_release(args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let key = take_value(&mut args, "--key")?.ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "missing '--key <asc>'"
+    ))?;
+    unknown_options(&args)?;
+    if args.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected a repository URL or path, such as 'repo/x86-64-v3/dists/focal'"
+        ));
+    }
+    let key = fs::canonicalize(&key)?;
+
+    let output = process::Command::new("mktemp")
+        .arg("--directory")
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("mktemp", output.status)?;
+    let tmp_dir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+    let res = verify_release_in(&key, &args[0], &tmp_dir);
+    fs::remove_dir_all(&tmp_dir)?;
+    res
+}
+
+// Checks a built suite before publishing: the indices against the hashes in Release, and the
+// signatures of InRelease and Release.gpg against the local keyring
+fn verify(arch: &Arch, sbuild_dist: &str, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let output_dir = take_value(&mut args, "--output-dir")?.unwrap_or_else(|| "repo".to_string());
+    unknown_options(&args)?;
+
+    let dists_dir = Path::new(&output_dir).join(&arch.name).join("dists").join(sbuild_dist);
+    let release_file = dists_dir.join("Release");
+    let release = fs::read_to_string(&release_file).map_err(|err| io::Error::new(
+        err.kind(),
+        format!("failed to read '{}': {}", release_file.display(), err)
+    ))?;
+
+    // Local files are read in place, so nothing is downloaded to the temporary directory
+    let (verified, mut problems) = release_problems(&release, &dists_dir.to_string_lossy(), &dists_dir)?;
+    for (name, _) in problems.iter_mut() {
+        *name = dists_dir.join(&name).display().to_string();
+    }
+    info!(target: LOG_VERIFY, "- {} files match Release", verified);
+
+    let signatures = [
+        (dists_dir.join("InRelease"), None),
+        (dists_dir.join("Release.gpg"), Some(&release_file)),
+    ];
+    for (signature, signed) in signatures.iter() {
+        let mut command = process::Command::new("gpg");
+        command
+            .arg("--batch")
+            .arg("--verify")
+            .arg(signature);
+        if let Some(signed) = signed {
+            command.arg(signed);
+        }
+        debug!(target: LOG_VERIFY, "- running {:?}", command);
+        let output = command.stderr(process::Stdio::piped()).output()?;
+        match status_err("gpg", output.status) {
+            Ok(()) => info!(target: LOG_VERIFY, "- {} signature is valid", signature.display()),
+            Err(err) => {
+                debug!(target: LOG_VERIFY, "{}", String::from_utf8_lossy(&output.stderr).trim_end());
+                problems.push((signature.display().to_string(), format!("signature verification failed: {}", err)));
+            },
+        }
+    }
+
+    for (path, problem) in problems.iter() {
+        println!("- {}: {}", path, problem);
+    }
+    if ! problems.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} problems found in '{}'", problems.len(), dists_dir.display())
+        ));
+    }
+    Ok(())
+}
+
+// Runs a build with only the keys from a key file, imported into a temporary GNUPGHOME that
+// is removed afterwards, even when the build or signing fails
+fn build_with_key_file(key_file: &str, build: impl FnOnce(Option<&str>) -> io::Result<()>, gpg_key: Option<&str>) -> io::Result<()> {
+    let key_file = fs::canonicalize(key_file).map_err(|err| io::Error::new(
+        err.kind(),
+        format!("failed to find gpg key file '{}': {}", key_file, err)
+    ))?;
+
+    let output = process::Command::new("mktemp")
+        .arg("--directory")
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("mktemp", output.status)?;
+    let gnupg_home = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+
+    // Inherited by every gpg command run from here on
+    let previous_home = env::var_os("GNUPGHOME");
+    env::set_var("GNUPGHOME", &gnupg_home);
+    let res = gpg::import_key(&key_file).and_then(|()| match gpg_key {
+        Some(key) => build(Some(key)),
+        None => {
+            let keys = gpg::secret_fingerprints()?;
+            build(Some(keys.first().map(|x| x.as_str()).ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("gpg key file '{}' has no secret key", key_file.display())
+            ))?))
+        },
+    });
+
+    // The agent started for the temporary home would otherwise keep running
+    let _ = process::Command::new("gpgconf").arg("--kill").arg("gpg-agent").status();
+    match previous_home {
+        Some(home) => env::set_var("GNUPGHOME", home),
+        None => env::remove_var("GNUPGHOME"),
+    }
+    fs::remove_dir_all(&gnupg_home)?;
+    res
+}
+
+// Validates all definitions without building, reporting every problem found
+fn check(dirs: &DefinitionDirs, sbuild_dist: &str, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    // Resolving sources requires the chroots
+    let resolve = ! take_flag(&mut args, "--no-resolve");
+    unknown_options(&args)?;
+
+    let problems = check_definitions(dirs, if resolve { Some(sbuild_dist) } else { None });
+    for problem in problems.iter() {
+        println!("- {}", problem);
+    }
+
+    if problems.is_empty() {
+        println!("All definitions are valid");
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("found {} problems in definitions", problems.len())
+        ))
+    }
+}
+
+// Problems in the arch and package definitions, each prefixed with the file it is in. Sources are
+// resolved in the archive of resolve_dist when given
+fn check_definitions(dirs: &DefinitionDirs, resolve_dist: Option<&str>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut levels = BTreeMap::new();
+    match definition_paths(&dirs.arch_dir, "toml", false) {
+        Ok(paths) => for path in paths {
+            match Arch::load(&path) {
+                Ok(arch) => {
+                    if arch.name.is_empty() {
+                        problems.push(format!("{}: name is empty", path.display()));
+                    }
+                    if arch.features.is_empty() {
+                        problems.push(format!("{}: no features listed", path.display()));
+                    }
+                    if let Some(other) = levels.insert(arch.level, path.clone()) {
+                        problems.push(format!(
+                            "{}: level {} already used by {}",
+                            path.display(), arch.level, other.display()
+                        ));
+                    }
+                },
+                Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+            }
+        },
+        Err(err) => problems.push(format!("{}: {}", dirs.arch_dir.display(), err)),
+    }
+
+    let mut names = BTreeMap::new();
+    match definition_paths(&dirs.pkg_dir, "toml", true) {
+        Ok(paths) => for path in paths {
+            match Pkg::load(&path) {
+                Ok(mut pkg) => {
+                    pkg.root_dir = Pkg::root_dir(&dirs.pkg_dir);
+                    if pkg.name.is_empty() {
+                        problems.push(format!("{}: name is empty", path.display()));
+                    }
+                    if let Some(other) = names.insert(pkg.name.clone(), path.clone()) {
+                        problems.push(format!(
+                            "{}: package '{}' already defined by {}",
+                            path.display(), pkg.name, other.display()
+                        ));
+                    }
+                    for patch in pkg.patches.iter() {
+                        if ! pkg.patch_path(patch).is_file() {
+                            problems.push(format!(
+                                "{}: patch '{}' does not exist",
+                                path.display(), pkg.patch_path(patch).display()
+                            ));
+                        }
+                    }
+                    if let Some(source_path) = pkg.source_path() {
+                        if ! source_path.join("debian/changelog").is_file() {
+                            problems.push(format!(
+                                "{}: source '{}' has no debian/changelog",
+                                path.display(), source_path.display()
+                            ));
+                        }
+                    }
+                    if let Some(sbuild_dist) = resolve_dist {
+                        match pkg.latest_version(sbuild_dist) {
+                            Ok(Some(_)) => (),
+                            Ok(None) => problems.push(format!(
+                                "{}: source '{}' not found in archive",
+                                path.display(), pkg.name
+                            )),
+                            Err(err) => problems.push(format!(
+                                "{}: failed to resolve source '{}': {}",
+                                path.display(), pkg.name, err
+                            )),
+                        }
+                    }
+                },
+                Err(err) => problems.push(format!("{}: {}", path.display(), err)),
+            }
+        },
+        Err(err) => problems.push(format!("{}: {}", dirs.pkg_dir.display(), err)),
+    }
+
+    problems
+}
+
+// Records the versions of the tools that created a chroot, which must match later builds
+const TOOL_STAMP: &str = "etc/popopt-tools";
+
+// Installed versions of sbuild and schroot, as "name version" lines
+fn tool_versions() -> io::Result<String> {
+    let output = process::Command::new("dpkg-query")
+        .arg("--show")
+        .arg("--showformat=${Package} ${Version}\n")
+        .arg("sbuild")
+        .arg("schroot")
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("dpkg-query", output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Versions are compatible when the major and minor upstream versions match
+fn tool_version_compatible(recorded: &str, current: &str) -> bool {
+    fn major_minor(version: &str) -> Vec<&str> {
+        let version = version.split_once(':').map_or(version, |x| x.1);
+        let upstream = version.rsplit_once('-').map_or(version, |x| x.0);
+        upstream.split(|x: char| ! x.is_ascii_digit()).take(2).collect()
+    }
+    major_minor(recorded) == major_minor(current)
+}
+
+// Tools whose versions differ from the ones recorded in a stamp
+fn tool_mismatches(recorded: &str, current: &str) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    for line in recorded.lines() {
+        let (name, recorded_version) = match line.split_once(' ') {
+            Some(x) => x,
+            None => continue,
+        };
+        let current_version = current.lines()
+            .filter_map(|x| x.split_once(' '))
+            .find(|x| x.0 == name)
+            .map(|x| x.1);
+        match current_version {
+            Some(current_version) if tool_version_compatible(recorded_version, current_version) => (),
+            Some(current_version) => mismatches.push(format!(
+                "{} {} (chroot created with {})", name, current_version, recorded_version
+            )),
+            None => mismatches.push(format!(
+                "{} not installed (chroot created with {})", name, recorded_version
+            )),
+        }
+    }
+    mismatches
+}
+
+fn apt_auth_command(apt_auth: &Path, chroot_dir: &Path) -> process::Command {
+    let mut command = process::Command::new("sudo");
+    command
+        .arg("install")
+        .arg("-D")
+        .arg("--mode=0640")
+        .arg("--group=sbuild")
+        .arg(apt_auth)
+        .arg(chroot_dir.join("etc/apt/auth.conf.d/popopt.conf"));
+    command
+}
+
+// Tools that are not an executable file in any PATH directory
+fn missing_tools(tools: &[&str]) -> Vec<String> {
+    let paths: Vec<PathBuf> = env::var_os("PATH").map_or_else(Vec::new, |x| env::split_paths(&x).collect());
+    tools.iter()
+        .filter(|tool| ! paths.iter().any(|dir| {
+            fs::metadata(dir.join(tool)).is_ok_and(|x| x.is_file() && x.permissions().mode() & 0o111 != 0)
+        }))
+        .map(|x| x.to_string())
+        .collect()
+}
+
+// Fails before any work is done when tools are missing, listing all of them
+fn require_tools(tools: &[&str]) -> io::Result<()> {
+    let missing = missing_tools(tools);
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("missing required tools: {}", missing.join(", "))
+        ))
+    }
+}
+
+// Chroots that schroot does not know for the sbuild archs of a dist
+fn missing_chroots(sbuild_dist: &str, sbuild_archs: &[&str]) -> io::Result<Vec<String>> {
+    let output = process::Command::new("schroot")
+        .arg("--list")
+        .arg("--all-chroots")
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err("schroot", output.status)?;
+    let chroots = String::from_utf8_lossy(&output.stdout);
+    Ok(sbuild_archs.iter()
+        .map(|sbuild_arch| format!("{}-{}-popopt", sbuild_dist, sbuild_arch))
+        .filter(|name| ! chroots.lines().any(|x| x.trim() == format!("chroot:{}", name)))
+        .collect())
+}
+
+fn chroot(_arch: &Arch, sbuild_dist: &str, sbuild_archs: &[&str], args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let apt_auth = take_apt_auth(&mut args)?;
+    let mirror = take_mirror(&mut args)?;
+    unknown_options(&args)?;
+    require_tools(&["dpkg-query", "sudo", "sbuild-createchroot", "sbuild-update"])?;
+
+    let parent_dir = Path::new("/srv/chroot");
+    for sbuild_arch in sbuild_archs.iter() {
+        let name = format!("{}-{}-popopt", sbuild_dist, sbuild_arch);
+        info!(target: LOG_CHROOT, "- chroot {}", name);
+        let dir = parent_dir.join(&name);
+        if ! dir.is_dir() {
+            process::Command::new("sudo")
+                .arg("sbuild-createchroot")
+                .arg(format!("--arch={}", sbuild_arch))
+                .arg("--chroot-suffix=-popopt")
+                .arg("--components=main,restricted,universe,multiverse")
+                .arg(format!("--extra-repository=deb {} {}-updates main restricted universe multiverse", mirror, sbuild_dist))
+                .arg(format!("--extra-repository=deb-src {} {}-updates main restricted universe multiverse", mirror, sbuild_dist))
+                .arg(format!("--extra-repository=deb {} {}-security main restricted universe multiverse", mirror, sbuild_dist))
+                .arg(format!("--extra-repository=deb-src {} {}-security main restricted universe multiverse", mirror, sbuild_dist))
+                .arg(sbuild_dist)
+                .arg(&dir)
+                .arg(&mirror)
+                .run()?;
+
+            sudo_write(&dir.join(TOOL_STAMP), &tool_versions()?)?;
+        }
+
+        // Builds and source downloads both use the chroot's apt, so it needs the credentials.
+        // The sbuild group, which build users belong to, must read it for apt-get source
+        if let Some(apt_auth) = &apt_auth {
+            apt_auth_command(apt_auth, &dir)
+                .run()?;
+        }
+
+        process::Command::new("sudo")
+            .arg("sbuild-update")
+            .arg("--update")
+            .arg("--dist-upgrade")
+            .arg("--clean")
+            .arg("--autoclean")
+            .arg("--autoremove")
+            .arg(format!("--arch={}", sbuild_arch))
+            .arg(&name)
+            .run()?;
+    }
+
+    Ok(())
+}
+
+fn dput_command(target: &str, changes_file: &Path) -> process::Command {
+    let mut command = process::Command::new("dput");
+    command.arg(target).arg(changes_file);
+    command
+}
+
+// Uploads the optimized source packages to a dput target, such as a PPA
+fn dput(arch: &Arch, pkg_dir: &Path, sbuild_dist: &str, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let key = take_value(&mut args, "--key")?;
+    let options = BuildOptions {
+        apt_auth: take_apt_auth(&mut args)?,
+        ..BuildOptions::default()
+    };
+    unknown_options(&args)?;
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "missing dput target"
+        ));
+    }
+    let target = args.remove(0);
+
+    let build_parent_dir = ensure_dir("build")?;
+    let sbuild_arch_dir = ensure_dir(build_parent_dir.join(&arch.name))?;
+    let build_dir = ensure_dir(sbuild_arch_dir.join(sbuild_dist))?;
+
+    for pkg in Pkg::load_all(pkg_dir)? {
+        if ! args.is_empty() && ! args.contains(&pkg.name) {
+            continue;
+        }
+
+        let version = pkg.latest_version(sbuild_dist)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("source '{}' not found in archive", pkg.name)
+        ))?;
+        let pkg_build_dir = ensure_dir(build_dir.join(&pkg.name))?;
+        let (changes_file, events) = pkg.source_changes(arch, sbuild_dist, &version, &pkg_build_dir, &options)?;
+        print_build_events(&events);
+        info!(target: LOG_DPUT, "- {}: uploading {}", pkg.name, changes_file.display());
+
+        // Uploads must be signed
+        let mut command = process::Command::new("debsign");
+        command.arg("--re-sign");
+        if let Some(key) = &key {
+            command.arg(format!("-k{}", key));
+        }
+        command
+            .arg(&changes_file)
+            .run()?;
+
+        dput_command(&target, &changes_file)
+            .run()?;
+    }
+
+    Ok(())
+}
+
+fn explain(arch: &Arch, arch_dir: &Path, args: &[String]) -> io::Result<()> {
+    let archs;
+    let arch = match args.first() {
+        Some(name) => {
+            archs = Arch::load_all(arch_dir)?;
+            archs.iter().find(|x| &x.name == name).ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown arch '{}'", name)
+            ))?
+        },
+        None => arch,
+    };
+
+    println!("{} (level {}): {}", arch.name, arch.level, arch.wiki);
+    for line in arch.explain() {
+        println!("- {}", line);
+    }
+
+    Ok(())
+}
+
+// Total size of the files in a directory, without following symlinks
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if ! metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry_res in fs::read_dir(path)? {
+        size += dir_size(&entry_res?.path())?;
+    }
+    Ok(size)
+}
+
+// Removes build and repo output of the selected arch, or of every arch with --all
+fn clean(arch: &Arch, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let all = take_flag(&mut args, "--all");
+    // Only remove the build directories of this package
+    let package = take_value(&mut args, "--package")?;
+    let output_dir = take_value(&mut args, "--output-dir")?.unwrap_or_else(|| "repo".to_string());
+    unknown_options(&args)?;
+
+    let mut paths = Vec::new();
+    match &package {
+        // Packages are built in build/<arch>/<dist>/<package>
+        Some(package) => {
+            let arch_dirs = if all {
+                sub_dirs(Path::new("build"))?
+            } else {
+                vec![Path::new("build").join(&arch.name)]
+            };
+            for arch_dir in arch_dirs {
+                for dist_dir in sub_dirs(&arch_dir)? {
+                    paths.push(dist_dir.join(package));
+                }
+            }
+        },
+        None => if all {
+            paths.push(PathBuf::from("build"));
+            paths.push(PathBuf::from(&output_dir));
+            paths.push(PathBuf::from(SOURCE_CACHE_DIR));
+        } else {
+            paths.push(Path::new("build").join(&arch.name));
+            paths.push(Path::new(&output_dir).join(&arch.name));
+        },
+    }
+
+    // Paths are resolved first, so symlinks cannot lead outside of the project
+    let root = fs::canonicalize(".")?;
+    let mut freed = 0;
+    for path in paths {
+        if ! path.exists() {
+            continue;
+        }
+        let path = fs::canonicalize(&path)?;
+        if ! path.starts_with(&root) || path == root {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("refusing to remove '{}' outside of '{}'", path.display(), root.display())
+            ));
+        }
+        let size = dir_size(&path)?;
+        if dry_run() {
+            println!("- would remove {} ({} bytes)", path.display(), size);
+        } else {
+            info!(target: LOG_CLEAN, "- removing {} ({} bytes)", path.display(), size);
+            fs::remove_dir_all(&path)?;
+        }
+        freed += size;
+    }
+    println!("{} bytes {}", freed, if dry_run() { "would be freed" } else { "freed" });
+
+    Ok(())
+}
+
+// Subdirectories of a directory, sorted, or none when it does not exist
+fn sub_dirs(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if dir.is_dir() {
+        for entry_res in fs::read_dir(dir)? {
+            let path = entry_res?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+// A package as printed by list --json, without a version when the archive does not have it
+#[derive(Serialize)]
+struct ListedPkg<'a> {
+    name: &'a str,
+    version: Option<&'a str>,
+    patches: usize,
+}
+
+// Configured packages with their latest archive version and the number of patches applying to it
+fn list(pkg_dir: &Path, sbuild_dist: &str, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let json = take_flag(&mut args, "--json");
+    unknown_options(&args)?;
+
+    let pkgs = Pkg::load_all(pkg_dir)?;
+    let mut rows = Vec::new();
+    for (pkg, version_res) in pkgs.iter().zip(parallel_map(&pkgs, default_jobs(), |x| x.latest_version(sbuild_dist))) {
+        let version = version_res?;
+        let mut patches = 0;
+        if let Some(version) = &version {
+            for patch in pkg.patches.iter() {
+                if patch.applies(sbuild_dist, version)? {
+                    patches += 1;
+                }
+            }
+        }
+        rows.push((pkg.name.clone(), version, patches));
+    }
+
+    if json {
+        let listed: Vec<ListedPkg> = rows.iter().map(|(name, version, patches)| ListedPkg {
+            name,
+            version: version.as_deref(),
+            patches: *patches,
+        }).collect();
+        print!("{}", to_json(&listed)?);
+    } else {
+        let name_width = rows.iter().map(|x| x.0.len()).max().unwrap_or(0);
+        let version_width = rows.iter().map(|x| x.1.as_deref().unwrap_or("-").len()).max().unwrap_or(0);
+        for (name, version, patches) in rows.iter() {
+            println!(
+                "{:name_width$}  {:version_width$}  {}",
+                name,
+                version.as_deref().unwrap_or("-"),
+                patches,
+                name_width = name_width,
+                version_width = version_width,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Removes share directories left behind by failed or interrupted source downloads
+fn gc(args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    // Downloads still in progress are only removed once they are this old
+    let older_than = match take_value(&mut args, "--older-than")? {
+        Some(hours) => hours.parse::<u64>().map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid hours '{}': {}", hours, err)
+        ))?,
+        None => 24,
+    };
+    // Builds staged in a tmpdir have their work in progress there
+    let tmpdir = take_value(&mut args, "--tmpdir")?
+        .map(|dir| fs::canonicalize(&dir).unwrap_or_else(|_| PathBuf::from(dir)));
+    unknown_options(&args)?;
+
+    let parent_dir = Path::new(SHARE_PARENT_DIR);
+    if ! parent_dir.is_dir() {
+        info!(target: LOG_CLEAN, "- {} does not exist", parent_dir.display());
+        return Ok(());
+    }
+
+    // Build directories are canonical, so the tmpdir mirrors the absolute path
+    let build_parent_dir = fs::canonicalize("build").unwrap_or_else(|_| PathBuf::from("build"));
+    let mut entries = Vec::new();
+    for entry_res in fs::read_dir(parent_dir)? {
+        entries.push(entry_res?.path());
+    }
+    entries.sort();
+
+    for path in entries {
+        let share_dir = match path.file_name().and_then(|x| x.to_str()).and_then(ShareDir::parse) {
+            Some(some) => some,
+            None => continue,
+        };
+
+        let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+        let stale = age.as_secs() >= older_than * 60 * 60;
+        let version_dir = build_parent_dir
+            .join(&share_dir.arch)
+            .join(&share_dir.dist)
+            .join(&share_dir.name)
+            .join(&share_dir.version);
+        let in_progress = partial_dir(&version_dir, tmpdir.as_deref(), "source.partial").is_dir();
+        if in_progress && ! stale {
+            info!(target: LOG_CLEAN, "- keeping {}: download in progress", path.display());
+            continue;
+        }
+
+        if dry_run() {
+            println!("- would remove {}", path.display());
+        } else {
+            info!(target: LOG_CLEAN, "- removing {}", path.display());
+            fs::remove_dir_all(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn command_stdout(command: &mut process::Command) -> io::Result<String> {
+    let output = command
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    status_err(&command.get_program().to_string_lossy(), output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Packages held on this machine, one per line from apt-mark showhold
+fn parse_held(output: &str) -> Vec<String> {
+    output.lines().map(|x| x.trim().to_string()).filter(|x| ! x.is_empty()).collect()
+}
+
+fn held_packages() -> io::Result<Vec<String>> {
+    command_stdout(
+        process::Command::new("apt-mark").arg("showhold")
+    ).map(|x| parse_held(&x))
+}
+
+// Packages with a version specific pin in apt-cache policy output, which lists each package as an
+// unindented "name:" line followed by indented fields, including "Package pin:" when pinned
+fn parse_pinned(output: &str) -> Vec<String> {
+    let mut pinned = Vec::new();
+    let mut name = None;
+    for line in output.lines() {
+        if ! line.starts_with(' ') && line.ends_with(':') {
+            name = Some(line.trim_end_matches(':').to_string());
+        } else if line.trim_start().starts_with("Package pin:") {
+            if let Some(name) = name.take() {
+                pinned.push(name);
+            }
+        }
+    }
+    pinned
+}
+
+// Built packages that will not be upgraded on this machine, as they are held or pinned away
+fn held_or_pinned(names: &[String]) -> io::Result<Vec<String>> {
+    let held = held_packages()?;
+    let mut blocked: Vec<String> = names.iter().filter(|x| held.contains(x)).cloned().collect();
+    if ! names.is_empty() {
+        let policy = command_stdout(
+            process::Command::new("apt-cache").arg("policy").args(names)
+        )?;
+        for name in parse_pinned(&policy) {
+            if ! blocked.contains(&name) {
+                blocked.push(name);
+            }
+        }
+    }
+    blocked.sort();
+    Ok(blocked)
+}
+
+// Origin in the Release of an arch's repository, which apt pins and policy output refer to
+fn opt_origin(arch: &Arch) -> String {
+    format!("pop-os-opt-{}", arch.name)
+}
+
+// Packages to build by name or pattern, all of them when neither is given
+fn select_pkgs<'a>(pkgs: &'a [Pkg], names: &[String], patterns: &[glob::Pattern], blocklist: &[glob::Pattern], components: &[String]) -> io::Result<Vec<&'a Pkg>> {
+    let mut selected_pkgs = Vec::new();
+    for pkg in pkgs.iter() {
+        let selected = (names.is_empty() && patterns.is_empty())
+            || names.contains(&pkg.name)
+            || (
+                patterns.iter().any(|x| x.matches(&pkg.name))
+                && ! blocklist.iter().any(|x| x.matches(&pkg.name))
+            );
+        if ! selected {
+            debug!(target: LOG_BUILD, "- skipping {}", pkg.name);
+            continue;
+        }
+        if let Some(component) = &pkg.component {
+            if ! components.contains(component) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: component '{}' is not built, add it with --component", pkg.name, component)
+                ));
+            }
+        }
+        selected_pkgs.push(pkg);
+    }
+    Ok(selected_pkgs)
+}
+
+// Flattens debs into one directory, such as for attaching to a release. Debs already there are
+// kept, as the same file name is the same package
+fn export_debs(debs: &[PathBuf], export_dir: &Path) -> io::Result<()> {
+    for deb in debs.iter() {
+        let export_deb = export_dir.join(deb.file_name().unwrap());
+        if ! export_deb.is_file() {
+            link_or_copy(deb, &export_deb)?;
+        }
+    }
+    Ok(())
+}
+
+// Description in the Release of a suite, naming the build that published it
+fn release_description(arch: &Arch, dist: &str, dist_version: &str, build_id: &str) -> String {
+    format!("Pop!_OS Opt {} {} {} (build {})", dist, dist_version, arch.name, build_id)
+}
+
+// Package file of the installed packages in apt-cache policy output
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+
+// A package in apt-cache policy output
+#[derive(Clone, Debug, Default, PartialEq)]
+struct PolicyPkg {
+    name: String,
+    installed: Option<String>,
+    // Versions from newest to oldest, with the package files publishing them, such as
+    // "http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages"
+    versions: Vec<(String, Vec<String>)>,
+}
+
+// Packages in apt-cache policy output for some packages, where each version of the version table
+// is followed by more indented lines of its priority and package file
+fn parse_policy(output: &str) -> Vec<PolicyPkg> {
+    let mut pkgs: Vec<PolicyPkg> = Vec::new();
+    for line in output.lines() {
+        let indent = line.len() - line.trim_start().len();
+        let trimmed = line.trim();
+        if indent == 0 && trimmed.ends_with(':') {
+            pkgs.push(PolicyPkg {
+                name: trimmed.trim_end_matches(':').to_string(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let pkg = match pkgs.last_mut() {
+            Some(some) => some,
+            None => continue,
+        };
+        if let Some(version) = trimmed.strip_prefix("Installed: ") {
+            pkg.installed = Some(version.to_string()).filter(|x| x != "(none)");
+        } else if let Some(version_line) = trimmed.strip_prefix("*** ") {
+            let version = version_line.split_whitespace().next().unwrap_or("");
+            pkg.versions.push((version.to_string(), Vec::new()));
+        } else if indent == 5 && trimmed.contains(' ') {
+            let version = trimmed.split_whitespace().next().unwrap_or("");
+            pkg.versions.push((version.to_string(), Vec::new()));
+        } else if indent > 5 {
+            if let (Some((_, sources)), Some((_priority, source))) = (pkg.versions.last_mut(), trimmed.split_once(' ')) {
+                sources.push(source.to_string());
+            }
+        }
+    }
+    pkgs
+}
+
+// Origins of package files in apt-cache policy output without packages, where each package file
+// is followed by a "release" line with its Release fields, such as o=Ubuntu
+fn parse_policy_origins(output: &str) -> BTreeMap<String, String> {
+    let mut origins = BTreeMap::new();
+    let mut source = None;
+    for line in output.lines() {
+        let trimmed = line.trim();
+        if let Some(fields) = trimmed.strip_prefix("release ") {
+            let origin = fields.split(',').find_map(|x| x.strip_prefix("o="));
+            if let (Some(source), Some(origin)) = (source.take(), origin) {
+                origins.insert(source, origin.to_string());
+            }
+        } else if trimmed.starts_with(|x: char| x.is_ascii_digit()) {
+            source = trimmed.split_once(' ').map(|x| x.1.to_string());
+        }
+    }
+    origins
+}
+
+// Opt packages to downgrade, with packages skipped as held or without an archive version
+#[derive(Debug, Default, PartialEq)]
+struct Downgrades {
+    downgrades: Vec<(String, String)>,
+    held: Vec<String>,
+    unavailable: Vec<String>,
+}
+
+// Opt packages are published by the opt origin, and installed at a version that no other origin
+// publishes, so they are found whatever version suffix they were built with. Each is downgraded to
+// its newest version from another origin, unless it is held
+fn downgrade_set(
+    pkgs: &[PolicyPkg],
+    origins: &BTreeMap<String, String>,
+    opt_origin: &str,
+    held: &[String],
+) -> Downgrades {
+    let is_opt = |source: &String| origins.get(source).is_some_and(|x| x == opt_origin);
+    let is_other = |source: &String| source != DPKG_STATUS && ! is_opt(source);
+
+    let mut downgrades = Downgrades::default();
+    for pkg in pkgs.iter() {
+        let installed = match &pkg.installed {
+            Some(some) => some,
+            None => continue,
+        };
+        if ! pkg.versions.iter().any(|(_, sources)| sources.iter().any(is_opt)) {
+            continue;
+        }
+        let installed_sources = pkg.versions.iter()
+            .find(|(version, _)| version == installed)
+            .map_or(&[][..], |(_, sources)| sources.as_slice());
+        if installed_sources.iter().any(is_other) {
+            continue;
+        }
 
-    Ok(())
+        if held.contains(&pkg.name) {
+            downgrades.held.push(pkg.name.clone());
+            continue;
+        }
+        match pkg.versions.iter().find(|(version, sources)| version != installed && sources.iter().any(is_other)) {
+            Some((version, _)) => downgrades.downgrades.push((pkg.name.clone(), version.clone())),
+            None => downgrades.unavailable.push(pkg.name.clone()),
+        }
+    }
+    downgrades
 }
 
-fn chroot(_arch: &Arch) -> io::Result<()> {
-    //TODO: passed as argument
-    let sbuild_dist = "focal";
-    let sbuild_archs = ["amd64", "i386"];
-    let mirror = "http://archive.ubuntu.com/ubuntu";
-
-    let parent_dir = Path::new("/srv/chroot");
-    for sbuild_arch in sbuild_archs.iter() {
-        let name = format!("{}-{}-popopt", sbuild_dist, sbuild_arch);
-        println!("- chroot {}", name);
-        let dir = parent_dir.join(&name);
-        if ! dir.is_dir() {
-            process::Command::new("sudo")
-                .arg("sbuild-createchroot")
-                .arg(format!("--arch={}", sbuild_arch))
-                .arg("--chroot-suffix=-popopt")
-                .arg("--components=main,restricted,universe,multiverse")
-                .arg(format!("--extra-repository=deb {} {}-updates main restricted universe multiverse", mirror, sbuild_dist))
-                .arg(format!("--extra-repository=deb-src {} {}-updates main restricted universe multiverse", mirror, sbuild_dist))
-                .arg(format!("--extra-repository=deb {} {}-security main restricted universe multiverse", mirror, sbuild_dist))
-                .arg(format!("--extra-repository=deb-src {} {}-security main restricted universe multiverse", mirror, sbuild_dist))
-                .arg(sbuild_dist)
-                .arg(&dir)
-                .arg(mirror)
-                .status()
-                .and_then(status_err)?;
+// Downgrades installed opt packages to their archive versions, leaving held packages alone
+fn downgrade_safe(arch: &Arch) -> io::Result<()> {
+    let installed: Vec<String> = command_stdout(
+        process::Command::new("dpkg-query")
+            .arg("--show")
+            .arg("--showformat=${db:Status-Abbrev} ${Package}\n")
+    )?.lines().filter_map(|line| {
+        let mut parts = line.split_whitespace();
+        if parts.next() != Some("ii") {
+            return None;
         }
+        parts.next().map(|x| x.to_string())
+    }).collect();
+    if installed.is_empty() {
+        return Ok(());
+    }
 
-        process::Command::new("sudo")
-            .arg("sbuild-update")
-            .arg("--update")
-            .arg("--dist-upgrade")
-            .arg("--clean")
-            .arg("--autoclean")
-            .arg("--autoremove")
-            .arg(format!("--arch={}", sbuild_arch))
-            .arg(&name)
-            .status()
-            .and_then(status_err)?;
+    let policy = command_stdout(
+        process::Command::new("apt-cache").arg("policy").args(&installed)
+    )?;
+    let origins = command_stdout(
+        process::Command::new("apt-cache").arg("policy")
+    )?;
+    let downgrades = downgrade_set(
+        &parse_policy(&policy),
+        &parse_policy_origins(&origins),
+        &opt_origin(arch),
+        &held_packages()?
+    );
+    for name in downgrades.held.iter() {
+        warn!(target: LOG_REPO, "- warning: {} is held, not downgrading", name);
+    }
+    for name in downgrades.unavailable.iter() {
+        warn!(target: LOG_REPO, "- warning: {} has no archive version available, not downgrading", name);
+    }
+    if downgrades.downgrades.is_empty() {
+        return Ok(());
     }
 
-    Ok(())
+    let mut command = process::Command::new("sudo");
+    command
+        .arg("apt-get")
+        .arg("install")
+        .arg("--yes")
+        .arg("--allow-downgrades");
+    for (name, version) in downgrades.downgrades.iter() {
+        command.arg(format!("{}={}", name, version));
+    }
+    command.run()
+}
+
+fn list_source(url: &str, suite: &str, components: &[String]) -> String {
+    format!("deb {} {} {}", url, suite, components.join(" "))
+}
+
+fn deb822_source(url: &str, suite: &str, components: &[String], keyring_file: &Path) -> String {
+    let mut source = String::new();
+    writeln!(source, "Types: deb").unwrap();
+    writeln!(source, "URIs: {}", url).unwrap();
+    writeln!(source, "Suites: {}", suite).unwrap();
+    writeln!(source, "Components: {}", components.join(" ")).unwrap();
+    write!(source, "Signed-By: {}", keyring_file.display()).unwrap();
+    source
 }
 
+const REPO_HELP: &str = "\
+Usage: pop-opt repo [OPTIONS]
+
+Adds the opt repository of the selected arch and upgrades to its packages
+
+Options:
+  -r                      Remove the repository and downgrade to archive packages
+  --force-downgrade-safe  With -r, only downgrade opt packages that have an archive version
+                          and are not held
+  --deb822                Write a deb822 .sources file with Signed-By
+  --component NAME        Component to add, can be repeated [default: main]
+  --priority N            Pin priority of the opt packages [default: 1001]. Above 1000, opt
+                          packages replace newer archive versions, downgrading them, and
+                          archive updates, including security updates, do not replace an opt
+                          package of the same name until a rebuild is published. Use 500 to
+                          prefer the newest version instead
+  --repo-url URL          Base URL of the repositories, or a URL with an {arch} placeholder
+                          [default: https://apt.pop-os.org/opt/]
+";
+
 fn repo(arch: &Arch, args: &[String]) -> io::Result<()> {
-    let remove = args.contains(&"-r".to_string());
+    let mut args = args.to_vec();
+    if take_flag(&mut args, "--help") {
+        print!("{}", REPO_HELP);
+        return Ok(());
+    }
+    let remove = take_flag(&mut args, "-r");
+    // Only downgrade opt packages that have an archive replacement and are not held
+    let downgrade_safe_mode = take_flag(&mut args, "--force-downgrade-safe");
+    // Write a deb822 .sources file with Signed-By instead of a one-line .list
+    let deb822 = take_flag(&mut args, "--deb822");
+    // Components to add, which must match the ones the repository was built with
+    let components = take_components(&mut args)?;
+    // Pin priority of the opt packages. Above 1000, they are installed even over newer archive
+    // versions, as long as the pin is in place. This forces downgrades to opt packages, and keeps
+    // archive security updates from replacing a package with the same name until it is rebuilt
+    let priority = match take_value(&mut args, "--priority")? {
+        Some(priority) => priority.parse::<u32>().map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid priority '{}': {}", priority, err)
+        ))?,
+        None => 1001,
+    };
+    // Base URL of the repositories of every arch, or a full URL with an {arch} placeholder
+    let repo_url = take_value(&mut args, "--repo-url")?.unwrap_or_else(|| "https://apt.pop-os.org/opt/".to_string());
+    if ! is_mirror_url(&repo_url) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid repository URL '{}', expected an http or https URL", repo_url)
+        ));
+    }
+    if repo_url.starts_with("http://") {
+        warn!(target: LOG_REPO, "- warning: repository URL '{}' does not use https", repo_url);
+    }
+    unknown_options(&args)?;
+    let mut tools = vec!["apt-get", "sudo"];
+    if deb822 {
+        tools.push("curl");
+    }
+    if remove && downgrade_safe_mode {
+        tools.extend(["apt-cache", "apt-mark", "dpkg-query"]);
+    }
+    require_tools(&tools)?;
 
-    let url = format!("https://apt.pop-os.org/opt/{}/", arch.name);
-    println!("- {} {}", if remove { "Removing" } else { "Adding" }, url);
+    let url = if repo_url.contains("{arch}") {
+        repo_url.replace("{arch}", &arch.name)
+    } else {
+        format!("{}/{}/", repo_url.trim_end_matches('/'), arch.name)
+    };
+    // The key is downloaded relative to the URL
+    let url = if url.ends_with('/') { url } else { format!("{}/", url) };
+    info!(target: LOG_REPO, "- {} {}", if remove { "Removing" } else { "Adding" }, url);
 
-    //TODO: something better than this preferences hack to remove opt packages
+    // Opt packages are pinned while the repository is added. When removing, the pin is replaced
+    // by one for the archive, as apt only downgrades to versions pinned at 1000 or more
     let pref_file = Path::new("/etc/apt/preferences.d/popopt");
-    if remove {
-        process::Command::new("sudo")
-            .arg("bash")
-            .arg("-c")
-            .arg(format!(
-                "echo 'Package: *\nPin: release o=Ubuntu\nPin-Priority: 1000' > '{}'",
-                pref_file.display()
-            ))
-            .status()
-            .and_then(status_err)?;
+    let list_file = Path::new("/etc/apt/sources.list.d/popopt.list");
+    let sources_file = Path::new("/etc/apt/sources.list.d/popopt.sources");
+    let keyring_file = Path::new("/etc/apt/keyrings/popopt.asc");
+    // Every file written or removed below, kept so a failed update can restore them
+    let snapshot = snapshot_files(&[pref_file, list_file, sources_file, keyring_file]);
+    if remove && downgrade_safe_mode {
+        downgrade_safe(arch)?;
+    } else if remove {
+        write_pin(pref_file, "o=Ubuntu", 1000)?;
 
         process::Command::new("sudo")
             .arg("apt-get")
             .arg("upgrade")
             .arg("--yes")
             .arg("--allow-downgrades")
-            .status()
-            .and_then(status_err)?;
+            .run()?;
     }
 
     process::Command::new("sudo")
         .arg("rm")
         .arg("--force")
         .arg("--verbose")
-        .arg(&pref_file)
-        .status()
-        .and_then(status_err)?;
+        .arg(pref_file)
+        .run()?;
 
-    let source_file = Path::new("/etc/apt/sources.list.d/popopt.list");
+    let source_file = if deb822 { sources_file } else { list_file };
     if remove {
+        // Either format may have been used when adding
         process::Command::new("sudo")
             .arg("rm")
             .arg("--force")
             .arg("--verbose")
-            .arg(&source_file)
-            .status()
-            .and_then(status_err)?;
+            .arg(list_file)
+            .arg(sources_file)
+            .arg(keyring_file)
+            .run()?;
     } else {
         let os_release = os_release::OsRelease::new()?;
-        let source = format!("deb {} {} main", url, os_release.version_codename);
+        let source = if deb822 {
+            // The repository publishes its signing key next to the dists
+            process::Command::new("sudo")
+                .arg("install")
+                .arg("--directory")
+                .arg("--mode=0755")
+                .arg(keyring_file.parent().unwrap())
+                .run()?;
+            process::Command::new("sudo")
+                .arg("curl")
+                .arg("--fail")
+                .arg("--silent")
+                .arg("--show-error")
+                .arg("--location")
+                .arg("--output").arg(keyring_file)
+                .arg(format!("{}popopt.asc", url))
+                .run()?;
+            deb822_source(&url, &os_release.version_codename, &components, keyring_file)
+        } else {
+            list_source(&url, &os_release.version_codename, &components)
+        };
 
-        process::Command::new("sudo")
-            .arg("bash")
-            .arg("-c")
-            .arg(format!(
-                "echo '{}' > '{}'",
-                source,
-                source_file.display()
-            ))
-            .status()
-            .and_then(status_err)?;
+        sudo_write(source_file, &format!("{}\n", source))?;
+
+        write_pin(pref_file, &format!("o={}", opt_origin(arch)), priority)?;
     }
 
-    process::Command::new("sudo")
-        .arg("apt-get")
-        .arg("update")
-        .status()
-        .and_then(status_err)?;
+    if let Err(err) = apt_update_retry(3) {
+        error!(target: LOG_REPO, "apt-get update failed: {}", err);
+        eprint!("Restore the previous {}? [y/N] ", source_file.display());
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            // The pin and keyring of the new source are also restored, so they cannot outlive it
+            for (path, previous) in snapshot.iter() {
+                sudo_restore(path, previous.as_deref())?;
+            }
+        }
+        return Err(err);
+    }
 
     process::Command::new("sudo")
         .arg("apt-get")
         .arg("upgrade")
         .arg("--yes")
-        .status()
-        .and_then(status_err)?;
+        .run()?;
 
     Ok(())
 }
 
-fn pop_opt(args: &[String]) -> io::Result<()> {
-    let cpu_features = Arch::cpu_features()?;
-    println!("CPU features: {:?}", cpu_features);
-    println!();
+// Contents of files before they are changed, None for those that do not exist
+fn snapshot_files(paths: &[&Path]) -> Vec<(PathBuf, Option<String>)> {
+    paths.iter().map(|x| (x.to_path_buf(), fs::read_to_string(x).ok())).collect()
+}
+
+// Restores a root owned file to its previous content, or removes it if it did not exist
+fn sudo_restore(path: &Path, previous: Option<&str>) -> io::Result<()> {
+    match previous {
+        Some(content) => sudo_write(path, content),
+        None => process::Command::new("sudo")
+            .arg("rm")
+            .arg("--force")
+            .arg("--verbose")
+            .arg(path)
+            .run(),
+    }
+}
+
+// Writes an apt preferences file pinning every package of a release, such as o=Ubuntu
+fn write_pin(pref_file: &Path, release: &str, priority: u32) -> io::Result<()> {
+    sudo_write(pref_file, &format!("Package: *\nPin: release {}\nPin-Priority: {}\n", release, priority))
+}
 
-    let archs = Arch::load_all("arch/x86_64")?;
-    let mut highest = None;
-    for arch in archs {
-        match arch.check_features(&cpu_features) {
+// Writes a file as root with sudo tee. The content is passed on stdin, so no shell parses it
+fn sudo_write(path: &Path, content: &str) -> io::Result<()> {
+    use std::io::Write as _;
+
+    if dry_run() {
+        println!("- would write {}:\n{}", path.display(), content);
+        return Ok(());
+    }
+    debug!(target: LOG_REPO, "- writing {}", path.display());
+    let mut child = process::Command::new("sudo")
+        .arg("tee")
+        .arg("--")
+        .arg(path)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()?;
+    // Dropping stdin closes it, so tee sees the end of the content. A failed write is reported
+    // after the exit status, which explains it when tee exited early
+    let write_res = child.stdin.take().unwrap().write_all(content.as_bytes());
+    status_err("sudo", child.wait()?)?;
+    write_res
+}
+
+// Run apt-get update, retrying with a doubling delay on failure
+fn apt_update_retry(attempts: u32) -> io::Result<()> {
+    let mut delay = time::Duration::from_secs(2);
+    let mut attempt = 1;
+    loop {
+        let res = process::Command::new("sudo")
+            .arg("apt-get")
+            .arg("update")
+            .run();
+        match res {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt >= attempts => return Err(err),
+            Err(err) => {
+                warn!(target: LOG_REPO, "- warning: apt-get update attempt {} of {} failed: {}, retrying in {:?}", attempt, attempts, err, delay);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Ubuntu release codenames and versions
+static DIST_VERSIONS: &[(&str, &str)] = &[
+    ("focal", "20.04"),
+    ("jammy", "22.04"),
+    ("noble", "24.04"),
+];
+
+fn dist_version(codename: &str) -> Option<&'static str> {
+    DIST_VERSIONS.iter().find(|x| x.0 == codename).map(|x| x.1)
+}
+
+// The arch selected by name, or the highest one the CPU features support
+fn detect_arch(arch_dir: &Path, cpu_features: &[String], arch_name: Option<&str>) -> io::Result<Arch> {
+    let mut archs = Arch::load_all(arch_dir)?;
+    // Selected by level rather than file order, so definitions can be named freely
+    let mut highest: Option<usize> = None;
+    for (i, arch) in archs.iter().enumerate() {
+        match arch.check_features(cpu_features) {
             Ok(()) => {
-                println!("{}: Supported", arch.name);
-                highest = Some(arch);
+                info!(target: LOG_ARCH, "{} (level {}): Supported", arch.name, arch.level);
+                if highest.is_none_or(|x| archs[x].level <= arch.level) {
+                    highest = Some(i);
+                }
             },
-            Err(missing) => {
-                println!("{}: Missing {:?}", arch.name, missing);
-            }
+            Err(Error::MissingFeatures(missing)) => {
+                info!(target: LOG_ARCH, "{} (level {}): Missing {:?}", arch.name, arch.level, missing);
+            },
+            Err(err) => return Err(err.into()),
         }
     }
 
-    let arch = match highest {
-        Some(some) => some,
-        None => return Err(io::Error::new(
+    match arch_name {
+        Some(name) => match archs.iter().position(|x| x.name == name) {
+            Some(i) => {
+                let arch = archs.remove(i);
+                info!(target: LOG_ARCH, "");
+                info!(target: LOG_ARCH, "{} (level {}): Selected arch", arch.name, arch.level);
+                Ok(arch)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("unknown arch '{}'", name)
+            ))
+        },
+        None => match highest {
+            Some(i) => {
+                let arch = archs.remove(i);
+                info!(target: LOG_ARCH, "");
+                info!(target: LOG_ARCH, "{} (level {}): Highest arch found", arch.name, arch.level);
+                Ok(arch)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no optimization level found"
+            ))
+        },
+    }
+}
+
+// Selected arch and its flags, as written by detect --json
+#[derive(Serialize)]
+struct DetectedFlags<'a> {
+    arch: &'a str,
+    level: u32,
+    cflags: Vec<String>,
+    cxxflags: Vec<String>,
+    rustflags: Vec<String>,
+}
+
+// Prints the selected arch and its flags, or writes them as JSON for scripts
+fn detect(arch: &Arch, args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    let json = take_flag(&mut args, "--json");
+    let output = take_value(&mut args, "--output")?;
+    unknown_options(&args)?;
+
+    if json || output.is_some() {
+        let value = to_json(&DetectedFlags {
+            arch: &arch.name,
+            level: arch.level,
+            cflags: arch.cflags(),
+            cxxflags: arch.cxxflags(),
+            rustflags: arch.rustflags(),
+        })?;
+        match output {
+            Some(output) => fs::write(output, value)?,
+            None => print!("{}", value),
+        }
+    } else {
+        println!("arch: {}", arch.name);
+        println!("level: {}", arch.level);
+        println!("cflags: {}", arch.cflags().join(" "));
+        println!("cxxflags: {}", arch.cxxflags().join(" "));
+        println!("rustflags: {}", arch.rustflags().join(" "));
+    }
+
+    Ok(())
+}
+
+fn pop_opt(args: &[String]) -> io::Result<()> {
+    let mut args = args.to_vec();
+    // Only print warnings and errors, or also details such as the commands run. RUST_LOG sets
+    // the level when neither is given
+    let mut logger = env_logger::Builder::new();
+    logger
+        .format(|buf, record| {
+            // Log lines share stderr with the progress line
+            progress::clear_line(buf)?;
+            io::Write::write_fmt(buf, format_args!("{}\n", record.args()))
+        })
+        .filter_level(LevelFilter::Info)
+        .parse_default_env();
+    // Only print errors and a final summary, for CI logs
+    if take_flag(&mut args, "--quiet") | take_flag(&mut args, "--summary-only") {
+        logger.filter_level(LevelFilter::Warn);
+    }
+    if take_flag(&mut args, "-v") | take_flag(&mut args, "--verbose") {
+        logger.filter_level(LevelFilter::Debug);
+    }
+    // Already set when called again, such as from tests
+    let _ = logger.try_init();
+    // Target a specific arch instead of the highest one supported by this CPU
+    let arch_name = take_value(&mut args, "--arch")?;
+    // Print commands that change state instead of running them
+    set_dry_run(take_flag(&mut args, "--dry-run"));
+    // Architectures to build, the first of which also builds architecture independent packages
+    let sbuild_archs = take_values(&mut args, "--sbuild-arch")?;
+    let sbuild_archs: Vec<&str> = if sbuild_archs.is_empty() {
+        vec!["amd64", "i386"]
+    } else {
+        sbuild_archs.iter().map(|x| x.as_str()).collect()
+    };
+    // Arch definitions are grouped by machine architecture family, such as x86_64 or aarch64
+    let arch_family = take_value(&mut args, "--arch-family")?.unwrap_or_else(|| host_family().to_string());
+    // Definition directories, relative to the working directory by default. Patches are
+    // relative to the parent of the package directory
+    let arch_dir = match take_value(&mut args, "--arch-dir")? {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new("arch").join(&arch_family),
+    };
+    if ! arch_dir.is_dir() {
+        return Err(io::Error::new(
             io::ErrorKind::NotFound,
-            "no optimization level found"
-        ))
+            format!("no arch definitions for family '{}' in '{}'", arch_family, arch_dir.display())
+        ));
+    }
+    let dirs = DefinitionDirs {
+        arch_dir,
+        pkg_dir: PathBuf::from(take_value(&mut args, "--pkg-dir")?.unwrap_or_else(|| "pkg".to_string())),
+    };
+    // Compare Debian versions without dpkg
+    version::set_native(take_flag(&mut args, "--native-version-compare"));
+    // Key to sign the repository with, instead of the previously used or default key
+    let gpg_key = take_value(&mut args, "--gpg-key")?;
+    // Armored secret key to sign with, such as from a CI secret, without using the keyring
+    let gpg_key_file = take_value(&mut args, "--gpg-key-file")?;
+    // Ubuntu release to build for, with its version looked up unless given
+    let sbuild_dist = take_value(&mut args, "--dist")?.unwrap_or_else(|| "focal".to_string());
+    let sbuild_dist_version = match take_value(&mut args, "--dist-version")? {
+        Some(version) => version,
+        None => dist_version(&sbuild_dist).map(|x| x.to_string()).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown dist '{}', use --dist-version to set its version", sbuild_dist)
+        ))?,
     };
+    // Features reported by the CPU but unusable, such as when disabled by microcode, or the reverse
+    let require_features = take_values(&mut args, "--require-feature")?;
+    let exclude_features = take_values(&mut args, "--exclude-feature")?;
+    for feature in require_features.iter().chain(exclude_features.iter()) {
+        if feature_info(feature).is_none() {
+            warn!(target: LOG_ARCH, "- warning: unknown feature '{}'", feature);
+        }
+    }
 
-    println!();
-    println!("{}: Highest arch found", arch.name);
-    println!("cflags: {:?}", arch.cflags());
-    println!("rustflags: {:?}", arch.rustflags());
-    println!();
+    let cpu_features = Arch::override_features(Arch::cpu_features()?, &require_features, &exclude_features);
+    info!(target: LOG_ARCH, "CPU features: {:?}", cpu_features);
+    info!(target: LOG_ARCH, "");
+
+    let arch = detect_arch(&dirs.arch_dir, &cpu_features, arch_name.as_deref())?;
+
+    info!(target: LOG_ARCH, "cflags: {:?}", arch.cflags());
+    info!(target: LOG_ARCH, "rustflags: {:?}", arch.rustflags());
+    info!(target: LOG_ARCH, "");
 
-    match args.get(0).map(|x| x.as_str()) {
+    match args.first().map(|x| x.as_str()) {
         None => Ok(()),
-        Some("build") => build(&arch, &args[1..]),
-        Some("chroot") => chroot(&arch),
+        Some("build") => match &gpg_key_file {
+            Some(key_file) => build_with_key_file(key_file, |key| {
+                build(&arch, &dirs, &sbuild_dist, &sbuild_dist_version, &sbuild_archs, key, &args[1..])
+            }, gpg_key.as_deref()),
+            None => build(&arch, &dirs, &sbuild_dist, &sbuild_dist_version, &sbuild_archs, gpg_key.as_deref(), &args[1..]),
+        },
+        Some("check") => check(&dirs, &sbuild_dist, &args[1..]),
+        Some("check-compat") => check_compat(&dirs.arch_dir, &cpu_features, &args[1..]),
+        Some("chroot") => chroot(&arch, &sbuild_dist, &sbuild_archs, &args[1..]),
+        Some("clean") => clean(&arch, &args[1..]),
+        Some("detect") => detect(&arch, &args[1..]),
+        Some("dput") => dput(&arch, &dirs.pkg_dir, &sbuild_dist, &args[1..]),
+        Some("explain") => explain(&arch, &dirs.arch_dir, &args[1..]),
+        Some("gc") => gc(&args[1..]),
+        Some("list") => list(&dirs.pkg_dir, &sbuild_dist, &args[1..]),
         Some("repo") => repo(&arch, &args[1..]),
-        Some(arg) => Err(io::Error::new(
-            io::ErrorKind::Other,
+        Some("verify") => verify(&arch, &sbuild_dist, &args[1..]),
+        Some("verify-release") => verify_release(&args[1..]),
+        Some(arg) => Err(io::Error::other(
             format!("unknown subcommand '{}'", arg)
         ))
     }
 }
 
 fn main() {
-    if unsafe { libc::signal(libc::SIGINT, interrupt as libc::sighandler_t) == libc::SIG_ERR } {
+    if unsafe { libc::signal(libc::SIGINT, interrupt as *const () as libc::sighandler_t) == libc::SIG_ERR } {
         panic!("failed to handle SIGINT");
     }
 
@@ -335,8 +2641,817 @@ fn main() {
     match pop_opt(&args) {
         Ok(()) => (),
         Err(err) => {
-            eprintln!("pop-opt {:?}: {}", args, err);
+            error!("pop-opt {:?}: {}", args, err);
             process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    // apt-cache policy, without packages, with the opt repository added
+    const POLICY_ORIGINS: &str = "\
+Package files:
+ 100 /var/lib/dpkg/status
+     release a=now
+ 1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+     release o=pop-os-opt-haswell,a=jammy,n=jammy,l=pop-os-opt-haswell,c=main,b=amd64
+     origin apt.pop-os.org
+ 500 http://archive.ubuntu.com/ubuntu jammy-updates/main amd64 Packages
+     release v=22.04,o=Ubuntu,a=jammy-updates,n=jammy,l=Ubuntu,c=main,b=amd64
+     origin archive.ubuntu.com
+ 500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+     release v=22.04,o=Ubuntu,a=jammy,n=jammy,l=Ubuntu,c=main,b=amd64
+     origin archive.ubuntu.com
+Pinned packages:
+";
+
+    // apt-cache policy for installed packages: an opt build, an opt build newer in the repository
+    // than installed, an opt build without an archive version, and archive packages
+    const POLICY: &str = "\
+libc6:
+  Installed: 2.35-0ubuntu3.1popopt4
+  Candidate: 2.35-0ubuntu3.1popopt4
+  Version table:
+ *** 2.35-0ubuntu3.1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+     2.35-0ubuntu3.1 500
+        500 http://archive.ubuntu.com/ubuntu jammy-updates/main amd64 Packages
+     2.35-0ubuntu3 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+python3.10:
+  Installed: 3.10.6-1~22.04popopt4
+  Candidate: 3.10.6-1~22.04.1popopt4
+  Version table:
+     3.10.6-1~22.04.1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+ *** 3.10.6-1~22.04popopt4 100
+        100 /var/lib/dpkg/status
+     3.10.6-1~22.04.1 500
+        500 http://archive.ubuntu.com/ubuntu jammy-updates/main amd64 Packages
+libopt-only:
+  Installed: 1.0-1popopt4
+  Candidate: 1.0-1popopt4
+  Version table:
+ *** 1.0-1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+bash:
+  Installed: 5.1-6ubuntu1
+  Candidate: 5.1-6ubuntu1
+  Version table:
+ *** 5.1-6ubuntu1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+zstd:
+  Installed: 1.4.8+dfsg-3build1
+  Candidate: 1.4.8+dfsg-3build1popopt4
+  Version table:
+     1.4.8+dfsg-3build1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+ *** 1.4.8+dfsg-3build1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+libzstd-doc:
+  Installed: (none)
+  Candidate: 1.4.8+dfsg-3build1popopt4
+  Version table:
+     1.4.8+dfsg-3build1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+";
+
+    #[test]
+    fn parse_policy_versions() {
+        let pkgs = parse_policy(POLICY);
+        assert_eq!(pkgs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec![
+            "libc6", "python3.10", "libopt-only", "bash", "zstd", "libzstd-doc",
+        ]);
+        assert_eq!(pkgs[0], PolicyPkg {
+            name: "libc6".to_string(),
+            installed: Some("2.35-0ubuntu3.1popopt4".to_string()),
+            versions: vec![
+                ("2.35-0ubuntu3.1popopt4".to_string(), strings(&[
+                    "https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages",
+                    DPKG_STATUS,
+                ])),
+                ("2.35-0ubuntu3.1".to_string(), strings(&[
+                    "http://archive.ubuntu.com/ubuntu jammy-updates/main amd64 Packages",
+                ])),
+                ("2.35-0ubuntu3".to_string(), strings(&[
+                    "http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages",
+                ])),
+            ],
+        });
+        assert_eq!(pkgs[5].installed, None);
+    }
+
+    #[test]
+    fn parse_policy_origins_by_package_file() {
+        let origins = parse_policy_origins(POLICY_ORIGINS);
+        assert_eq!(origins.len(), 3);
+        assert_eq!(origins["https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages"], "pop-os-opt-haswell");
+        assert_eq!(origins["http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages"], "Ubuntu");
+        // The status file has no origin
+        assert!(! origins.contains_key(DPKG_STATUS));
+    }
+
+    #[test]
+    fn downgrade_set_opt_packages() {
+        let downgrades = downgrade_set(
+            &parse_policy(POLICY),
+            &parse_policy_origins(POLICY_ORIGINS),
+            "pop-os-opt-haswell",
+            &[]
+        );
+        assert_eq!(downgrades, Downgrades {
+            // The newest archive version is chosen
+            downgrades: vec![
+                ("libc6".to_string(), "2.35-0ubuntu3.1".to_string()),
+                ("python3.10".to_string(), "3.10.6-1~22.04.1".to_string()),
+            ],
+            held: Vec::new(),
+            unavailable: strings(&["libopt-only"]),
+        });
+    }
+
+    #[test]
+    fn downgrade_set_held() {
+        let downgrades = downgrade_set(
+            &parse_policy(POLICY),
+            &parse_policy_origins(POLICY_ORIGINS),
+            "pop-os-opt-haswell",
+            &strings(&["libc6", "bash"])
+        );
+        assert_eq!(downgrades.downgrades, vec![("python3.10".to_string(), "3.10.6-1~22.04.1".to_string())]);
+        assert_eq!(downgrades.held, strings(&["libc6"]));
+    }
+
+    #[test]
+    fn downgrade_set_other_origin() {
+        // Packages of another arch's repository are left alone
+        let downgrades = downgrade_set(
+            &parse_policy(POLICY),
+            &parse_policy_origins(POLICY_ORIGINS),
+            "pop-os-opt-skylake",
+            &[]
+        );
+        assert_eq!(downgrades, Downgrades::default());
+    }
+
+    #[test]
+    fn downgrade_set_by_origin_not_suffix() {
+        let opt = "https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages";
+        let archive = "http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages";
+        let pkgs = vec![
+            // Built with --version-suffix +opt{arch}, without popopt in the version
+            PolicyPkg {
+                name: "zlib1g".to_string(),
+                installed: Some("1:1.2.11.dfsg-2ubuntu9+opthaswell".to_string()),
+                versions: vec![
+                    ("1:1.2.11.dfsg-2ubuntu9+opthaswell".to_string(), strings(&[opt, DPKG_STATUS])),
+                    ("1:1.2.11.dfsg-2ubuntu9".to_string(), strings(&[archive])),
+                ],
+            },
+            // popopt in a version of another origin does not make it an opt package
+            PolicyPkg {
+                name: "libpopopt".to_string(),
+                installed: Some("1.0-1popopt1".to_string()),
+                versions: vec![
+                    ("1.0-1popopt1".to_string(), strings(&[archive, DPKG_STATUS])),
+                    ("0.9-1".to_string(), strings(&[archive])),
+                ],
+            },
+        ];
+        let origins = parse_policy_origins(POLICY_ORIGINS);
+        let downgrades = downgrade_set(&pkgs, &origins, "pop-os-opt-haswell", &[]);
+        assert_eq!(downgrades, Downgrades {
+            downgrades: vec![("zlib1g".to_string(), "1:1.2.11.dfsg-2ubuntu9".to_string())],
+            held: Vec::new(),
+            unavailable: Vec::new(),
+        });
+    }
+
+    fn summary(name: &str, version: &str, arch: &str, outcomes: Vec<(&str, BuildOutcome)>) -> BuildSummary {
+        BuildSummary {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.to_string(),
+            archs: Vec::new(),
+            success: ! outcomes.iter().any(|(_, x)| matches!(x, BuildOutcome::Failed(_))),
+            outcomes: outcomes.into_iter().map(|(sbuild_arch, x)| (sbuild_arch.to_string(), x)).collect(),
+        }
+    }
+
+    #[test]
+    fn summary_table_aligned() {
+        let table = summary_table(&[
+            summary("glibc", "2.35-0ubuntu3.1", "haswell", vec![
+                ("amd64", BuildOutcome::Built(12)),
+                ("i386", BuildOutcome::Built(9)),
+            ]),
+            summary("gcc-9", "9.4.0-5ubuntu1", "ivybridge", vec![
+                ("amd64", BuildOutcome::Built(104)),
+            ]),
+        ]);
+        assert_eq!(table, vec![
+            "glibc  2.35-0ubuntu3.1  haswell    amd64   12  built",
+            "glibc  2.35-0ubuntu3.1  haswell    i386     9  built",
+            "gcc-9  9.4.0-5ubuntu1   ivybridge  amd64  104  built",
+        ]);
+    }
+
+    #[test]
+    fn summary_table_failures() {
+        let table = summary_table(&[
+            summary("python3.10", "3.10.6-1~22.04.1", "haswell", vec![
+                ("amd64", BuildOutcome::Failed("sbuild exited with status 1".to_string())),
+                ("arm64", BuildOutcome::Skipped("not in architectures".to_string())),
+            ]),
+            summary("zstd", "1.4.8+dfsg-3build1", "haswell", vec![
+                ("amd64", BuildOutcome::Failed("source: patch does not apply".to_string())),
+            ]),
+        ]);
+        assert_eq!(table, vec![
+            "python3.10  3.10.6-1~22.04.1    haswell  amd64  -  failed: sbuild exited with status 1",
+            "python3.10  3.10.6-1~22.04.1    haswell  arm64  -  skipped: not in architectures",
+            "zstd        1.4.8+dfsg-3build1  haswell  amd64  -  failed: source: patch does not apply",
+        ]);
+        assert!(summary_table(&[]).is_empty());
+    }
+
+    // The definitions shipped in arch/x86_64
+    fn x86_64_archs() -> Vec<Arch> {
+        Arch::load_all(Path::new(env!("CARGO_MANIFEST_DIR")).join("arch/x86_64")).unwrap()
+    }
+
+    fn names(archs: &[&Arch]) -> Vec<String> {
+        archs.iter().map(|x| x.name.clone()).collect()
+    }
+
+    #[test]
+    fn fallback_archs_walk_down() {
+        let archs = x86_64_archs();
+        let skylake = archs.iter().find(|x| x.name == "skylake").unwrap();
+        assert_eq!(names(&fallback_archs(&archs, skylake)), strings(&["broadwell", "haswell", "ivybridge", "sandybridge"]));
+        let haswell = archs.iter().find(|x| x.name == "haswell").unwrap();
+        assert_eq!(names(&fallback_archs(&archs, haswell)), strings(&["ivybridge", "sandybridge"]));
+        let sandybridge = archs.iter().find(|x| x.name == "sandybridge").unwrap();
+        assert!(fallback_archs(&archs, sandybridge).is_empty());
+    }
+
+    #[test]
+    fn fallback_archs_by_level() {
+        // Levels decide the order, not the order the definitions were loaded in
+        let mut archs = x86_64_archs();
+        archs.reverse();
+        archs.rotate_left(2);
+        let skylake = archs.iter().find(|x| x.name == "skylake").unwrap().clone();
+        let levels: Vec<u32> = fallback_archs(&archs, &skylake).iter().map(|x| x.level).collect();
+        assert_eq!(levels, vec![5, 4, 3, 2]);
+    }
+
+    #[test]
+    fn tool_version_equal() {
+        assert!(tool_version_compatible("0.81.2ubuntu6", "0.81.2ubuntu6"));
+        assert!(tool_version_compatible("1.6.10-12", "1.6.10-12"));
+    }
+
+    #[test]
+    fn tool_version_newer() {
+        // Newer patch releases and revisions are compatible, newer minor or major versions are not
+        assert!(tool_version_compatible("1.6.10-12", "1.6.10-13"));
+        assert!(tool_version_compatible("1.6.10-12", "1.6.11-1"));
+        assert!(! tool_version_compatible("1.6.10-12", "1.7.0-1"));
+        assert!(! tool_version_compatible("0.81.2ubuntu6", "0.85.0-1"));
+        assert!(! tool_version_compatible("1.6.10-12", "2.6.10-12"));
+    }
+
+    #[test]
+    fn tool_version_older() {
+        assert!(tool_version_compatible("1.6.10-13", "1.6.9-1"));
+        assert!(! tool_version_compatible("1.7.0-1", "1.6.10-12"));
+        assert!(! tool_version_compatible("0.85.0-1", "0.81.2ubuntu6"));
+    }
+
+    #[test]
+    fn tool_version_epoch_and_revision() {
+        // Epochs and revisions are ignored, including hyphens in the revision
+        assert!(tool_version_compatible("1:1.6.10-12", "1.6.10-12"));
+        assert!(tool_version_compatible("1.6.10", "1.6.10-1ubuntu1-2"));
+    }
+
+    #[test]
+    fn tool_mismatches_listed() {
+        let recorded = "sbuild 0.81.2ubuntu6\nschroot 1.6.10-12\n";
+        assert!(tool_mismatches(recorded, "schroot 1.6.10-13\nsbuild 0.81.2ubuntu6\n").is_empty());
+        assert_eq!(tool_mismatches(recorded, "sbuild 0.85.0-1\n"), strings(&[
+            "sbuild 0.85.0-1 (chroot created with 0.81.2ubuntu6)",
+            "schroot not installed (chroot created with 1.6.10-12)",
+        ]));
+    }
+
+    #[test]
+    fn pool_subdir_by_letter() {
+        assert_eq!(pool_subdir("by-letter", None, "glibc"), Path::new("g/glibc"));
+        assert_eq!(pool_subdir("by-letter", None, "gcc-9"), Path::new("g/gcc-9"));
+        // Sources starting with lib are grouped by their first four letters
+        assert_eq!(pool_subdir("by-letter", None, "libzstd"), Path::new("libz/libzstd"));
+        assert_eq!(pool_subdir("by-letter", None, "libx"), Path::new("libx/libx"));
+        assert_eq!(pool_subdir("by-letter", None, "lib"), Path::new("l/lib"));
+        assert_eq!(pool_subdir("by-letter", None, "linux"), Path::new("l/linux"));
+    }
+
+    #[test]
+    fn pool_subdir_single_letter() {
+        assert_eq!(pool_subdir("by-letter", None, "r"), Path::new("r/r"));
+        assert_eq!(pool_subdir("source", None, "r"), Path::new("r"));
+    }
+
+    #[test]
+    fn pool_subdir_component() {
+        assert_eq!(pool_subdir("source", None, "libzstd"), Path::new("libzstd"));
+        assert_eq!(pool_subdir("source", Some("main"), "libzstd"), Path::new("main/libzstd"));
+        assert_eq!(pool_subdir("by-letter", Some("non-free"), "libzstd"), Path::new("non-free/libz/libzstd"));
+    }
+
+    #[test]
+    fn parse_held_lines() {
+        assert_eq!(parse_held("libc6\nzstd\n\n  python3.10  \n"), strings(&["libc6", "zstd", "python3.10"]));
+        assert!(parse_held("").is_empty());
+    }
+
+    #[test]
+    fn parse_pinned_packages() {
+        // apt-cache policy, with libc6 pinned to a version by a preferences file
+        let policy = "\
+libc6:
+  Installed: 2.35-0ubuntu3
+  Candidate: 2.35-0ubuntu3
+  Package pin: 2.35-0ubuntu3
+  Version table:
+     2.35-0ubuntu3.1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+ *** 2.35-0ubuntu3 1002
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+zstd:
+  Installed: 1.4.8+dfsg-3build1
+  Candidate: 1.4.8+dfsg-3build1popopt4
+  Version table:
+     1.4.8+dfsg-3build1popopt4 1001
+       1001 https://apt.pop-os.org/opt/haswell/ jammy/main amd64 Packages
+ *** 1.4.8+dfsg-3build1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+";
+        assert_eq!(parse_pinned(policy), strings(&["libc6"]));
+        assert!(parse_pinned(POLICY).is_empty());
+    }
+
+    #[test]
+    fn take_components_default() {
+        let mut args = strings(&["-r"]);
+        assert_eq!(take_components(&mut args).unwrap(), strings(&["main"]));
+        assert_eq!(args, strings(&["-r"]));
+
+        let mut args = strings(&["--component", "main", "--component=non-free"]);
+        assert_eq!(take_components(&mut args).unwrap(), strings(&["main", "non-free"]));
+        assert!(args.is_empty());
+
+        assert!(take_components(&mut strings(&["--component", "main/x"])).is_err());
+    }
+
+    #[test]
+    fn list_source_components() {
+        let url = "https://apt.pop-os.org/opt/x86-64-v3/";
+        assert_eq!(
+            list_source(url, "focal", &strings(&["main"])),
+            "deb https://apt.pop-os.org/opt/x86-64-v3/ focal main"
+        );
+        assert_eq!(
+            list_source(url, "focal", &strings(&["main", "non-free"])),
+            "deb https://apt.pop-os.org/opt/x86-64-v3/ focal main non-free"
+        );
+    }
+
+    #[test]
+    fn deb822_source_components() {
+        let url = "https://apt.pop-os.org/opt/x86-64-v3/";
+        let keyring_file = Path::new("/etc/apt/keyrings/popopt.asc");
+        assert_eq!(
+            deb822_source(url, "jammy", &strings(&["main"]), keyring_file),
+            "Types: deb\n\
+            URIs: https://apt.pop-os.org/opt/x86-64-v3/\n\
+            Suites: jammy\n\
+            Components: main\n\
+            Signed-By: /etc/apt/keyrings/popopt.asc"
+        );
+        assert!(
+            deb822_source(url, "jammy", &strings(&["main", "non-free"]), keyring_file)
+                .contains("\nComponents: main non-free\n")
+        );
+    }
+
+    fn command_args(command: &process::Command) -> Vec<String> {
+        command.get_args().map(|x| x.to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn dput_command_args() {
+        let changes_file = Path::new("build/haswell/jammy/zstd/1.4.8+dfsg-3build1/source/zstd_1.4.8+dfsg-3build1popopt4_source.changes");
+        let command = dput_command("ppa:system76/pop-opt", changes_file);
+        assert_eq!(command.get_program(), "dput");
+        assert_eq!(command_args(&command), vec![
+            "ppa:system76/pop-opt".to_string(),
+            changes_file.display().to_string(),
+        ]);
+    }
+
+    #[test]
+    fn debsigs_command_per_deb() {
+        let debs = [
+            Path::new("repo/haswell/pool/jammy/zstd/zstd_1.4.8+dfsg-3build1popopt4_amd64.deb"),
+            Path::new("repo/haswell/pool/jammy/zstd/libzstd1_1.4.8+dfsg-3build1popopt4_amd64.deb"),
+        ];
+        for deb in debs.iter() {
+            let command = debsigs_command(deb, "origin", None);
+            assert_eq!(command.get_program(), "debsigs");
+            assert_eq!(command_args(&command), vec!["--sign=origin".to_string(), deb.display().to_string()]);
+
+            let command = debsigs_command(deb, "origin", Some("0123456789ABCDEF"));
+            assert_eq!(command_args(&command), vec![
+                "--sign=origin".to_string(),
+                "-k".to_string(),
+                "0123456789ABCDEF".to_string(),
+                deb.display().to_string(),
+            ]);
+        }
+    }
+
+    #[test]
+    fn apt_auth_command_target() {
+        let command = apt_auth_command(Path::new("/home/user/auth.conf"), Path::new("/srv/chroot/jammy-amd64-popopt"));
+        assert_eq!(command.get_program(), "sudo");
+        let args = command_args(&command);
+        assert_eq!(args[0], "install");
+        assert!(args.contains(&"--group=sbuild".to_string()));
+        assert_eq!(&args[args.len() - 2..], &[
+            "/home/user/auth.conf",
+            "/srv/chroot/jammy-amd64-popopt/etc/apt/auth.conf.d/popopt.conf",
+        ]);
+    }
+
+    #[test]
+    fn compress_command_level() {
+        let file = Path::new("repo/haswell/dists/jammy/main/binary-amd64/Packages");
+        let command = compress_command(file, "xz", Some(0));
+        assert_eq!(command.get_program(), "xz");
+        assert_eq!(command_args(&command), vec!["--keep".to_string(), "-0".to_string(), file.display().to_string()]);
+
+        let command = compress_command(file, "gzip", None);
+        assert_eq!(command_args(&command), vec!["--keep".to_string(), file.display().to_string()]);
+    }
+
+    #[test]
+    fn compression_level_per_compressor() {
+        assert_eq!(check_compression_level("0", &["xz"]).unwrap(), 0);
+        assert_eq!(check_compression_level("9", &["gzip", "xz"]).unwrap(), 9);
+        // gzip has no level 0, so both fail with it
+        let err = check_compression_level("0", &["gzip", "xz"]).unwrap_err();
+        assert!(err.to_string().contains("gzip levels are 1 to 9"), "{}", err);
+        assert!(check_compression_level("10", &["xz"]).is_err());
+        assert!(check_compression_level("fast", &["gzip"]).is_err());
+        // Without compressors, any level is unused
+        assert_eq!(check_compression_level("0", &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn release_description_build_id() {
+        let archs = x86_64_archs();
+        let haswell = archs.iter().find(|x| x.name == "haswell").unwrap();
+        assert_eq!(
+            release_description(haswell, "jammy", "22.04", "20230101T000000Z"),
+            "Pop!_OS Opt jammy 22.04 haswell (build 20230101T000000Z)"
+        );
+    }
+
+    #[test]
+    fn check_definitions_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |file: &str, contents: &str| {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("arch/4_haswell.toml", "level = 4\nname = \"haswell\"\nwiki = \"\"\nfeatures = [\"avx2\"]\n");
+        write("arch/5_broadwell.toml", "level = 4\nname = \"broadwell\"\nwiki = \"\"\nfeatures = []\n");
+        write("arch/6_skylake.toml", "level = \"six\"\n");
+        write("patch/glibc.patch", "");
+        write("pkg/glibc.toml", "name = \"glibc\"\npatches = [\"patch/glibc.patch\"]\n");
+        write("pkg/gcc-9.toml", "name = \"gcc-9\"\npatches = [\"patch/gcc-9.patch\"]\n");
+        write("pkg/group/glibc.toml", "name = \"glibc\"\n");
+        write("pkg/zstd.toml", "name = \"zstd\"\nsource_dir = \"source/zstd\"\n");
+        write("pkg/xz-utils.toml", "patches = []\n");
+
+        let dirs = DefinitionDirs {
+            arch_dir: dir.path().join("arch"),
+            pkg_dir: dir.path().join("pkg"),
+        };
+        let relative = |problem: &String| problem.replace(&format!("{}/", dir.path().display()), "");
+        let problems: Vec<String> = check_definitions(&dirs, None).iter().map(relative).collect();
+        // Every problem is reported, not only the first
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+        assert_eq!(problems[0], "arch/5_broadwell.toml: no features listed");
+        assert_eq!(problems[1], "arch/5_broadwell.toml: level 4 already used by arch/4_haswell.toml");
+        assert!(problems[2].starts_with("arch/6_skylake.toml: "));
+        assert_eq!(problems[3], "pkg/gcc-9.toml: patch 'patch/gcc-9.patch' does not exist");
+        assert_eq!(problems[4], "pkg/group/glibc.toml: package 'glibc' already defined by pkg/glibc.toml");
+        assert!(problems[5].starts_with("pkg/xz-utils.toml: "));
+        assert_eq!(problems[6], "pkg/zstd.toml: source 'source/zstd' has no debian/changelog");
+
+        // A missing directory is a problem of its own
+        let dirs = DefinitionDirs {
+            arch_dir: dir.path().join("arch"),
+            pkg_dir: dir.path().join("missing"),
+        };
+        let problems: Vec<String> = check_definitions(&dirs, None).iter().map(relative).collect();
+        assert!(problems.last().unwrap().starts_with("missing: "));
+
+        // The definitions in the repository are valid
+        let dirs = DefinitionDirs {
+            arch_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("arch/x86_64"),
+            pkg_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("pkg"),
+        };
+        assert!(check_definitions(&dirs, None).is_empty());
+    }
+
+    #[test]
+    fn compat_report_missing() {
+        let archs = x86_64_archs();
+        let haswell = archs.iter().find(|x| x.name == "haswell").unwrap();
+        let (lines, result) = compat_report(haswell, &haswell.features);
+        assert_eq!(lines, strings(&["haswell: Supported"]));
+        assert!(result.is_ok());
+
+        let sandybridge = archs.iter().find(|x| x.name == "sandybridge").unwrap();
+        let (lines, result) = compat_report(haswell, &sandybridge.features);
+        assert!(lines.contains(&"- missing avx2".to_string()), "{:?}", lines);
+        assert!(lines.iter().all(|x| x.starts_with("- missing ")));
+        let err = result.unwrap_err().to_string();
+        assert!(err.starts_with("host is missing haswell features: "), "{}", err);
+        assert!(err.contains("avx2") && err.contains("fma"), "{}", err);
+
+        // Unknown targets fail before comparing
+        let arch_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("arch/x86_64");
+        let err = check_compat(&arch_dir, &sandybridge.features, &strings(&["--target", "znver9"])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(check_compat(&arch_dir, &sandybridge.features, &strings(&["--target", "haswell"])).is_err());
+        assert!(check_compat(&arch_dir, &haswell.features, &strings(&["--target", "sandybridge"])).is_ok());
+    }
+
+    #[test]
+    fn release_problems_tampered_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let dists_dir = dir.path().join("dists/jammy");
+        let packages = "Package: zstd\nVersion: 1.4.8+dfsg-3build1popopt4\n";
+        let sources = "Package: zstd\n";
+        for (name, contents) in [("main/binary-amd64/Packages", packages), ("main/source/Sources", sources)].iter() {
+            let path = dists_dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+        }
+        let mut release = "Suite: jammy\nSHA256:\n".to_string();
+        for name in ["main/binary-amd64/Packages", "main/source/Sources"].iter() {
+            let path = dists_dir.join(name);
+            writeln!(
+                release, " {} {} {}",
+                sha256(&path).unwrap(), fs::metadata(&path).unwrap().len(), name
+            ).unwrap();
+        }
+        // Indices listed but not published are skipped
+        writeln!(release, " {} 0 main/binary-arm64/Packages", "0".repeat(64)).unwrap();
+        let repo = dists_dir.to_string_lossy().to_string();
+
+        let (verified, problems) = release_problems(&release, &repo, &dists_dir).unwrap();
+        assert_eq!(verified, 2);
+        assert!(problems.is_empty());
+
+        // Same size, different contents
+        fs::write(dists_dir.join("main/binary-amd64/Packages"), packages.replace("popopt4", "popopt5")).unwrap();
+        let (verified, problems) = release_problems(&release, &repo, &dists_dir).unwrap();
+        assert_eq!(verified, 1);
+        assert_eq!(problems, vec![("main/binary-amd64/Packages".to_string(), "SHA256 does not match".to_string())]);
+
+        fs::write(dists_dir.join("main/source/Sources"), "Package: zstd-doc\n").unwrap();
+        let (verified, problems) = release_problems(&release, &repo, &dists_dir).unwrap();
+        assert_eq!(verified, 0);
+        assert_eq!(problems[1], (
+            "main/source/Sources".to_string(),
+            format!("size 18 does not match {}", sources.len())
+        ));
+    }
+
+    #[test]
+    fn export_debs_flattened() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool_dir = dir.path().join("pool/jammy");
+        let debs: Vec<PathBuf> = [
+            "glibc/libc6_2.35-0ubuntu3popopt4_amd64.deb",
+            "glibc/libc6_2.35-0ubuntu3popopt4_i386.deb",
+            "glibc/libc-bin_2.35-0ubuntu3popopt4_amd64.deb",
+            "zstd/zstd_1.4.8+dfsg-3build1popopt4_amd64.deb",
+        ].iter().map(|x| pool_dir.join(x)).collect();
+        for deb in debs.iter() {
+            fs::create_dir_all(deb.parent().unwrap()).unwrap();
+            fs::write(deb, deb.file_name().unwrap().to_string_lossy().as_bytes()).unwrap();
+        }
+        let export_dir = ensure_dir(dir.path().join("export")).unwrap();
+        // Left from a previous export
+        fs::write(export_dir.join("zstd_1.4.8+dfsg-3build1popopt4_amd64.deb"), "previous").unwrap();
+
+        export_debs(&debs, &export_dir).unwrap();
+        let mut exported: Vec<String> = fs::read_dir(&export_dir).unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        exported.sort();
+        assert_eq!(exported, strings(&[
+            "libc-bin_2.35-0ubuntu3popopt4_amd64.deb",
+            "libc6_2.35-0ubuntu3popopt4_amd64.deb",
+            "libc6_2.35-0ubuntu3popopt4_i386.deb",
+            "zstd_1.4.8+dfsg-3build1popopt4_amd64.deb",
+        ]));
+        assert_eq!(
+            fs::read_to_string(export_dir.join("libc6_2.35-0ubuntu3popopt4_i386.deb")).unwrap(),
+            "libc6_2.35-0ubuntu3popopt4_i386.deb"
+        );
+        assert_eq!(fs::read_to_string(export_dir.join("zstd_1.4.8+dfsg-3build1popopt4_amd64.deb")).unwrap(), "previous");
+    }
+
+    #[test]
+    fn compress_indices_all_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        for component in ["main", "non-free"].iter() {
+            for sbuild_arch in ["amd64", "arm64", "i386"].iter() {
+                let file = dir.path().join(component).join(format!("binary-{}", sbuild_arch)).join("Packages");
+                fs::create_dir_all(file.parent().unwrap()).unwrap();
+                fs::write(&file, format!("Package: zstd\nArchitecture: {}\n", sbuild_arch)).unwrap();
+                files.push(file);
+            }
+            let file = dir.path().join(component).join("i18n/Translation-en");
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, "Package: zstd\n").unwrap();
+            files.push(file);
+        }
+
+        compress_indices(&files, &["gzip", "xz"], Some(1), 3).unwrap();
+        for file in files.iter() {
+            // The uncompressed index is kept, as it is also published
+            assert!(file.is_file());
+            for extension in ["gz", "xz"].iter() {
+                let compressed = PathBuf::from(format!("{}.{}", file.display(), extension));
+                assert!(compressed.is_file(), "{}", compressed.display());
+            }
+        }
+
+        // A failed compression fails the step
+        let missing = vec![dir.path().join("main/binary-riscv64/Packages")];
+        assert!(compress_indices(&missing, &["gzip"], None, 3).is_err());
+    }
+
+    #[test]
+    fn sign_suites_each_suite() {
+        let dir = tempfile::tempdir().unwrap();
+        let gnupg_home = ensure_dir(dir.path().join("gnupg")).unwrap();
+        fs::set_permissions(&gnupg_home, fs::Permissions::from_mode(0o700)).unwrap();
+        // Only this test runs gpg, so the home is not shared with others
+        env::set_var("GNUPGHOME", &gnupg_home);
+        process::Command::new("gpg")
+            .arg("--batch")
+            .arg("--passphrase").arg("")
+            .arg("--quick-generate-key").arg("Pop!_OS Opt Test <opt@example.com>")
+            .arg("default").arg("default").arg("never")
+            .stderr(process::Stdio::null())
+            .run()
+            .unwrap();
+
+        let dists_parent_dir = ensure_dir(dir.path().join("dists")).unwrap();
+        for suite in ["focal", "jammy"].iter() {
+            let suite_dir = ensure_dir(dists_parent_dir.join(suite)).unwrap();
+            fs::write(suite_dir.join("Release"), format!("Suite: {}\n", suite)).unwrap();
+        }
+        // Directories without a Release are not suites
+        ensure_dir(dists_parent_dir.join("noble")).unwrap();
+
+        let res = sign_suites(&dists_parent_dir, None);
+        let verified: Vec<bool> = ["focal", "jammy"].iter().map(|suite| {
+            let suite_dir = dists_parent_dir.join(suite);
+            let verify = |args: &[&Path]| process::Command::new("gpg")
+                .arg("--batch")
+                .arg("--verify")
+                .args(args)
+                .stderr(process::Stdio::null())
+                .status()
+                .unwrap()
+                .success();
+            verify(&[&suite_dir.join("InRelease")])
+                && verify(&[&suite_dir.join("Release.gpg"), &suite_dir.join("Release")])
+        }).collect();
+        let _ = process::Command::new("gpgconf").arg("--kill").arg("gpg-agent").status();
+        env::remove_var("GNUPGHOME");
+
+        res.unwrap();
+        assert_eq!(verified, vec![true, true]);
+        assert!(! dists_parent_dir.join("noble/InRelease").exists());
+    }
+
+    #[test]
+    fn targeted_build_selection() {
+        let pkg_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("pkg");
+        let pkgs = Pkg::load_all(&pkg_dir).unwrap();
+        let main = strings(&["main"]);
+        let pkg_names = |selected: Vec<&Pkg>| -> Vec<String> {
+            selected.iter().map(|x| x.name.clone()).collect()
+        };
+
+        // --only selects just that package
+        let selected = select_pkgs(&pkgs, &strings(&["glibc"]), &[], &[], &main).unwrap();
+        assert_eq!(pkg_names(selected), strings(&["glibc"]));
+        // Everything without names or patterns
+        assert_eq!(select_pkgs(&pkgs, &[], &[], &[], &main).unwrap().len(), pkgs.len());
+        assert_eq!(pkg_names(select_pkgs(&pkgs, &[], &glob_patterns(&strings(&["gcc-*"])).unwrap(), &[], &main).unwrap()), strings(&["gcc-9"]));
+
+        // A named arch is selected even when the CPU does not support it
+        let arch_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("arch/x86_64");
+        let archs = x86_64_archs();
+        let sandybridge = archs.iter().find(|x| x.name == "sandybridge").unwrap();
+        let arch = detect_arch(&arch_dir, &sandybridge.features, Some("skylake")).unwrap();
+        assert_eq!(arch.name, "skylake");
+        assert_eq!(detect_arch(&arch_dir, &sandybridge.features, None).unwrap().name, "sandybridge");
+        assert_eq!(
+            detect_arch(&arch_dir, &sandybridge.features, Some("znver9")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn select_pkgs_component() {
+        let pkgs: Vec<Pkg> = vec![
+            toml::from_str("name = \"nvidia-graphics-drivers\"\ncomponent = \"non-free\"\n").unwrap(),
+        ];
+        let err = select_pkgs(&pkgs, &[], &[], &[], &strings(&["main"])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(select_pkgs(&pkgs, &[], &[], &[], &strings(&["main", "non-free"])).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn snapshot_files_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let list_file = dir.path().join("popopt.list");
+        let sources_file = dir.path().join("popopt.sources");
+        let keyring_file = dir.path().join("popopt.asc");
+        fs::write(&list_file, "deb https://apt.pop-os.org/opt/haswell/ jammy main\n").unwrap();
+        fs::write(&keyring_file, "-----BEGIN PGP PUBLIC KEY BLOCK-----\n").unwrap();
+
+        let snapshot = snapshot_files(&[&list_file, &sources_file, &keyring_file]);
+        assert_eq!(snapshot, vec![
+            (list_file, Some("deb https://apt.pop-os.org/opt/haswell/ jammy main\n".to_string())),
+            // Missing files are removed when restoring
+            (sources_file, None),
+            (keyring_file, Some("-----BEGIN PGP PUBLIC KEY BLOCK-----\n".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn select_pkgs_match_blocklist() {
+        let pkgs: Vec<Pkg> = ["gcc-9", "gst-plugins-base1.0", "gst-plugins-good1.0", "gstreamer1.0", "libgstreamer"]
+            .iter()
+            .map(|x| toml::from_str(&format!("name = \"{}\"\n", x)).unwrap())
+            .collect();
+        let main = strings(&["main"]);
+        let select = |names: &[&str], patterns: &[&str], blocklist: &[&str]| -> Vec<String> {
+            select_pkgs(
+                &pkgs,
+                &strings(names),
+                &glob_patterns(&strings(patterns)).unwrap(),
+                &glob_patterns(&strings(blocklist)).unwrap(),
+                &main
+            ).unwrap().iter().map(|x| x.name.clone()).collect()
+        };
+
+        assert_eq!(select(&[], &["gst*"], &[]), strings(&["gst-plugins-base1.0", "gst-plugins-good1.0", "gstreamer1.0"]));
+        assert_eq!(select(&["gcc-9"], &["gst-plugins-*"], &[]), strings(&["gcc-9", "gst-plugins-base1.0", "gst-plugins-good1.0"]));
+        // Blocked packages are never matched, but are built when named
+        assert_eq!(select(&[], &["gst*"], &["gst-plugins-[bg]*"]), strings(&["gstreamer1.0"]));
+        assert_eq!(select(&["gst-plugins-good1.0"], &["gst*"], &["gst-plugins-*"]), strings(&["gst-plugins-good1.0", "gstreamer1.0"]));
+        // A blocklist alone does not limit building everything
+        assert_eq!(select(&[], &[], &["gst*"]).len(), pkgs.len());
+    }
+}
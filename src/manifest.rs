@@ -0,0 +1,340 @@
+// Record of the packages published in a suite, compared between runs to list changes
+
+use crate::to_json;
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    fmt::Write,
+    fs,
+    io,
+    path::Path,
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestPkg {
+    pub name: String,
+    // Source version in the archive
+    pub version: String,
+    // Arch the package was built for, which may be lower than the manifest's
+    pub arch: String,
+    #[serde(default)]
+    pub archs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub build_id: String,
+    pub arch: String,
+    pub dist: String,
+    #[serde(default)]
+    pub packages: Vec<ManifestPkg>,
+}
+
+impl Manifest {
+    // The manifest of a previous run, if there was one
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Option<Self>> {
+        if ! path.as_ref().is_file() {
+            return Ok(None);
+        }
+        let data = fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(&data)?))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, to_json(self)?)
+    }
+}
+
+// A deb published in a repository, for auditing and downstream tooling
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestDeb {
+    pub dist: String,
+    // Path in the repository, as in the Filename field of Packages
+    pub file: String,
+    pub package: String,
+    pub version: String,
+    pub architecture: String,
+    pub size: u64,
+    // Modification time in seconds since the epoch, to tell whether the entry can be reused
+    #[serde(default)]
+    pub mtime: u64,
+    pub sha256: String,
+}
+
+// Inventory of the debs of every suite in a repository, replaced a suite at a time
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DebManifest {
+    #[serde(default)]
+    pub debs: Vec<ManifestDeb>,
+}
+
+impl DebManifest {
+    // An empty manifest when there is none yet
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        if ! path.as_ref().is_file() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, to_json(self)?)
+    }
+
+    // The entry of a deb, if its size and modification time are unchanged. The pool is recreated
+    // on every build, so a deb at the same path may still have been rebuilt or signed again
+    pub fn reusable(&self, dist: &str, file: &str, size: u64, mtime: u64) -> Option<&ManifestDeb> {
+        self.debs.iter().find(|x| x.dist == dist && x.file == file && x.size == size && x.mtime == mtime)
+    }
+
+    // Replaces the debs of a suite, keeping those of other suites
+    pub fn replace_dist(&mut self, dist: &str, debs: Vec<ManifestDeb>) {
+        self.debs.retain(|x| x.dist != dist);
+        self.debs.extend(debs);
+        self.debs.sort_by(|a, b| (&a.dist, &a.file).cmp(&(&b.dist, &b.file)));
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Changes {
+    pub added: Vec<ManifestPkg>,
+    // Previous and current package
+    #[serde(serialize_with = "serialize_updated")]
+    pub updated: Vec<(ManifestPkg, ManifestPkg)>,
+    pub removed: Vec<ManifestPkg>,
+}
+
+// An updated package as written to CHANGES.json
+#[derive(Serialize)]
+struct Update<'a> {
+    name: &'a str,
+    old_version: &'a str,
+    new_version: &'a str,
+    old_arch: &'a str,
+    new_arch: &'a str,
+}
+
+fn serialize_updated<S: Serializer>(updated: &[(ManifestPkg, ManifestPkg)], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(updated.iter().map(|(old, new)| Update {
+        name: &new.name,
+        old_version: &old.version,
+        new_version: &new.version,
+        old_arch: &old.arch,
+        new_arch: &new.arch,
+    }))
+}
+
+impl Changes {
+    pub fn between(old: &Manifest, new: &Manifest) -> Self {
+        let mut changes = Self::default();
+        for package in new.packages.iter() {
+            match old.packages.iter().find(|x| x.name == package.name) {
+                Some(old_package) => if old_package.version != package.version || old_package.arch != package.arch {
+                    changes.updated.push((old_package.clone(), package.clone()));
+                },
+                None => changes.added.push(package.clone()),
+            }
+        }
+        for package in old.packages.iter() {
+            if ! new.packages.iter().any(|x| x.name == package.name) {
+                changes.removed.push(package.clone());
+            }
+        }
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn to_markdown(&self, manifest: &Manifest) -> String {
+        let mut markdown = String::new();
+        writeln!(markdown, "# {} {} (build {})", manifest.arch, manifest.dist, manifest.build_id).unwrap();
+        if self.is_empty() {
+            writeln!(markdown).unwrap();
+            writeln!(markdown, "No changes").unwrap();
+        }
+        if ! self.added.is_empty() {
+            writeln!(markdown, "\n## Added\n").unwrap();
+            for package in self.added.iter() {
+                writeln!(markdown, "- {} {}", package.name, package.version).unwrap();
+            }
+        }
+        if ! self.updated.is_empty() {
+            writeln!(markdown, "\n## Updated\n").unwrap();
+            for (old, new) in self.updated.iter() {
+                if old.arch == new.arch {
+                    writeln!(markdown, "- {} {} → {}", new.name, old.version, new.version).unwrap();
+                } else {
+                    writeln!(
+                        markdown, "- {} {} ({}) → {} ({})",
+                        new.name, old.version, old.arch, new.version, new.arch
+                    ).unwrap();
+                }
+            }
+        }
+        if ! self.removed.is_empty() {
+            writeln!(markdown, "\n## Removed\n").unwrap();
+            for package in self.removed.iter() {
+                writeln!(markdown, "- {} {}", package.name, package.version).unwrap();
+            }
+        }
+        markdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(name: &str, version: &str, arch: &str) -> ManifestPkg {
+        ManifestPkg {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.to_string(),
+            archs: vec!["amd64".to_string()],
+        }
+    }
+
+    fn manifest(packages: Vec<ManifestPkg>) -> Manifest {
+        Manifest {
+            build_id: "20230101T000000Z".to_string(),
+            arch: "haswell".to_string(),
+            dist: "jammy".to_string(),
+            packages,
+        }
+    }
+
+    #[test]
+    fn changes_between() {
+        let old = manifest(vec![
+            pkg("gcc-9", "9.4.0-5ubuntu1", "haswell"),
+            pkg("glibc", "2.35-0ubuntu3", "haswell"),
+            pkg("python3.10", "3.10.6-1~22.04", "haswell"),
+            pkg("zstd", "1.4.8+dfsg-3build1", "haswell"),
+        ]);
+        let new = manifest(vec![
+            pkg("gcc-9", "9.4.0-5ubuntu1", "haswell"),
+            pkg("glibc", "2.35-0ubuntu3.1", "haswell"),
+            pkg("python3.10", "3.10.6-1~22.04", "ivybridge"),
+            pkg("xz-utils", "5.2.5-2ubuntu1", "haswell"),
+        ]);
+        let changes = Changes::between(&old, &new);
+        assert_eq!(changes.added, vec![pkg("xz-utils", "5.2.5-2ubuntu1", "haswell")]);
+        // Both version and arch changes are updates
+        assert_eq!(changes.updated, vec![
+            (pkg("glibc", "2.35-0ubuntu3", "haswell"), pkg("glibc", "2.35-0ubuntu3.1", "haswell")),
+            (pkg("python3.10", "3.10.6-1~22.04", "haswell"), pkg("python3.10", "3.10.6-1~22.04", "ivybridge")),
+        ]);
+        assert_eq!(changes.removed, vec![pkg("zstd", "1.4.8+dfsg-3build1", "haswell")]);
+        assert!(! changes.is_empty());
+    }
+
+    #[test]
+    fn changes_between_first_run() {
+        let new = manifest(vec![pkg("glibc", "2.35-0ubuntu3", "haswell")]);
+        let changes = Changes::between(&Manifest::default(), &new);
+        assert_eq!(changes.added, new.packages);
+        assert!(changes.updated.is_empty() && changes.removed.is_empty());
+        assert!(Changes::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn changes_json() {
+        let old = manifest(vec![pkg("glibc", "2.35-0ubuntu3", "haswell")]);
+        let new = manifest(vec![pkg("glibc", "2.35-0ubuntu3.1", "haswell")]);
+        assert_eq!(to_json(&Changes::between(&old, &new)).unwrap(), r#"{
+  "added": [],
+  "updated": [
+    {
+      "name": "glibc",
+      "old_version": "2.35-0ubuntu3",
+      "new_version": "2.35-0ubuntu3.1",
+      "old_arch": "haswell",
+      "new_arch": "haswell"
+    }
+  ],
+  "removed": []
+}
+"#);
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        assert!(Manifest::load(&path).unwrap().is_none());
+
+        let saved = manifest(vec![pkg("glibc", "2.35-0ubuntu3", "haswell")]);
+        saved.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.build_id, saved.build_id);
+        assert_eq!(loaded.packages, saved.packages);
+
+        // Manifests missing required fields are rejected
+        fs::write(&path, r#"{"arch": "haswell", "dist": "jammy"}"#).unwrap();
+        assert_eq!(Manifest::load(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    fn manifest_deb(dist: &str, file: &str, size: u64, mtime: u64) -> ManifestDeb {
+        ManifestDeb {
+            dist: dist.to_string(),
+            file: file.to_string(),
+            package: "zstd".to_string(),
+            version: "1.4.8+dfsg-3build1popopt4".to_string(),
+            architecture: "amd64".to_string(),
+            size,
+            mtime,
+            sha256: "0".repeat(64),
+        }
+    }
+
+    #[test]
+    fn deb_manifest_reusable() {
+        let file = "pool/jammy/zstd/zstd_1.4.8+dfsg-3build1popopt4_amd64.deb";
+        let deb_manifest = DebManifest {
+            debs: vec![
+                manifest_deb("focal", file, 100, 1000),
+                manifest_deb("jammy", file, 100, 1000),
+            ],
+        };
+        assert_eq!(deb_manifest.reusable("jammy", file, 100, 1000), Some(&deb_manifest.debs[1]));
+        // A deb rebuilt or signed again at the same path is hashed again
+        assert_eq!(deb_manifest.reusable("jammy", file, 100, 1001), None);
+        assert_eq!(deb_manifest.reusable("jammy", file, 101, 1000), None);
+        assert_eq!(deb_manifest.reusable("noble", file, 100, 1000), None);
+        assert_eq!(deb_manifest.reusable("jammy", "pool/jammy/zstd/other.deb", 100, 1000), None);
+    }
+
+    #[test]
+    fn deb_manifest_without_mtime() {
+        // Manifests from before mtimes were recorded load, but their entries are never reused
+        let deb_manifest: DebManifest = serde_json::from_str(r#"{"debs": [{
+            "dist": "jammy",
+            "file": "pool/jammy/zstd/zstd.deb",
+            "package": "zstd",
+            "version": "1.4.8+dfsg-3build1popopt4",
+            "architecture": "amd64",
+            "size": 100,
+            "sha256": "00"
+        }]}"#).unwrap();
+        assert_eq!(deb_manifest.debs[0].mtime, 0);
+        assert_eq!(deb_manifest.reusable("jammy", "pool/jammy/zstd/zstd.deb", 100, 1000), None);
+    }
+
+    #[test]
+    fn changes_markdown_build_id() {
+        let old = manifest(vec![pkg("glibc", "2.35-0ubuntu3", "haswell")]);
+        let mut new = manifest(vec![pkg("glibc", "2.35-0ubuntu3.1", "haswell")]);
+        new.build_id = "20230102T000000Z".to_string();
+        let markdown = Changes::between(&old, &new).to_markdown(&new);
+        assert_eq!(markdown, "\
+# haswell jammy (build 20230102T000000Z)
+
+## Updated
+
+- glibc 2.35-0ubuntu3 → 2.35-0ubuntu3.1
+");
+        assert!(Changes::between(&new, &new).to_markdown(&new).starts_with("# haswell jammy (build 20230102T000000Z)\n"));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    RunCommand,
+    control,
+    ensure_dir,
+    sha256,
+    status_err,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    io,
+    path::Path,
+    process,
+};
+
+// Patches listed in an Index, older ones are dropped so clients that far behind download the
+// full index instead
+pub const MAX_PATCHES: usize = 30;
+
+// A patch in a Packages.diff/Index, with the hash and size of the index it applies to, of the
+// patch itself, and of its compressed download
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexPatch {
+    pub name: String,
+    pub history: (String, u64),
+    pub patch: (String, u64),
+    pub download: (String, u64),
+}
+
+// Patches of a previous Packages.diff with their compressed files, read before the repository is
+// cleaned so they can be published again
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    pub patches: Vec<(IndexPatch, Vec<u8>)>,
+}
+
+impl History {
+    // Empty when there is no Index yet. A patch whose file is missing ends the history, as the
+    // patches before it could not be chained past it
+    pub fn read(diff_dir: &Path) -> io::Result<Self> {
+        let index_file = diff_dir.join("Index");
+        if ! index_file.is_file() {
+            return Ok(Self::default());
+        }
+        let mut patches = Vec::new();
+        for patch in parse_index(&fs::read_to_string(&index_file)?).into_iter().rev() {
+            let download_file = diff_dir.join(format!("{}.gz", patch.name));
+            if ! download_file.is_file() {
+                break;
+            }
+            let data = fs::read(&download_file)?;
+            patches.push((patch, data));
+        }
+        patches.reverse();
+        Ok(Self { patches })
+    }
+}
+
+// Patches of an Index in history order. Lines are `hash size name`, and patches missing from a
+// section are skipped
+pub fn parse_index(data: &str) -> Vec<IndexPatch> {
+    let paragraph = control::parse(data).into_iter().next().unwrap_or_default();
+    let entries = |field: &str| -> Vec<(String, (String, u64))> {
+        paragraph.lines(field).iter().filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let hash = words.next()?.to_string();
+            let size = words.next()?.parse().ok()?;
+            let name = words.next()?.to_string();
+            Some((name, (hash, size)))
+        }).collect()
+    };
+    let patches: BTreeMap<String, (String, u64)> = entries("SHA256-Patches").into_iter().collect();
+    let downloads: BTreeMap<String, (String, u64)> = entries("SHA256-Download").into_iter()
+        .map(|(name, entry)| (name.trim_end_matches(".gz").to_string(), entry))
+        .collect();
+    entries("SHA256-History").into_iter().filter_map(|(name, history)| {
+        Some(IndexPatch {
+            patch: patches.get(&name)?.clone(),
+            download: downloads.get(&name)?.clone(),
+            name,
+            history,
+        })
+    }).collect()
+}
+
+// An Index for the current index hash and size, listing the patches oldest first
+pub fn index(current: (&str, u64), patches: &[IndexPatch]) -> String {
+    let mut index = String::new();
+    writeln!(index, "SHA256-Current: {} {}", current.0, current.1).unwrap();
+    writeln!(index, "SHA256-History:").unwrap();
+    for patch in patches.iter() {
+        writeln!(index, " {} {} {}", patch.history.0, patch.history.1, patch.name).unwrap();
+    }
+    writeln!(index, "SHA256-Patches:").unwrap();
+    for patch in patches.iter() {
+        writeln!(index, " {} {} {}", patch.patch.0, patch.patch.1, patch.name).unwrap();
+    }
+    writeln!(index, "SHA256-Download:").unwrap();
+    for patch in patches.iter() {
+        writeln!(index, " {} {} {}.gz", patch.download.0, patch.download.1, patch.name).unwrap();
+    }
+    index
+}
+
+// Creates an ed script turning old into new, which apt applies with its rred method
+pub fn ed_diff(old: &Path, new: &Path) -> io::Result<Vec<u8>> {
+    let output = process::Command::new("diff")
+        .arg("--ed")
+        .arg(old)
+        .arg(new)
+        .stdout(process::Stdio::piped())
+        .spawn()?
+        .wait_with_output()?;
+    // diff exits with 1 when the files differ
+    match output.status.code() {
+        Some(0) | Some(1) => Ok(output.stdout),
+        _ => Ok(status_err("diff", output.status).map(|()| output.stdout)?),
+    }
+}
+
+// Writes Packages.diff with the previous patches, and a new one from the old index to the
+// current one if it changed, and its Index
+pub fn write(index_file: &Path, old_index: &[u8], history: &History, name: &str) -> io::Result<()> {
+    let current = fs::read(index_file)?;
+    if current == old_index && history.patches.is_empty() {
+        return Ok(());
+    }
+
+    let index_name = index_file.file_name().unwrap().to_string_lossy();
+    let parent = index_file.parent().unwrap();
+    let diff_dir = ensure_dir(parent.join(format!("{}.diff", index_name)))?;
+
+    let mut patches = Vec::new();
+    let kept = if current == old_index { MAX_PATCHES } else { MAX_PATCHES - 1 };
+    for (patch, data) in history.patches.iter().skip(history.patches.len().saturating_sub(kept)) {
+        fs::write(diff_dir.join(format!("{}.gz", patch.name)), data)?;
+        patches.push(patch.clone());
+    }
+
+    if current != old_index {
+        let old_file = parent.join(format!("{}.old", index_name));
+        fs::write(&old_file, old_index)?;
+        let diff = ed_diff(&old_file, index_file);
+        let old_hash = sha256(&old_file);
+        fs::remove_file(&old_file)?;
+        let diff = diff?;
+        let old_hash = old_hash?;
+
+        let patch_file = diff_dir.join(name);
+        fs::write(&patch_file, &diff)?;
+        let patch_hash = sha256(&patch_file)?;
+        process::Command::new("gzip")
+            .arg("-9n")
+            .arg(&patch_file)
+            .run()?;
+        let download_file = diff_dir.join(format!("{}.gz", name));
+
+        patches.push(IndexPatch {
+            name: name.to_string(),
+            history: (old_hash, old_index.len() as u64),
+            patch: (patch_hash, diff.len() as u64),
+            download: (sha256(&download_file)?, fs::metadata(&download_file)?.len()),
+        });
+    }
+
+    fs::write(
+        diff_dir.join("Index"),
+        index((&sha256(index_file)?, current.len() as u64), &patches)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_PACKAGES: &str = "\
+Package: zstd
+Version: 1.4.8+dfsg-3build1popopt4
+
+Package: libzstd1
+Version: 1.4.8+dfsg-3build1popopt4
+";
+
+    const NEW_PACKAGES: &str = "\
+Package: zstd
+Version: 1.4.8+dfsg-3build1popopt4
+
+Package: libzstd1
+Version: 1.4.8+dfsg-3build2popopt4
+";
+
+    #[test]
+    fn ed_diff_script() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("old"), OLD_PACKAGES).unwrap();
+        fs::write(dir.path().join("new"), NEW_PACKAGES).unwrap();
+        let diff = ed_diff(&dir.path().join("old"), &dir.path().join("new")).unwrap();
+        assert_eq!(String::from_utf8(diff).unwrap(), "5c\nVersion: 1.4.8+dfsg-3build2popopt4\n.\n");
+        let diff = ed_diff(&dir.path().join("old"), &dir.path().join("old")).unwrap();
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn write_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let packages_file = dir.path().join("Packages");
+        fs::write(&packages_file, NEW_PACKAGES).unwrap();
+        write(&packages_file, OLD_PACKAGES.as_bytes(), &History::default(), "2024-01-01-0000.00").unwrap();
+
+        let diff_dir = dir.path().join("Packages.diff");
+        let old_file = dir.path().join("old");
+        fs::write(&old_file, OLD_PACKAGES).unwrap();
+        let patch = "5c\nVersion: 1.4.8+dfsg-3build2popopt4\n.\n";
+        let patch_file = dir.path().join("patch");
+        fs::write(&patch_file, patch).unwrap();
+        let download_file = diff_dir.join("2024-01-01-0000.00.gz");
+        assert_eq!(
+            fs::read_to_string(diff_dir.join("Index")).unwrap(),
+            format!(
+                "SHA256-Current: {} {}\nSHA256-History:\n {} {} 2024-01-01-0000.00\nSHA256-Patches:\n {} {} 2024-01-01-0000.00\nSHA256-Download:\n {} {} 2024-01-01-0000.00.gz\n",
+                sha256(&packages_file).unwrap(), NEW_PACKAGES.len(),
+                sha256(&old_file).unwrap(), OLD_PACKAGES.len(),
+                sha256(&patch_file).unwrap(), patch.len(),
+                sha256(&download_file).unwrap(), fs::metadata(&download_file).unwrap().len(),
+            )
+        );
+        // Only the compressed patch is published
+        assert!(! diff_dir.join("2024-01-01-0000.00").exists());
+        assert!(! dir.path().join("Packages.old").exists());
+    }
+
+    #[test]
+    fn write_keeps_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let packages_file = dir.path().join("Packages");
+        fs::write(&packages_file, NEW_PACKAGES).unwrap();
+        write(&packages_file, OLD_PACKAGES.as_bytes(), &History::default(), "2024-01-01-0000.00").unwrap();
+
+        // The next run recreates the dists directory, with the history read before
+        let diff_dir = dir.path().join("Packages.diff");
+        let history = History::read(&diff_dir).unwrap();
+        assert_eq!(history.patches.len(), 1);
+        fs::remove_dir_all(&diff_dir).unwrap();
+        let newest = format!("{}\nPackage: zstd-doc\nVersion: 1.4.8+dfsg-3build2popopt4\n", NEW_PACKAGES);
+        fs::write(&packages_file, &newest).unwrap();
+        write(&packages_file, NEW_PACKAGES.as_bytes(), &history, "2024-01-02-0000.00").unwrap();
+
+        let patches = parse_index(&fs::read_to_string(diff_dir.join("Index")).unwrap());
+        assert_eq!(patches.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec![
+            "2024-01-01-0000.00", "2024-01-02-0000.00",
+        ]);
+        assert_eq!(patches[0], history.patches[0].0);
+        // The new patch applies to the index the previous one produced
+        assert_eq!(patches[1].history.1, NEW_PACKAGES.len() as u64);
+        for patch in patches.iter() {
+            assert!(diff_dir.join(format!("{}.gz", patch.name)).is_file());
+        }
+
+        // An unchanged index keeps the patches, with the same current hash
+        let history = History::read(&diff_dir).unwrap();
+        write(&packages_file, newest.as_bytes(), &history, "2024-01-03-0000.00").unwrap();
+        assert_eq!(parse_index(&fs::read_to_string(diff_dir.join("Index")).unwrap()).len(), 2);
+    }
+
+    #[test]
+    fn write_unchanged_without_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let packages_file = dir.path().join("Packages");
+        fs::write(&packages_file, NEW_PACKAGES).unwrap();
+        write(&packages_file, NEW_PACKAGES.as_bytes(), &History::default(), "2024-01-01-0000.00").unwrap();
+        assert!(! dir.path().join("Packages.diff").exists());
+    }
+}
@@ -1,19 +1,274 @@
 use crate::{
+    Error,
     Arch,
+    Jobs,
+    RunCommand,
+    buildflags,
+    command_stdout,
+    control,
+    compare_versions,
+    default_jobs,
+    definition_paths,
+    dry_run,
+    parallel_map,
     ensure_dir,
     ensure_dir_clean,
+    link_or_copy,
+    sha256,
     status_err,
+    version,
+    write_file,
 };
+use log::{Level, log_enabled, warn};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
+    fmt,
     fs,
     io,
+    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process,
     str,
     thread,
+    time,
 };
 
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    // sbuild config loaded before the generated one, for site-specific settings
+    pub sbuild_profile: Option<PathBuf>,
+    // Merge flags into dpkg-buildflags defaults instead of appending to them
+    pub merge_buildflags: bool,
+    // Compare patched and original sources to annotate rebuilds that only change flags
+    pub detect_flags_only: bool,
+    // Rebuild when the archive has published newer binaries of an already built source version
+    pub newer_than_archive: bool,
+    // Print each generated sbuild.conf before building
+    pub dump_sbuild_conf: bool,
+    // Toolchain selected through rustup instead of the archive's rustc
+    pub rustup_toolchain: Option<String>,
+    // snapshot.ubuntu.com timestamp, such as 20230101T000000Z, pinning the archive state
+    pub snapshot: Option<String>,
+    // Hard link the source files into each sbuild directory, so every arch reads the same files
+    pub shared_source: bool,
+    // Slots limiting concurrent source preparations and sbuilds, unbounded when unset
+    pub jobs: Option<Jobs>,
+    // Commands run in order inside the chroot before building, such as installing a key
+    pub chroot_setup_commands: Vec<String>,
+    // Describe the level, flags, and tool version in the changelog, shown by apt changelog
+    pub changelog_metadata: bool,
+    // Scratch space for work in progress, moved into the build tree once complete
+    pub tmpdir: Option<PathBuf>,
+    // Rebuild when the patches or flags differ from the last build of a version, and only then
+    pub only_source_changed: bool,
+    // Ubuntu archive mirror for the extra repositories, DEFAULT_MIRROR when unset
+    pub mirror: Option<String>,
+    // Always download sources, ignoring and not updating the source cache
+    pub no_cache: bool,
+    // Appended to the source version, with {level} and {arch} placeholders. DEFAULT_VERSION_SUFFIX
+    // when unset
+    pub version_suffix: Option<String>,
+    // Retries of failed source downloads, DEFAULT_DOWNLOAD_RETRIES when unset
+    pub download_retries: Option<u32>,
+    // Credentials for private mirrors in apt's auth.conf format, for downloading sources
+    pub apt_auth: Option<PathBuf>,
+}
+
+pub const DEFAULT_DOWNLOAD_RETRIES: u32 = 3;
+
+// Runs a download, retrying with exponential backoff when it exits unsuccessfully, such as on
+// a flaky mirror. Other errors, such as a missing command, are returned immediately
+fn download(command: &mut process::Command, retries: u32) -> Result<(), Error> {
+    let mut delay = time::Duration::from_secs(2);
+    let mut attempt = 0;
+    loop {
+        match command.run().map_err(Error::from) {
+            Err(err @ Error::CommandFailed { .. }) if attempt < retries => {
+                attempt += 1;
+                warn!("    - warning: download failed: {}, retry {} of {} in {:?}", err, attempt, retries, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            res => return res,
+        }
+    }
+}
+
+pub const DEFAULT_VERSION_SUFFIX: &str = "popopt{level}";
+
+// Version suffix for an arch from a template, such as popopt3 from popopt{level}
+pub fn version_suffix(template: &str, arch: &Arch) -> String {
+    template
+        .replace("{level}", &arch.level.to_string())
+        .replace("{arch}", &arch.name)
+}
+
+// Source version with the suffix for an arch, which must still be valid and sort above the
+// source version
+fn suffixed_version(version: &str, template: &str, arch: &Arch) -> io::Result<String> {
+    // The text before any placeholder, such as popopt, marks sources that are already rebuilds
+    let marker = template.split('{').next().unwrap_or("");
+    if ! marker.is_empty() && version.contains(marker) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("version '{}' already contains a {} suffix, a different version suffix is needed", version, marker)
+        ));
+    }
+    let new_version = format!("{}{}", version, version_suffix(template, arch));
+    if ! version::is_valid(&new_version) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("version '{}' is not a valid Debian version, a different version suffix is needed", new_version)
+        ));
+    }
+    // Otherwise apt would keep the archive version installed
+    if ! compare_versions(&new_version, "gt", version)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("version '{}' does not sort above '{}', a different version suffix is needed", new_version, version)
+        ));
+    }
+    Ok(new_version)
+}
+
+pub const DEFAULT_MIRROR: &str = "http://archive.ubuntu.com/ubuntu/";
+
+// Archive mirror, pinned to a timestamp when a snapshot is used
+fn archive_mirror(snapshot: Option<&str>, mirror: Option<&str>) -> String {
+    match snapshot {
+        Some(timestamp) => format!("http://snapshot.ubuntu.com/ubuntu/{}/", timestamp),
+        None => mirror.unwrap_or(DEFAULT_MIRROR).to_string(),
+    }
+}
+
+// Mirrors must be http or https URLs with a host, such as http://archive.ubuntu.com/ubuntu/
+pub fn is_mirror_url(url: &str) -> bool {
+    let rest = match url.strip_prefix("http://").or_else(|| url.strip_prefix("https://")) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let host = rest.split('/').next().unwrap_or("");
+    ! host.is_empty() && ! url.chars().any(|x| x.is_whitespace() || x.is_control())
+}
+
+// Snapshot timestamps are of the form YYYYMMDDTHHMMSSZ
+pub fn is_snapshot_timestamp(timestamp: &str) -> bool {
+    let bytes = timestamp.as_bytes();
+    bytes.len() == 16
+        && bytes[..8].iter().all(|x| x.is_ascii_digit())
+        && bytes[8] == b'T'
+        && bytes[9..15].iter().all(|x| x.is_ascii_digit())
+        && bytes[15] == b'Z'
+}
+
+// Output of a successful sbuild of one arch
+#[derive(Clone, Debug)]
+pub struct SbuildResult {
+    pub arch: String,
+    pub output_dir: PathBuf,
+    pub debs: Vec<PathBuf>,
+    // Lists the built files with their hashes, as for uploading
+    pub changes: Option<PathBuf>,
+}
+
+impl SbuildResult {
+    fn scan(arch: String, output_dir: PathBuf) -> io::Result<Self> {
+        let mut debs = Vec::new();
+        let mut changes = None;
+        for entry_res in fs::read_dir(&output_dir)? {
+            let path = entry_res?.path();
+            if path.extension() == Some("deb".as_ref()) {
+                debs.push(path);
+            } else if path.extension() == Some("changes".as_ref()) {
+                changes = Some(path);
+            }
+        }
+        debs.sort();
+        Ok(Self {
+            arch,
+            output_dir,
+            debs,
+            changes,
+        })
+    }
+}
+
+// Decisions and notes while preparing a build, returned for the caller to report
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BuildEvent {
+    // Reasons Pkg::build rebuilds or reuses a prepared source
+    ArchiveNewer,
+    Unchanged,
+    Changed,
+    CachedSource(PathBuf),
+    LocalSource(PathBuf),
+    SkippedPatch(String),
+    FlagsOnly,
+    FlagConflict { sbuild_arch: String, conflict: String },
+    Network(String),
+    // The generated sbuild configuration, with --dump-sbuild-conf
+    SbuildConf { sbuild_arch: String, conf: String },
+    Sbuild(String),
+    SkippedSbuild { sbuild_arch: String, architectures: Vec<String> },
+}
+
+impl fmt::Display for BuildEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildEvent::ArchiveNewer => write!(f, "Archive has newer binaries, rebuilding"),
+            BuildEvent::Unchanged => write!(f, "Patches and flags unchanged, skipping"),
+            BuildEvent::Changed => write!(f, "Patches or flags changed, rebuilding"),
+            BuildEvent::CachedSource(dir) => write!(f, "using cached source in {}", dir.display()),
+            BuildEvent::LocalSource(path) => write!(f, "using local source {}", path.display()),
+            BuildEvent::SkippedPatch(file) => write!(f, "skipping patch {}", file),
+            BuildEvent::FlagsOnly => write!(f, "flags-only rebuild"),
+            BuildEvent::FlagConflict { sbuild_arch, conflict } => write!(f, "{}: {}", sbuild_arch, conflict),
+            BuildEvent::Network(sbuild_arch) => write!(f, "{}: network enabled, build may not be reproducible", sbuild_arch),
+            BuildEvent::SbuildConf { sbuild_arch, .. } => write!(f, "sbuild.conf for {}", sbuild_arch),
+            BuildEvent::Sbuild(sbuild_arch) => write!(f, "sbuild {}", sbuild_arch),
+            BuildEvent::SkippedSbuild { sbuild_arch, architectures } => write!(
+                f,
+                "skipping sbuild {}: not in architectures {}",
+                sbuild_arch,
+                architectures.join(", ")
+            ),
+        }
+    }
+}
+
+// Sbuild threads of a started build, with the events of preparing it
+#[derive(Debug)]
+pub struct StartedBuild {
+    pub threads: Vec<thread::JoinHandle<io::Result<SbuildResult>>>,
+    pub events: Vec<BuildEvent>,
+}
+
+// A build of one package version, for Pkg::build_with
+#[derive(Clone, Copy, Debug)]
+pub struct BuildConfig<'a> {
+    pub arch: &'a Arch,
+    pub dist: &'a str,
+    // Source version to build
+    pub version: &'a str,
+    pub sbuild_archs: &'a [&'a str],
+    // Directory of the package's builds, with a subdirectory per version
+    pub dir: &'a Path,
+    // Prepare the source and build again, even when complete results exist
+    pub rebuild: bool,
+    // Remove the work of failed or interrupted builds instead of failing
+    pub retry: bool,
+    // Other settings, such as the mirror and the job slots
+    pub options: &'a BuildOptions,
+}
+
+// Build flags of an sbuild, with the dpkg-buildflags mode they are passed with
+struct SbuildFlags {
+    mode: &'static str,
+    cflags: Vec<String>,
+    cxxflags: Vec<String>,
+}
+
 struct Config<'a> {
     arch: &'a Arch,
     dist: &'a str,
@@ -21,13 +276,195 @@ struct Config<'a> {
     dir: &'a Path,
     rebuild: bool,
     retry: bool,
+    options: &'a BuildOptions,
+}
+
+impl Config<'_> {
+    fn partial_dir(&self, name: &str) -> io::Result<PathBuf> {
+        let dir = partial_dir(self.dir, self.options.tmpdir.as_deref(), name);
+        if self.options.tmpdir.is_some() {
+            ensure_dir(dir.parent().unwrap())?;
+        }
+        Ok(dir)
+    }
+}
+
+// Directory for work in progress on a version's build directory, mirroring the build tree
+// under the tmpdir when one is set
+pub fn partial_dir(dir: &Path, tmpdir: Option<&Path>, name: &str) -> PathBuf {
+    match tmpdir {
+        Some(tmpdir) => tmpdir.join(dir.strip_prefix("/").unwrap_or(dir)).join(name),
+        None => dir.join(name),
+    }
+}
+
+// Renames a completed directory into place. Across filesystems, such as from a tmpdir, it is
+// copied next to the destination first, so the destination still only appears once complete
+fn move_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    match fs::rename(src, dst) {
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            let mut copy_name = dst.file_name().unwrap().to_os_string();
+            copy_name.push(".moving");
+            let copy = dst.with_file_name(copy_name);
+            if copy.is_dir() {
+                fs::remove_dir_all(&copy)?;
+            }
+            process::Command::new("cp")
+                .arg("-a")
+                .arg(src)
+                .arg(&copy)
+                .run()?;
+            fs::rename(&copy, dst)?;
+            fs::remove_dir_all(src)
+        },
+        res => res,
+    }
+}
+
+// Shared with the chroots as /build
+pub const SHARE_PARENT_DIR: &str = "/var/lib/sbuild/build";
+
+// Name of the apt credentials in a share directory
+const APT_AUTH_FILE: &str = "auth.conf";
+
+// Directory used to download sources in the chroot, named popopt_{arch}_{dist}_{name}_{version}
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShareDir {
+    pub arch: String,
+    pub dist: String,
+    pub name: String,
+    pub version: String,
+}
+
+impl ShareDir {
+    pub fn file_name(&self) -> String {
+        format!("popopt_{}_{}_{}_{}", self.arch, self.dist, self.name, self.version)
+    }
+
+    // Package names and versions cannot contain underscores, so the fields split unambiguously
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let parts: Vec<&str> = file_name.strip_prefix("popopt_")?.split('_').collect();
+        match parts.as_slice() {
+            [arch, dist, name, version] => Some(Self {
+                arch: arch.to_string(),
+                dist: dist.to_string(),
+                name: name.to_string(),
+                version: version.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Patch {
+    Plain(String),
+    Conditional {
+        file: String,
+        #[serde(default)]
+        dist: Option<String>,
+        // Inclusive lower bound on the source version
+        #[serde(default)]
+        min_version: Option<String>,
+        // Exclusive upper bound on the source version
+        #[serde(default)]
+        max_version: Option<String>,
+    },
+}
+
+// How patches are applied to the extracted source
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PatchMode {
+    // Applied directly with patch -p1
+    #[default]
+    Plain,
+    // Added to debian/patches/series, for dpkg-source to apply with the source's own patches
+    Quilt,
+}
+
+impl Patch {
+    pub fn file(&self) -> &str {
+        match self {
+            Patch::Plain(file) => file,
+            Patch::Conditional { file, .. } => file,
+        }
+    }
+
+    pub fn applies(&self, dist: &str, version: &str) -> io::Result<bool> {
+        match self {
+            Patch::Plain(_) => Ok(true),
+            Patch::Conditional { dist: patch_dist, min_version, max_version, .. } => {
+                if let Some(patch_dist) = patch_dist {
+                    if patch_dist != dist {
+                        return Ok(false);
+                    }
+                }
+                if let Some(min_version) = min_version {
+                    if ! compare_versions(version, "ge", min_version)? {
+                        return Ok(false);
+                    }
+                }
+                if let Some(max_version) = max_version {
+                    if ! compare_versions(version, "lt", max_version)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct Pkg {
     pub name: String,
     #[serde(default)]
-    pub patches: Vec<String>,
+    pub patches: Vec<Patch>,
+    #[serde(default)]
+    pub patch_mode: PatchMode,
+    // Installed in the chroot in addition to the source's build dependencies
+    #[serde(default)]
+    pub extra_build_depends: Vec<String>,
+    // Allow network access during the build, which makes it less reproducible
+    #[serde(default)]
+    pub network: bool,
+    // rustc target features to disable, such as ones triggering a rustc bug
+    #[serde(default)]
+    pub rustflags_remove: Vec<String>,
+    // Expected sha256 of the downloaded .dsc, catching tampered mirrors and version drift
+    #[serde(default)]
+    pub dsc_sha256: Option<String>,
+    // sbuild archs to build, such as only amd64 for arch specific code. All archs when empty
+    #[serde(default)]
+    pub architectures: Vec<String>,
+    // Repository component to publish in, such as non-free. The first built component when not set
+    #[serde(default)]
+    pub component: Option<String>,
+    // Changelog message of the rebuild, instead of the default
+    #[serde(default)]
+    pub changelog: Option<String>,
+    // Extracted source tree to build instead of the archive source, relative like patches
+    #[serde(default)]
+    pub source_dir: Option<String>,
+    // Directory patch files are relative to, set by load_all to the parent of the package directory
+    #[serde(skip)]
+    pub root_dir: PathBuf,
+}
+
+// Downloaded sources by name and version, so rebuilds do not download them again
+pub const SOURCE_CACHE_DIR: &str = "cache";
+
+// Links or copies a .dsc and every file it lists between directories
+fn copy_source(from: &Path, to: &Path, dsc_name: &str) -> io::Result<()> {
+    let dsc = fs::read_to_string(from.join(dsc_name))?;
+    let paragraphs = control::parse(&dsc);
+    let files = paragraphs.first().map_or_else(Vec::new, |x| x.lines("Files"));
+    for file in files.iter().filter_map(|x| x.split_whitespace().last()) {
+        link_or_copy(from.join(file), to.join(file))?;
+    }
+    link_or_copy(from.join(dsc_name), to.join(dsc_name))
 }
 
 fn source_values(source: &str, key: &str) -> io::Result<Vec<String>> {
@@ -50,32 +487,364 @@ fn source_values(source: &str, key: &str) -> io::Result<Vec<String>> {
     }
 }
 
+// Compares two source trees, ignoring the given relative paths
+fn same_tree(a: &Path, b: &Path, ignore: &[&Path], relative: &Path) -> io::Result<bool> {
+    let mut a_names = Vec::new();
+    for entry_res in fs::read_dir(a.join(relative))? {
+        a_names.push(entry_res?.file_name());
+    }
+    let mut b_names = Vec::new();
+    for entry_res in fs::read_dir(b.join(relative))? {
+        b_names.push(entry_res?.file_name());
+    }
+    a_names.retain(|x| ! ignore.contains(&relative.join(x).as_path()));
+    b_names.retain(|x| ! ignore.contains(&relative.join(x).as_path()));
+    a_names.sort();
+    b_names.sort();
+    if a_names != b_names {
+        return Ok(false);
+    }
+
+    for name in a_names {
+        let path = relative.join(&name);
+        let a_meta = fs::symlink_metadata(a.join(&path))?;
+        let b_meta = fs::symlink_metadata(b.join(&path))?;
+        let same = if a_meta.file_type().is_symlink() || b_meta.file_type().is_symlink() {
+            a_meta.file_type().is_symlink() == b_meta.file_type().is_symlink()
+                && fs::read_link(a.join(&path))? == fs::read_link(b.join(&path))?
+        } else if a_meta.is_dir() || b_meta.is_dir() {
+            a_meta.is_dir() == b_meta.is_dir() && same_tree(a, b, ignore, &path)?
+        } else {
+            a_meta.len() == b_meta.len() && fs::read(a.join(&path))? == fs::read(b.join(&path))?
+        };
+        if ! same {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn arch_matches(arch_list: &str, sbuild_arch: &str, arch_all: bool) -> bool {
+    arch_list.split(',').any(|x| match x {
+        "all" => arch_all,
+        "any" | "linux-any" => true,
+        _ => x == sbuild_arch || x == format!("any-{}", sbuild_arch),
+    })
+}
+
+// Binary packages a source should produce on an architecture, from its Package-List
+fn expected_binaries(dsc: &control::Paragraph, sbuild_arch: &str, arch_all: bool) -> Vec<String> {
+    let mut binaries = Vec::new();
+    for line in dsc.lines("Package-List") {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        // Entries restricted to build profiles may legitimately be missing
+        if words.len() < 5 || words[5..].iter().any(|x| x.starts_with("profile=")) {
+            continue;
+        }
+        if let Some(arch_list) = words[4].strip_prefix("arch=") {
+            if arch_matches(arch_list, sbuild_arch, arch_all) {
+                binaries.push(words[0].to_string());
+            }
+        }
+    }
+    binaries
+}
+
+// Changelog entries of a rebuild, optionally describing how it was optimized
+fn changelog_entries(message: &str, patches: &[String], arch: &Arch, rustflags_remove: &[String], metadata: bool) -> Vec<String> {
+    let mut entries = vec![message.to_string()];
+    entries.extend(patches.iter().map(|x| format!("Apply {}", x)));
+    if metadata {
+        entries.push(format!("Optimized for {} (level {})", arch.name, arch.level));
+        entries.push(format!("CFLAGS: {}", arch.cflags().join(" ")));
+        entries.push(format!("CXXFLAGS: {}", arch.cxxflags().join(" ")));
+        entries.push(format!("RUSTFLAGS: {}", arch.rustflags_without(rustflags_remove).join(" ")));
+        entries.push(format!("Built with pop-opt {}", env!("CARGO_PKG_VERSION")));
+    }
+    entries
+}
+
+// Upstream tarballs of a source, including component tarballs and their signatures
+fn orig_tarballs(dsc: &control::Paragraph) -> Vec<String> {
+    dsc.lines("Files").iter()
+        .filter_map(|line| line.split_whitespace().last())
+        .filter(|file| file.contains(".orig.tar.") || file.contains(".orig-"))
+        .map(|file| file.to_string())
+        .collect()
+}
+
+// Checks that sbuild's .changes lists every expected binary, as sbuild may succeed on partial builds
+fn validate_changes(dir: &Path, expected: &[String]) -> io::Result<()> {
+    let mut changes_file = None;
+    for entry_res in fs::read_dir(dir)? {
+        let path = entry_res?.path();
+        if path.extension() == Some("changes".as_ref()) {
+            changes_file = Some(path);
+        }
+    }
+    let changes_file = changes_file.ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("failed to find changes file in '{}'", dir.display())
+    ))?;
+
+    let changes = fs::read_to_string(&changes_file)?;
+    let paragraphs = control::parse(&changes);
+    let mut built = Vec::new();
+    if let Some(paragraph) = paragraphs.first() {
+        for line in paragraph.lines("Files") {
+            if let Some(file) = line.split_whitespace().last() {
+                if file.ends_with(".deb") || file.ends_with(".udeb") {
+                    built.push(file.split('_').next().unwrap_or("").to_string());
+                }
+            }
+        }
+    }
+
+    let missing: Vec<&str> = expected.iter()
+        .filter(|x| ! built.contains(x))
+        .map(|x| x.as_str())
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is missing binaries: {}", changes_file.display(), missing.join(", "))
+        ))
+    }
+}
+
+// Candidate versions from apt-cache policy output, by package name
+fn policy_candidates(policy: &str) -> Vec<(String, String)> {
+    let mut candidates = Vec::new();
+    let mut name = None;
+    for line in policy.lines() {
+        if ! line.starts_with(' ') && line.ends_with(':') {
+            name = Some(line.trim_end_matches(':').to_string());
+        } else if let Some(version) = line.trim().strip_prefix("Candidate: ") {
+            if let Some(name) = name.take() {
+                if version != "(none)" {
+                    candidates.push((name, version.to_string()));
+                }
+            }
+        }
+    }
+    candidates
+}
+
+// A binary-only upload, such as 1.0-1+b1, is newer than the 1.0-1 source it was built from
+fn binary_rebuild_needed(source_version: &str, candidates: &[(String, String)]) -> io::Result<bool> {
+    for (_name, version) in candidates.iter() {
+        if compare_versions(version, "gt", source_version)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 impl Pkg {
-    pub fn load<P: AsRef<Path>>(p: P) -> io::Result<Self> {
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self, Error> {
         let data = fs::read_to_string(p)?;
-        toml::from_str(&data).map_err(|err| io::Error::new(
+        toml::from_str(&data).map_err(|err| Error::Io(io::Error::new(
             io::ErrorKind::InvalidData,
             err,
-        ))
+        )))
+    }
+
+    // Data directory of a package directory, such as the checkout containing pkg and patch
+    pub fn root_dir<P: AsRef<Path>>(pkg_dir: P) -> PathBuf {
+        pkg_dir.as_ref().parent().map_or_else(PathBuf::new, |x| x.to_path_buf())
+    }
+
+    pub fn load_all<P: AsRef<Path>>(p: P) -> Result<Vec<Self>, Error> {
+        // Packages may be grouped in subdirectories
+        let entries = definition_paths(&p, "toml", true)?;
+
+        // Parsing is done in parallel, but results keep the sorted path order
+        let mut pkgs = parallel_map(&entries, default_jobs(), |entry| Self::load(entry))
+            .into_iter()
+            .collect::<Result<Vec<Self>, Error>>()?;
+        for pkg in pkgs.iter_mut() {
+            pkg.root_dir = Self::root_dir(&p);
+        }
+
+        // The same source must not be built twice
+        let mut files = BTreeMap::new();
+        for (pkg, entry) in pkgs.iter().zip(entries.iter()) {
+            files.entry(pkg.name.as_str()).or_insert_with(Vec::new).push(entry.display().to_string());
+        }
+        let duplicates: Vec<String> = files.iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(name, files)| format!("'{}' in {}", name, files.join(", ")))
+            .collect();
+        if ! duplicates.is_empty() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("duplicate package definitions: {}", duplicates.join("; "))
+            )));
+        }
+
+        Ok(pkgs)
     }
 
-    pub fn load_all<P: AsRef<Path>>(p: P) -> io::Result<Vec<Self>> {
-        let mut entries = Vec::new();
-        for entry_res in fs::read_dir(p)? {
-            entries.push(entry_res?.path());
+    pub fn patch_path(&self, patch: &Patch) -> PathBuf {
+        self.root_dir.join(patch.file())
+    }
+
+    pub fn source_path(&self) -> Option<PathBuf> {
+        self.source_dir.as_ref().map(|x| self.root_dir.join(x))
+    }
+
+    // Downloads the source, or copies it from the cache, and extracts it into original_dir
+    fn archive_source(&self, config: &Config, dir: &Path, original_dir: &Path, events: &mut Vec<BuildEvent>) -> Result<(), Error> {
+        let share_name = ShareDir {
+            arch: config.arch.name.clone(),
+            dist: config.dist.to_string(),
+            name: self.name.clone(),
+            version: config.version.to_string(),
+        }.file_name();
+        let share_dir = ensure_dir_clean(Path::new(SHARE_PARENT_DIR).join(&share_name))?;
+
+        // Download package source, unless it is cached
+        let retries = config.options.download_retries.unwrap_or(DEFAULT_DOWNLOAD_RETRIES);
+        let dsc_name = format!("{}_{}.dsc", self.name, config.version);
+        let cache_dir = Path::new(SOURCE_CACHE_DIR).join(format!("{}_{}", self.name, config.version));
+        let cached = ! config.options.no_cache && cache_dir.join(&dsc_name).is_file();
+        if cached {
+            events.push(BuildEvent::CachedSource(cache_dir.clone()));
+            copy_source(&cache_dir, &share_dir, &dsc_name)?;
+        } else if let Some(snapshot) = &config.options.snapshot {
+            // The pool path is the same in the snapshot, but the source must exist at the timestamp
+            let directory = self.source_directory(config.dist, config.version)?;
+            download(process::Command::new("dget")
+                .arg("--download-only")
+                .arg(format!(
+                    "{}{}/{}_{}.dsc",
+                    archive_mirror(Some(snapshot), None),
+                    directory,
+                    self.name,
+                    config.version.split_once(':').map_or(config.version, |x| x.1)
+                ))
+                .current_dir(&share_dir)
+                .stdout(command_stdout()), retries)?;
+        } else {
+            // The share directory is the only one visible in the chroot, so the credentials are
+            // copied there, readable only by this user, and removed with it
+            if let Some(apt_auth) = &config.options.apt_auth {
+                let auth_file = share_dir.join(APT_AUTH_FILE);
+                fs::copy(apt_auth, &auth_file)?;
+                fs::set_permissions(&auth_file, fs::Permissions::from_mode(0o600))?;
+            }
+            download(self.apt_source_command(config, &share_name)
+                .stdout(command_stdout()), retries)?;
+        }
+
+        let dsc_file = share_dir.join(&dsc_name);
+        if ! dsc_file.is_file() {
+            return Err(Error::MissingDsc(dsc_file));
+        }
+        if let Some(expected) = &self.dsc_sha256 {
+            let found = sha256(&dsc_file)?;
+            if ! found.eq_ignore_ascii_case(expected) {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("DSC file '{}' has sha256 {}, expected {}", dsc_file.display(), found, expected)
+                )));
+            }
+        }
+
+        // Cached once complete, so an interrupted copy is never used
+        if ! cached && ! config.options.no_cache {
+            let cache_partial_dir = ensure_dir_clean(
+                Path::new(SOURCE_CACHE_DIR).join(format!("{}_{}.partial", self.name, config.version))
+            )?;
+            copy_source(&share_dir, &cache_partial_dir, &dsc_name)?;
+            if cache_dir.is_dir() {
+                fs::remove_dir_all(&cache_dir)?;
+            }
+            fs::rename(&cache_partial_dir, &cache_dir)?;
         }
-        entries.sort();
 
-        let mut archs = Vec::new();
-        for entry in entries.iter() {
-            archs.push(Self::load(entry)?);
+        // Extract package source
+        process::Command::new("dpkg-source")
+            .arg("--extract")
+            .arg(&dsc_file)
+            .arg(original_dir)
+            .current_dir(dir)
+            .stdout(command_stdout())
+            .run()?;
+
+        // Rebuilding requires every upstream tarball next to the source, which dpkg-source only
+        // copies by default, so link any missing ones, such as multiple component tarballs
+        let dsc = fs::read_to_string(&dsc_file)?;
+        for orig in control::parse(&dsc).first().map_or_else(Vec::new, orig_tarballs) {
+            let orig_file = dir.join(&orig);
+            if ! orig_file.is_file() {
+                let share_file = share_dir.join(&orig);
+                if ! share_file.is_file() {
+                    return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("failed to find upstream tarball '{}'", share_file.display())
+                    )));
+                }
+                link_or_copy(&share_file, &orig_file)?;
+            }
         }
-        Ok(archs)
+
+        fs::remove_dir_all(&share_dir)?;
+        Ok(())
     }
 
-    fn source(&self, config: &Config) -> io::Result<PathBuf> {
+    // Downloads the source with apt-get in the chroot, into the share directory
+    fn apt_source_command(&self, config: &Config, share_name: &str) -> process::Command {
+        let mut command = process::Command::new("schroot");
+        command
+            //TODO: Use sbuild arch?
+            .arg("--chroot").arg(format!("{}-amd64-popopt", config.dist))
+            .arg("--directory").arg(format!("/build/{}", share_name))
+            .arg("--")
+            .arg("apt-get");
+        if config.options.apt_auth.is_some() {
+            command.arg("-o").arg(format!("Dir::Etc::netrc=/build/{}/{}", share_name, APT_AUTH_FILE));
+        }
+        command
+            .arg("source")
+            .arg("--only-source")
+            .arg("--download-only")
+            .arg(format!("{}={}", self.name, config.version))
+            .current_dir(config.dir);
+        command
+    }
+
+    // Copies a local source tree into original_dir, with the upstream tarballs next to it
+    fn local_source(&self, source_path: &Path, config: &Config, dir: &Path, original_dir: &Path, events: &mut Vec<BuildEvent>) -> Result<(), Error> {
+        events.push(BuildEvent::LocalSource(source_path.to_path_buf()));
+        process::Command::new("cp")
+            .arg("-a")
+            .arg(source_path)
+            .arg(original_dir)
+            .current_dir(dir)
+            .run()?;
+
+        // Sources that are not native need their upstream tarballs, such as name_1.0.orig.tar.gz
+        let upstream = config.version.split_once(':').map_or(config.version, |x| x.1);
+        let upstream = upstream.rsplit_once('-').map_or(upstream, |x| x.0);
+        let orig_prefix = format!("{}_{}.orig", self.name, upstream);
+        let parent = source_path.parent().unwrap_or(Path::new("."));
+        for entry_res in fs::read_dir(parent)? {
+            let path = entry_res?.path();
+            if path.file_name().and_then(|x| x.to_str()).is_some_and(|x| x.starts_with(&orig_prefix)) {
+                link_or_copy(&path, dir.join(path.file_name().unwrap()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn source(&self, config: &Config, events: &mut Vec<BuildEvent>) -> Result<PathBuf, Error> {
         let complete_dir = config.dir.join("source");
-        let new_version = format!("{}popopt{}", config.version, config.arch.level);
+        let template = config.options.version_suffix.as_deref().unwrap_or(DEFAULT_VERSION_SUFFIX);
+        let new_version = suffixed_version(config.version, template, config.arch)?;
         let new_dsc_file = complete_dir.join(format!("{}_{}.dsc", self.name, new_version));
         if complete_dir.is_dir() {
             if config.rebuild {
@@ -83,67 +852,40 @@ impl Pkg {
             } else if new_dsc_file.is_file() {
                 return Ok(new_dsc_file);
             } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("failed to find DSC file '{}'", new_dsc_file.display())
-                ));
+                return Err(Error::MissingDsc(new_dsc_file));
             }
         }
 
-        let dir = config.dir.join("source.partial");
+        let dir = config.partial_dir("source.partial")?;
         if dir.is_dir() {
             if config.retry {
                 fs::remove_dir_all(&dir)?;
             } else {
-                return Err(io::Error::new(
+                return Err(Error::Io(io::Error::new(
                     io::ErrorKind::AlreadyExists,
                     format!(
                         "'{}' already exists, build is in progress or already failed",
                         dir.display()
                     )
-                ));
+                )));
             }
         }
 
-        fs::create_dir(&dir)?;
-
-        let share_name = format!("popopt_{}_{}_{}_{}", config.arch.name, config.dist, self.name, config.version);
-        let share_dir = ensure_dir_clean(format!("/var/lib/sbuild/build/{}", share_name))?;
-
-        // Download package source
-        process::Command::new("schroot")
-            //TODO: Use sbuild arch?
-            .arg("--chroot").arg(format!("{}-amd64-popopt", config.dist))
-            .arg("--directory").arg(format!("/build/{}", share_name))
-            .arg("--")
-            .arg("apt-get")
-            .arg("source")
-            .arg("--only-source")
-            .arg("--download-only")
-            .arg(format!("{}={}", self.name, config.version))
-            .current_dir(&config.dir)
-            .status()
-            .and_then(status_err)?;
-
-        let dsc_file = share_dir.join(format!("{}_{}.dsc", self.name, config.version));
-        if ! dsc_file.is_file() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("failed to find DSC file '{}'", dsc_file.display())
-            ));
+        // The commands that prepare the source are only printed, so there is nothing to build it
+        // from and no directory is created
+        if dry_run() {
+            println!("- would prepare {} in {}", new_dsc_file.display(), dir.display());
+            return Ok(new_dsc_file);
         }
 
-        // Extract package source
-        let original_dir = dir.join("original");
-        process::Command::new("dpkg-source")
-            .arg("--extract")
-            .arg(&dsc_file)
-            .arg(&original_dir)
-            .current_dir(&dir)
-            .status()
-            .and_then(status_err)?;
+        fs::create_dir(&dir)?;
 
-        fs::remove_dir_all(&share_dir)?;
+        // Extract package source, from the archive unless a local source is configured
+        let original_dir = dir.join("original");
+        match self.source_path() {
+            Some(source_path) => self.local_source(&source_path, config, &dir, &original_dir, events)?,
+            None => self.archive_source(config, &dir, &original_dir, events)?,
+        }
 
         // Make a copy where patches are applied
         let patched_dir = dir.join("patched");
@@ -152,61 +894,122 @@ impl Pkg {
             .arg(&original_dir)
             .arg(&patched_dir)
             .current_dir(&dir)
-            .status()
-            .and_then(status_err)?;
+            .run()?;
 
-        // Apply additional source patches
+        // Quilt patches are only valid for sources in the 3.0 (quilt) format
+        let quilt_dir = patched_dir.join("debian/patches");
+        if self.patch_mode == PatchMode::Quilt {
+            let format = fs::read_to_string(patched_dir.join("debian/source/format")).unwrap_or_default();
+            if format.trim() != "3.0 (quilt)" {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("patch_mode quilt requires a 3.0 (quilt) source, found '{}'", format.trim())
+                )));
+            }
+            ensure_dir(&quilt_dir)?;
+        }
+
+        // Apply additional source patches, listed by file name in the changelog
+        let mut applied = Vec::new();
         for patch in self.patches.iter() {
-            let patch_file = fs::canonicalize(patch)?;
-            process::Command::new("patch")
-                .arg("-p1")
-                .arg("-i").arg(&patch_file)
-                .current_dir(&patched_dir)
-                .status()
-                .and_then(status_err)?;
+            if ! patch.applies(config.dist, config.version)? {
+                events.push(BuildEvent::SkippedPatch(patch.file().to_string()));
+                continue;
+            }
+
+            let patch_file = fs::canonicalize(self.patch_path(patch))?;
+            applied.push(patch_file.file_name().unwrap().to_string_lossy().to_string());
+            match self.patch_mode {
+                PatchMode::Plain => {
+                    process::Command::new("patch")
+                        .arg("-p1")
+                        .arg("-i").arg(&patch_file)
+                        .current_dir(&patched_dir)
+                        .stdout(command_stdout())
+                        .run()?;
+                },
+                PatchMode::Quilt => {
+                    // Prefixed so they cannot replace the source's own patches
+                    let name = format!("popopt-{}", patch_file.file_name().unwrap().to_string_lossy());
+                    fs::copy(&patch_file, quilt_dir.join(&name))?;
+                    let series_file = quilt_dir.join("series");
+                    let mut series = fs::read_to_string(&series_file).unwrap_or_default();
+                    if ! series.is_empty() && ! series.ends_with('\n') {
+                        series.push('\n');
+                    }
+                    series.push_str(&name);
+                    series.push('\n');
+                    fs::write(&series_file, series)?;
+                },
+            }
+        }
+
+        // Note when only the build flags differ from the archive source
+        let mut message = self.changelog.clone().unwrap_or_else(|| "Pop!_OS Optimizations".to_string());
+        if config.options.detect_flags_only && same_tree(
+            &original_dir,
+            &patched_dir,
+            &[Path::new("debian/changelog")],
+            Path::new("")
+        )? {
+            events.push(BuildEvent::FlagsOnly);
+            message.push_str(" (flags-only rebuild)");
         }
 
         // Update changelog
+        let entries = changelog_entries(
+            &message,
+            &applied,
+            config.arch,
+            &self.rustflags_remove,
+            config.options.changelog_metadata
+        );
         process::Command::new("dch")
             .arg("--distribution").arg(config.dist)
             .arg("--newversion").arg(&new_version)
-            .arg("Pop!_OS Optimizations")
+            .arg(&entries[0])
             .current_dir(&patched_dir)
-            .status()
-            .and_then(status_err)?;
+            .stdout(command_stdout())
+            .run()?;
+        for entry in entries[1..].iter() {
+            process::Command::new("dch")
+                .arg("--append")
+                .arg(entry)
+                .current_dir(&patched_dir)
+                .stdout(command_stdout())
+                .run()?;
+        }
 
         // Create DSC file
         process::Command::new("dpkg-source")
             .arg("--build").arg(&patched_dir)
             .current_dir(&dir)
-            .status()
-            .and_then(status_err)?;
+            .stdout(command_stdout())
+            .run()?;
 
-        fs::rename(&dir, &complete_dir)?;
+        move_dir(&dir, &complete_dir)?;
 
         if ! new_dsc_file.is_file() {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("failed to find DSC file '{}'", new_dsc_file.display())
-            ));
+            return Err(Error::MissingDsc(new_dsc_file));
         }
 
         Ok(new_dsc_file)
     }
 
-    fn sbuild_thread(&self, source_dsc: &Path, sbuild_arch: &str, config: &Config) -> io::Result<thread::JoinHandle<io::Result<PathBuf>>> {
+    fn sbuild_thread(&self, source_dsc: &Path, sbuild_arch: &str, arch_all: bool, config: &Config, events: &mut Vec<BuildEvent>) -> io::Result<thread::JoinHandle<io::Result<SbuildResult>>> {
         let complete_dir = config.dir.join(format!("sbuild-{}", sbuild_arch));
         if complete_dir.is_dir() {
             if config.rebuild {
                 fs::remove_dir_all(&complete_dir)?;
             } else {
+                let sbuild_arch = sbuild_arch.to_string();
                 return Ok(thread::spawn(move || {
-                    Ok(complete_dir)
+                    SbuildResult::scan(sbuild_arch, complete_dir)
                 }));
             }
         }
 
-        let dir = config.dir.join(format!("sbuild-{}.partial", sbuild_arch));
+        let dir = config.partial_dir(&format!("sbuild-{}.partial", sbuild_arch))?;
         if dir.is_dir() {
             if config.retry {
                 fs::remove_dir_all(&dir)?;
@@ -223,28 +1026,121 @@ impl Pkg {
             }
         }
 
+        // Without a prepared source, only the configuration and command are printed
+        if dry_run() {
+            let flags = self.sbuild_flags(sbuild_arch, config, events)?;
+            let (mut command, sbuild_conf) = self.sbuild_command(source_dsc, sbuild_arch, arch_all, config, &dir, &flags);
+            println!("- would write {}:\n{}", dir.join("sbuild.conf").display(), sbuild_conf);
+            command.run()?;
+            let sbuild_arch = sbuild_arch.to_string();
+            return Ok(thread::spawn(move || Ok(SbuildResult {
+                arch: sbuild_arch,
+                output_dir: complete_dir,
+                debs: Vec::new(),
+                changes: None,
+            })));
+        }
+
         fs::create_dir(&dir)?;
 
-        // Create sbuild config
-        //TODO: can flags be passed as an array?
-        let sbuild_conf = format!(
-r#"$build_environment = {{
-    'DEB_CFLAGS_APPEND' => '{}',
-    'DEB_CXXFLAGS_APPEND' => '{}',
-    'POP_OPT_ARCH' => '{}',
-    'RUSTFLAGS' => '{}',
-}};
-"#,
-            config.arch.cflags().join(" "),
-            config.arch.cxxflags().join(" "),
-            config.arch.name,
-            config.arch.rustflags().join(" "),
+        let (mut command, expected) = self.prepare_sbuild(source_dsc, sbuild_arch, arch_all, config, &dir, events)?;
+        // Output of parallel builds would interleave, so it is only streamed with --verbose
+        let build_log = if log_enabled!(Level::Debug) {
+            command.stdout(command_stdout());
+            None
+        } else {
+            let build_log = config.dir.join(format!("sbuild-{}.log", sbuild_arch));
+            let file = fs::File::create(&build_log)?;
+            command.stdout(file.try_clone()?).stderr(file);
+            Some(build_log)
+        };
+
+        let jobs = config.options.jobs.clone();
+        let sbuild_arch = sbuild_arch.to_string();
+        Ok(thread::spawn(move || {
+            let _slot = jobs.as_ref().map(|x| x.acquire());
+            command.run().map_err(|err| match &build_log {
+                Some(build_log) => io::Error::new(
+                    err.kind(),
+                    format!("{} (log: {})", err, build_log.display())
+                ),
+                None => err,
+            })?;
+
+            validate_changes(&dir, &expected)?;
+
+            move_dir(&dir, &complete_dir)?;
+
+            SbuildResult::scan(sbuild_arch, complete_dir)
+        }))
+    }
+
+    // Flags for the sbuild configuration, either appended to the defaults or merged with the
+    // defaults of the chroot
+    fn sbuild_flags(&self, sbuild_arch: &str, config: &Config, events: &mut Vec<BuildEvent>) -> io::Result<SbuildFlags> {
+        if ! config.options.merge_buildflags {
+            return Ok(SbuildFlags {
+                mode: "APPEND",
+                cflags: config.arch.cflags(),
+                cxxflags: config.arch.cxxflags(),
+            });
+        }
+        let chroot = format!("{}-{}-popopt", config.dist, sbuild_arch);
+        let (cflags, cflags_conflicts) = buildflags::merge(
+            &buildflags::query(&chroot, "CFLAGS")?,
+            &config.arch.cflags()
         );
-        let sbuild_conf_file = dir.join("sbuild.conf");
-        fs::write(&sbuild_conf_file, sbuild_conf)?;
+        let (cxxflags, cxxflags_conflicts) = buildflags::merge(
+            &buildflags::query(&chroot, "CXXFLAGS")?,
+            &config.arch.cxxflags()
+        );
+        for conflict in cflags_conflicts.iter().chain(cxxflags_conflicts.iter()) {
+            events.push(BuildEvent::FlagConflict {
+                sbuild_arch: sbuild_arch.to_string(),
+                conflict: conflict.clone(),
+            });
+        }
+        Ok(SbuildFlags {
+            mode: "SET",
+            cflags,
+            cxxflags,
+        })
+    }
+
+    // The sbuild command for an arch, run in dir, and the configuration it reads from
+    // dir/sbuild.conf through SBUILD_CONFIG. A selected profile is loaded by the configuration
+    // before the build environment, so the flags cannot be replaced by it
+    fn sbuild_command(&self, source_dsc: &Path, sbuild_arch: &str, arch_all: bool, config: &Config, dir: &Path, flags: &SbuildFlags) -> (process::Command, String) {
+        let mut sbuild_conf = String::new();
+        if let Some(profile) = &config.options.sbuild_profile {
+            sbuild_conf.push_str(&format!(
+r#"do '{}';
+die $@ if $@;
+
+"#,
+                profile.display()
+            ));
+        }
+
+        //TODO: can flags be passed as an array?
+        let mut build_environment = vec![
+            (format!("DEB_CFLAGS_{}", flags.mode), flags.cflags.join(" ")),
+            (format!("DEB_CXXFLAGS_{}", flags.mode), flags.cxxflags.join(" ")),
+            ("POP_OPT_ARCH".to_string(), config.arch.name.clone()),
+            ("RUSTFLAGS".to_string(), config.arch.rustflags_without(&self.rustflags_remove).join(" ")),
+        ];
+        if let Some(toolchain) = &config.options.rustup_toolchain {
+            build_environment.push(("RUSTUP_TOOLCHAIN".to_string(), toolchain.clone()));
+        }
+        sbuild_conf.push_str("$build_environment = {\n");
+        for (key, value) in build_environment.iter() {
+            sbuild_conf.push_str(&format!("    '{}' => '{}',\n", key, value));
+        }
+        sbuild_conf.push_str("};\n");
 
+        let mirror = archive_mirror(config.options.snapshot.as_deref(), config.options.mirror.as_deref());
         let mut command = process::Command::new("sbuild");
-        if sbuild_arch == "amd64" {
+        if arch_all {
             command.arg("--arch-all");
         } else {
             command.arg("--no-arch-all");
@@ -255,29 +1151,110 @@ r#"$build_environment = {{
             .arg(format!("--chroot={}-{}-popopt", config.dist, sbuild_arch))
             .arg(format!("--dist={}", config.dist))
             .arg(format!("--arch={}", sbuild_arch))
-            .arg(format!("--extra-repository=deb http://us.archive.ubuntu.com/ubuntu/ {}-updates main restricted universe multiverse", config.dist))
-            .arg(format!("--extra-repository=deb http://us.archive.ubuntu.com/ubuntu/ {}-security main restricted universe multiverse", config.dist))
-            .arg(&source_dsc)
-            .current_dir(&dir)
-            .env("SBUILD_CONFIG", &sbuild_conf_file);
+            .args(self.extra_build_depends.iter().map(|x| format!("--add-depends={}", x)))
+            .args(config.options.chroot_setup_commands.iter().map(|x| format!("--chroot-setup-commands={}", x)))
+            .arg(format!("--extra-repository=deb {} {}-updates main restricted universe multiverse", mirror, config.dist))
+            .arg(format!("--extra-repository=deb {} {}-security main restricted universe multiverse", mirror, config.dist))
+            .current_dir(dir)
+            .env("SBUILD_CONFIG", dir.join("sbuild.conf"));
+        if self.network {
+            command.arg("--enable-network");
+        }
+        if config.options.rustup_toolchain.is_some() {
+            // The toolchain itself must be installed by rustup in the chroot
+            command.arg("--add-depends=rustup");
+        }
+        command.arg(source_dsc);
+        (command, sbuild_conf)
+    }
 
-        Ok(thread::spawn(move || {
-            command
-                .status()
-                .and_then(status_err)?;
+    // Writes the sbuild configuration into dir, and links the source there when it is shared,
+    // returning the sbuild command and the binaries it must build
+    fn prepare_sbuild(&self, source_dsc: &Path, sbuild_arch: &str, arch_all: bool, config: &Config, dir: &Path, events: &mut Vec<BuildEvent>) -> io::Result<(process::Command, Vec<String>)> {
+        let flags = self.sbuild_flags(sbuild_arch, config, events)?;
 
-            fs::rename(&dir, &complete_dir)?;
+        let dsc = fs::read_to_string(source_dsc)?;
+        let dsc_paragraph = control::parse(&dsc).into_iter().next();
+        let expected = dsc_paragraph.as_ref()
+            .map_or_else(Vec::new, |x| expected_binaries(x, sbuild_arch, arch_all));
 
-            Ok(complete_dir)
-        }))
+        // sbuild still unpacks the source in each chroot session, as every build needs its own tree
+        let source_dsc = if config.options.shared_source {
+            let source_dir = source_dsc.parent().unwrap();
+            let files = dsc_paragraph.as_ref().map_or_else(Vec::new, |x| x.lines("Files"));
+            for file in files.iter().filter_map(|x| x.split_whitespace().last()) {
+                link_or_copy(source_dir.join(file), dir.join(file))?;
+            }
+            let local_dsc = dir.join(source_dsc.file_name().unwrap());
+            link_or_copy(source_dsc, &local_dsc)?;
+            local_dsc
+        } else {
+            source_dsc.to_path_buf()
+        };
+
+        let (command, sbuild_conf) = self.sbuild_command(&source_dsc, sbuild_arch, arch_all, config, dir, &flags);
+        fs::write(dir.join("sbuild.conf"), &sbuild_conf)?;
+        if config.options.dump_sbuild_conf {
+            events.push(BuildEvent::SbuildConf {
+                sbuild_arch: sbuild_arch.to_string(),
+                conf: sbuild_conf,
+            });
+        }
+        if self.network {
+            events.push(BuildEvent::Network(sbuild_arch.to_string()));
+        }
+        Ok((command, expected))
     }
 
-    pub fn build<P: AsRef<Path>>(&self, arch: &Arch, dist: &str, sbuild_archs: &[&str], dir: P) -> io::Result<Vec<thread::JoinHandle<io::Result<PathBuf>>>> {
-        let dir = dir.as_ref();
+    // The sbuild archs this package is built for, in the order given
+    pub fn sbuild_archs<'a>(&self, sbuild_archs: &[&'a str]) -> Vec<&'a str> {
+        sbuild_archs.iter()
+            .filter(|x| self.architectures.is_empty() || self.architectures.iter().any(|y| y == *x))
+            .copied()
+            .collect()
+    }
+
+    // Output of the sbuild of a version for an arch, with dir as passed to build
+    pub fn build_log<P: AsRef<Path>>(&self, dir: P, version: &str, sbuild_arch: &str) -> PathBuf {
+        dir.as_ref().join(version).join(format!("sbuild-{}.log", sbuild_arch))
+    }
+
+    // Prepares the source like build does, and creates a source-only .changes for uploading
+    pub fn source_changes<P: AsRef<Path>>(&self, arch: &Arch, dist: &str, version: &str, dir: P, options: &BuildOptions) -> Result<(PathBuf, Vec<BuildEvent>), Error> {
+        let version_dir = ensure_dir(dir.as_ref().join(version))?;
+        let config = Config {
+            arch,
+            dist,
+            version,
+            dir: &version_dir,
+            rebuild: false,
+            retry: false,
+            options,
+        };
+
+        let mut events = Vec::new();
+        let source_dsc = {
+            let _slot = options.jobs.as_ref().map(|x| x.acquire());
+            self.source(&config, &mut events)?
+        };
+        let source_dir = source_dsc.parent().unwrap();
+        let changes_file = source_dir.join(format!(
+            "{}_source.changes",
+            source_dsc.file_stem().unwrap().to_string_lossy()
+        ));
+        if ! changes_file.is_file() {
+            process::Command::new("dpkg-genchanges")
+                .arg("--build=source")
+                .arg(format!("-O{}", changes_file.display()))
+                .current_dir(source_dir.join("patched"))
+                .run()?;
+        }
 
-        println!("- Package {} in {}", self.name, dir.display());
+        Ok((changes_file, events))
+    }
 
-        // Get version of source
+    // Pool directory of a source version, from the archive's Sources
+    fn source_directory(&self, dist: &str, version: &str) -> io::Result<String> {
         let output = process::Command::new("schroot")
             //TODO: Use sbuild arch?
             .arg("--chroot").arg(format!("{}-amd64-popopt", dist))
@@ -288,61 +1265,844 @@ r#"$build_environment = {{
             .arg("showsrc")
             .arg("--only-source")
             .arg(&self.name)
-            .current_dir(&dir)
             .stdout(process::Stdio::piped())
             .spawn()?
             .wait_with_output()?;
-        status_err(output.status)?;
+        status_err("schroot", output.status)?;
+        let source = String::from_utf8_lossy(&output.stdout);
+        control::parse(&source).iter()
+            .find(|x| x.get("Version") == Some(version))
+            .and_then(|x| x.get("Directory"))
+            .map(|x| x.to_string())
+            .ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("failed to find pool directory of '{}' version '{}'", self.name, version)
+            ))
+    }
+
+    // Highest version of the source in the archive, or None if the archive does not have it
+    pub fn latest_version(&self, dist: &str) -> Result<Option<String>, Error> {
+        // A local source is built at the version of its changelog
+        if let Some(source_path) = self.source_path() {
+            let output = process::Command::new("dpkg-parsechangelog")
+                .arg("--file").arg(source_path.join("debian/changelog"))
+                .arg("--show-field").arg("Version")
+                .stdout(process::Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+            status_err("dpkg-parsechangelog", output.status)?;
+            return Ok(Some(String::from_utf8_lossy(&output.stdout).trim().to_string()));
+        }
+
+        let output = process::Command::new("schroot")
+            //TODO: Use sbuild arch?
+            .arg("--chroot").arg(format!("{}-amd64-popopt", dist))
+            .arg("--directory").arg("/root")
+            .arg("--user").arg("root")
+            .arg("--")
+            .arg("apt-cache")
+            .arg("showsrc")
+            .arg("--only-source")
+            .arg(&self.name)
+            .stdout(process::Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        status_err("schroot", output.status)?;
         let source = str::from_utf8(&output.stdout).map_err(|err| io::Error::new(
             io::ErrorKind::InvalidData,
             err
         ))?;
 
+        // apt-cache succeeds without output when the source is unknown
+        if source.trim().is_empty() {
+            return Ok(None);
+        }
+
         let packages = source_values(source, "Package")?;
         for package in packages.iter() {
             if &self.name != package {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("requested source '{}' does not match source '{}'", self.name, package)
-                ));
+                return Err(Error::SourceMismatch {
+                    requested: self.name.clone(),
+                    found: package.clone(),
+                });
             }
         }
 
         let versions = source_values(source, "Version")?;
         let mut version = &versions[0];
         for other_version in versions.iter() {
-            let status = process::Command::new("dpkg")
-                .arg("--compare-versions")
-                .arg(other_version)
-                .arg("gt")
-                .arg(version)
-                .status()?;
-            match status.code() {
-                Some(0) => version = other_version,
-                _ => (),
+            if compare_versions(other_version, "gt", version)? {
+                version = other_version;
             }
         }
 
-        let version_dir = ensure_dir(dir.join(&version))?;
-        println!("  - Version {} in {}", version, version_dir.display());
+        Ok(Some(version.clone()))
+    }
 
-        let config = Config {
+    // Everything a build of a version depends on: the source version, the content of each patch
+    // that applies, and the arch flags. Kept as text so the reason for a rebuild can be read
+    fn fingerprint(&self, arch: &Arch, dist: &str, version: &str, options: &BuildOptions) -> io::Result<String> {
+        let mut fingerprint = String::new();
+        fingerprint.push_str(&format!("version: {}\n", version));
+        if let Some(template) = &options.version_suffix {
+            fingerprint.push_str(&format!("version suffix: {}\n", version_suffix(template, arch)));
+        }
+        for patch in self.patches.iter() {
+            if patch.applies(dist, version)? {
+                fingerprint.push_str(&format!("patch: {} {}\n", patch.file(), sha256(self.patch_path(patch))?));
+            }
+        }
+        if let Some(changelog) = &self.changelog {
+            fingerprint.push_str(&format!("changelog: {}\n", changelog));
+        }
+        fingerprint.push_str(&format!("cflags: {}\n", arch.cflags().join(" ")));
+        fingerprint.push_str(&format!("cxxflags: {}\n", arch.cxxflags().join(" ")));
+        fingerprint.push_str(&format!("rustflags: {}\n", arch.rustflags_without(&self.rustflags_remove).join(" ")));
+        Ok(fingerprint)
+    }
+
+    // Whether the archive has newer binaries than the source version already built in version_dir
+    fn archive_binary_newer(&self, dist: &str, version: &str, version_dir: &Path) -> io::Result<bool> {
+        let mut names = Vec::new();
+        for entry_res in fs::read_dir(version_dir)? {
+            let path = entry_res?.path();
+            let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+            if ! file_name.starts_with("sbuild-") || file_name.ends_with(".partial") {
+                continue;
+            }
+            for deb_res in fs::read_dir(&path)? {
+                let deb_name = deb_res?.file_name().to_string_lossy().to_string();
+                if deb_name.ends_with(".deb") {
+                    let name = deb_name.split('_').next().unwrap_or("").to_string();
+                    if ! names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        if names.is_empty() {
+            return Ok(false);
+        }
+
+        let output = process::Command::new("schroot")
+            .arg("--chroot").arg(format!("{}-amd64-popopt", dist))
+            .arg("--directory").arg("/root")
+            .arg("--user").arg("root")
+            .arg("--")
+            .arg("apt-cache")
+            .arg("policy")
+            .args(&names)
+            .stdout(process::Stdio::piped())
+            .spawn()?
+            .wait_with_output()?;
+        status_err("schroot", output.status)?;
+        let policy = str::from_utf8(&output.stdout).map_err(|err| io::Error::new(
+            io::ErrorKind::InvalidData,
+            err
+        ))?;
+
+        binary_rebuild_needed(version, &policy_candidates(policy))
+    }
+
+    pub fn build<P: AsRef<Path>>(&self, arch: &Arch, dist: &str, version: &str, sbuild_archs: &[&str], dir: P, options: &BuildOptions) -> Result<StartedBuild, Error> {
+        let dir = dir.as_ref();
+        let version_dir = ensure_dir(dir.join(version))?;
+        let mut events = Vec::new();
+
+        let mut rebuild = options.newer_than_archive && self.archive_binary_newer(dist, version, &version_dir)?;
+        if rebuild {
+            events.push(BuildEvent::ArchiveNewer);
+        }
+
+        if options.only_source_changed && ! rebuild {
+            match fs::read_to_string(version_dir.join("fingerprint")) {
+                Ok(recorded) if recorded == self.fingerprint(arch, dist, version, options)? => {
+                    events.push(BuildEvent::Unchanged);
+                },
+                // Builds from before fingerprints were recorded are also rebuilt
+                _ => if version_dir.join("source").is_dir() {
+                    events.push(BuildEvent::Changed);
+                    rebuild = true;
+                },
+            }
+        }
+
+        let mut started = self.build_with(&BuildConfig {
             arch,
             dist,
-            version: &version,
+            version,
+            sbuild_archs,
+            dir,
+            rebuild,
+            retry: false,
+            options,
+        })?;
+        events.append(&mut started.events);
+
+        for sbuild_arch in sbuild_archs.iter() {
+            if self.sbuild_archs(sbuild_archs).contains(sbuild_arch) {
+                events.push(BuildEvent::Sbuild(sbuild_arch.to_string()));
+            } else {
+                events.push(BuildEvent::SkippedSbuild {
+                    sbuild_arch: sbuild_arch.to_string(),
+                    architectures: self.architectures.clone(),
+                });
+            }
+        }
+        started.events = events;
+
+        Ok(started)
+    }
+
+    // Prepares the source and starts an sbuild thread per arch, for use by other programs. Nothing
+    // is printed but the commands run, which go through log, and what happened is returned as
+    // events
+    pub fn build_with(&self, config: &BuildConfig) -> Result<StartedBuild, Error> {
+        let version_dir = ensure_dir(config.dir.join(config.version))?;
+        let source_config = Config {
+            arch: config.arch,
+            dist: config.dist,
+            version: config.version,
             dir: &version_dir,
+            rebuild: config.rebuild,
+            retry: config.retry,
+            options: config.options,
+        };
+
+        let mut events = Vec::new();
+        let source_dsc = {
+            let _slot = config.options.jobs.as_ref().map(|x| x.acquire());
+            self.source(&source_config, &mut events)?
+        };
+        // Recorded once the source is prepared, for comparison on the next build
+        write_file(
+            version_dir.join("fingerprint"),
+            self.fingerprint(config.arch, config.dist, config.version, config.options)?
+        )?;
+
+        let sbuild_archs = self.sbuild_archs(config.sbuild_archs);
+        let mut threads = Vec::new();
+        for sbuild_arch in sbuild_archs.iter() {
+            // Architecture independent packages are built with the first arch
+            let arch_all = Some(sbuild_arch) == sbuild_archs.first();
+            threads.push(self.sbuild_thread(&source_dsc, sbuild_arch, arch_all, &source_config, &mut events)?);
+        }
+
+        Ok(StartedBuild { threads, events })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditional(dist: Option<&str>, min_version: Option<&str>, max_version: Option<&str>) -> Patch {
+        Patch::Conditional {
+            file: "patch/test.patch".to_string(),
+            dist: dist.map(|x| x.to_string()),
+            min_version: min_version.map(|x| x.to_string()),
+            max_version: max_version.map(|x| x.to_string()),
+        }
+    }
+
+    #[test]
+    fn patch_applies_plain() {
+        let patch = Patch::Plain("patch/test.patch".to_string());
+        assert!(patch.applies("focal", "2.31-0ubuntu9").unwrap());
+        assert!(patch.applies("jammy", "1.0").unwrap());
+    }
+
+    #[test]
+    fn patch_applies_dist() {
+        let patch = conditional(Some("focal"), None, None);
+        assert!(patch.applies("focal", "2.31-0ubuntu9").unwrap());
+        assert!(! patch.applies("jammy", "2.31-0ubuntu9").unwrap());
+    }
+
+    #[test]
+    fn patch_applies_version_range() {
+        // min_version is inclusive and max_version is exclusive
+        let patch = conditional(None, Some("2.31"), Some("2.35"));
+        assert!(! patch.applies("focal", "2.30-0ubuntu2").unwrap());
+        assert!(patch.applies("focal", "2.31").unwrap());
+        assert!(patch.applies("focal", "2.31-0ubuntu9").unwrap());
+        assert!(patch.applies("focal", "2.35~rc1").unwrap());
+        assert!(! patch.applies("focal", "2.35").unwrap());
+        assert!(! patch.applies("focal", "2.35-0ubuntu3").unwrap());
+    }
+
+    #[test]
+    fn patch_applies_all_conditions() {
+        let patch = conditional(Some("jammy"), Some("2.35"), None);
+        assert!(patch.applies("jammy", "2.35-0ubuntu3").unwrap());
+        assert!(! patch.applies("focal", "2.35-0ubuntu3").unwrap());
+        assert!(! patch.applies("jammy", "2.31-0ubuntu9").unwrap());
+    }
+
+    const DSC: &str = "\
+Format: 3.0 (quilt)
+Source: zstd
+Binary: zstd, libzstd1, libzstd-dev, libzstd-doc
+Version: 1.4.8+dfsg-3
+Package-List:
+ libzstd-dev deb libdevel optional arch=any
+ libzstd-doc deb doc optional arch=all
+ libzstd1 deb libs optional arch=any
+ libzstd1-udeb udeb debian-installer optional arch=any profile=!noudeb
+ zstd deb utils optional arch=amd64,arm64
+";
+
+    fn write_changes(dir: &Path, debs: &[&str]) {
+        let mut changes = "Format: 1.8\nSource: zstd\nVersion: 1.4.8+dfsg-3popopt4\nFiles:\n".to_string();
+        for deb in debs.iter() {
+            changes.push_str(&format!(" 0123456789abcdef 1024 libs optional {}\n", deb));
+        }
+        fs::write(dir.join("zstd_1.4.8+dfsg-3popopt4_amd64.changes"), changes).unwrap();
+    }
+
+    #[test]
+    fn expected_binaries_by_arch() {
+        let dsc = control::parse(DSC).into_iter().next().unwrap();
+        // Profile restricted entries are never expected
+        assert_eq!(expected_binaries(&dsc, "amd64", true), vec!["libzstd-dev", "libzstd-doc", "libzstd1", "zstd"]);
+        assert_eq!(expected_binaries(&dsc, "amd64", false), vec!["libzstd-dev", "libzstd1", "zstd"]);
+        assert_eq!(expected_binaries(&dsc, "i386", false), vec!["libzstd-dev", "libzstd1"]);
+    }
+
+    #[test]
+    fn validate_changes_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        write_changes(dir.path(), &[
+            "libzstd-dev_1.4.8+dfsg-3popopt4_amd64.deb",
+            "libzstd1_1.4.8+dfsg-3popopt4_amd64.deb",
+            "zstd_1.4.8+dfsg-3popopt4_amd64.deb",
+            "zstd_1.4.8+dfsg-3popopt4.dsc",
+        ]);
+        let expected = vec!["libzstd-dev".to_string(), "libzstd1".to_string(), "zstd".to_string()];
+        assert!(validate_changes(dir.path(), &expected).is_ok());
+    }
+
+    #[test]
+    fn validate_changes_missing_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        write_changes(dir.path(), &[
+            "libzstd1_1.4.8+dfsg-3popopt4_amd64.deb",
+            "zstd_1.4.8+dfsg-3popopt4_amd64.deb",
+        ]);
+        let expected = vec!["libzstd-dev".to_string(), "libzstd1".to_string(), "zstd".to_string()];
+        let err = validate_changes(dir.path(), &expected).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with("is missing binaries: libzstd-dev"), "{}", err);
+    }
+
+    #[test]
+    fn validate_changes_extra_binary() {
+        // Binaries not in the Package-List, such as automatic debug symbols, are accepted
+        let dir = tempfile::tempdir().unwrap();
+        write_changes(dir.path(), &[
+            "libzstd1_1.4.8+dfsg-3popopt4_amd64.deb",
+            "libzstd1-dbgsym_1.4.8+dfsg-3popopt4_amd64.ddeb",
+            "zstd_1.4.8+dfsg-3popopt4_amd64.deb",
+            "zstd-extra_1.4.8+dfsg-3popopt4_amd64.deb",
+        ]);
+        let expected = vec!["libzstd1".to_string(), "zstd".to_string()];
+        assert!(validate_changes(dir.path(), &expected).is_ok());
+    }
+
+    #[test]
+    fn validate_changes_without_changes_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = validate_changes(dir.path(), &["zstd".to_string()]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    // apt-cache policy libzstd1 zstd libzstd-doc, where the last has no candidate
+    const POLICY: &str = "\
+libzstd1:
+  Installed: 1.4.8+dfsg-3build1
+  Candidate: 1.4.8+dfsg-3build1+b1
+  Version table:
+     1.4.8+dfsg-3build1+b1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+ *** 1.4.8+dfsg-3build1 100
+        100 /var/lib/dpkg/status
+zstd:
+  Installed: (none)
+  Candidate: 1.4.8+dfsg-3build1
+  Version table:
+     1.4.8+dfsg-3build1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/universe amd64 Packages
+libzstd-doc:
+  Installed: (none)
+  Candidate: (none)
+  Version table:
+";
+
+    #[test]
+    fn policy_candidates_by_name() {
+        assert_eq!(policy_candidates(POLICY), vec![
+            ("libzstd1".to_string(), "1.4.8+dfsg-3build1+b1".to_string()),
+            ("zstd".to_string(), "1.4.8+dfsg-3build1".to_string()),
+        ]);
+        assert!(policy_candidates("").is_empty());
+    }
+
+    #[test]
+    fn binary_rebuild_needed_for_binary_upload() {
+        let candidates = policy_candidates(POLICY);
+        assert!(binary_rebuild_needed("1.4.8+dfsg-3build1", &candidates).unwrap());
+    }
+
+    #[test]
+    fn binary_rebuild_not_needed() {
+        let candidates = policy_candidates(POLICY);
+        assert!(! binary_rebuild_needed("1.4.8+dfsg-3build1+b1", &candidates).unwrap());
+        assert!(! binary_rebuild_needed("1.4.8+dfsg-4", &candidates).unwrap());
+        assert!(! binary_rebuild_needed("1.4.8+dfsg-3build1", &[]).unwrap());
+    }
+
+    #[test]
+    fn share_dir_parse() {
+        let share_dir = ShareDir {
+            arch: "haswell".to_string(),
+            dist: "jammy".to_string(),
+            name: "gcc-9".to_string(),
+            version: "1:9.4.0-5ubuntu1".to_string(),
+        };
+        assert_eq!(share_dir.file_name(), "popopt_haswell_jammy_gcc-9_1:9.4.0-5ubuntu1");
+        assert_eq!(ShareDir::parse(&share_dir.file_name()), Some(share_dir));
+    }
+
+    #[test]
+    fn share_dir_parse_invalid() {
+        assert_eq!(ShareDir::parse("haswell_jammy_glibc_2.35-0ubuntu3"), None);
+        assert_eq!(ShareDir::parse("popopt_haswell_jammy_glibc"), None);
+        assert_eq!(ShareDir::parse("popopt_haswell_jammy_glibc_2.35_extra"), None);
+        assert_eq!(ShareDir::parse(""), None);
+    }
+
+    #[test]
+    fn partial_dir_tmpdir() {
+        let dir = Path::new("/srv/opt/build/haswell/jammy/glibc/2.35-0ubuntu3");
+        assert_eq!(partial_dir(dir, None, "source.partial"), dir.join("source.partial"));
+        assert_eq!(
+            partial_dir(dir, Some(Path::new("/tmp/popopt")), "source.partial"),
+            Path::new("/tmp/popopt/srv/opt/build/haswell/jammy/glibc/2.35-0ubuntu3/source.partial")
+        );
+    }
+
+    #[test]
+    fn load_all_duplicate_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkg_dir = dir.path().join("pkg");
+        fs::create_dir_all(pkg_dir.join("toolchain")).unwrap();
+        fs::write(pkg_dir.join("gcc-9.toml"), "name = \"gcc-9\"\n").unwrap();
+        fs::write(pkg_dir.join("glibc.toml"), "name = \"glibc\"\n").unwrap();
+        fs::write(pkg_dir.join("toolchain/gcc.toml"), "name = \"gcc-9\"\n").unwrap();
+
+        let err = Pkg::load_all(&pkg_dir).err().unwrap();
+        let message = err.to_string();
+        assert!(message.starts_with("duplicate package definitions: 'gcc-9' in "), "{}", message);
+        assert!(message.contains("gcc-9.toml") && message.contains("toolchain/gcc.toml"), "{}", message);
+        assert!(! message.contains("glibc"), "{}", message);
+    }
+
+    #[test]
+    fn load_all_unique_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkg_dir = dir.path().join("pkg");
+        fs::create_dir_all(&pkg_dir).unwrap();
+        fs::write(pkg_dir.join("gcc-9.toml"), "name = \"gcc-9\"\n").unwrap();
+        fs::write(pkg_dir.join("glibc.toml"), "name = \"glibc\"\n").unwrap();
+
+        let pkgs = Pkg::load_all(&pkg_dir).unwrap();
+        assert_eq!(pkgs.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["gcc-9", "glibc"]);
+        assert!(pkgs.iter().all(|x| x.root_dir == dir.path()));
+    }
+
+    fn haswell() -> Arch {
+        let mut arch: Arch = toml::from_str(r#"
+            level = 4
+            name = "haswell"
+            wiki = ""
+            features = []
+        "#).unwrap();
+        arch.family = "x86_64".to_string();
+        arch
+    }
+
+    #[test]
+    fn version_suffix_placeholders() {
+        let arch = haswell();
+        assert_eq!(version_suffix(DEFAULT_VERSION_SUFFIX, &arch), "popopt4");
+        assert_eq!(version_suffix("+opt{arch}{level}", &arch), "+opthaswell4");
+        assert_eq!(version_suffix("+opt", &arch), "+opt");
+    }
+
+    #[test]
+    fn suffixed_version_sorts_above() {
+        let arch = haswell();
+        for (version, template, expected) in [
+            ("2.35-0ubuntu3.1", DEFAULT_VERSION_SUFFIX, "2.35-0ubuntu3.1popopt4"),
+            ("1:1.2.11.dfsg-2ubuntu9", "+opt{arch}", "1:1.2.11.dfsg-2ubuntu9+opthaswell"),
+            ("3.10.6-1~22.04", "+v{level}", "3.10.6-1~22.04+v4"),
+        ] {
+            let new_version = suffixed_version(version, template, &arch).unwrap();
+            assert_eq!(new_version, expected);
+            assert!(compare_versions(&new_version, "gt", version).unwrap());
+            // A newer archive upload still replaces the rebuild
+            assert!(compare_versions(&new_version, "lt", &format!("{}.1", version)).unwrap());
+        }
+    }
+
+    #[test]
+    fn suffixed_version_errors() {
+        let arch = haswell();
+        // A tilde sorts before the end of the version, so apt would keep the archive version
+        let err = suffixed_version("1.0-1", "~opt{level}", &arch).unwrap_err();
+        assert!(err.to_string().contains("does not sort above"), "{}", err);
+        let err = suffixed_version("1.0-1", "_opt", &arch).unwrap_err();
+        assert!(err.to_string().contains("not a valid Debian version"), "{}", err);
+    }
+
+    #[test]
+    fn suffixed_version_already_suffixed() {
+        let arch = haswell();
+        // Rebuilding a rebuild, such as from an opt repository in the sources, is refused
+        let err = suffixed_version("2.35-0ubuntu3.1popopt4", DEFAULT_VERSION_SUFFIX, &arch).unwrap_err();
+        assert!(err.to_string().contains("already contains a popopt suffix"), "{}", err);
+        let err = suffixed_version("1.0-1+opthaswell", "+opt{arch}", &arch).unwrap_err();
+        assert!(err.to_string().contains("already contains a +opt suffix"), "{}", err);
+        // Templates starting with a placeholder have no marker
+        assert_eq!(suffixed_version("1.0-1", "{arch}", &arch).unwrap(), "1.0-1haswell");
+    }
+
+    #[test]
+    fn build_event_messages() {
+        assert_eq!(BuildEvent::SkippedPatch("patch/test.patch".to_string()).to_string(), "skipping patch patch/test.patch");
+        assert_eq!(
+            BuildEvent::SkippedSbuild {
+                sbuild_arch: "i386".to_string(),
+                architectures: vec!["amd64".to_string(), "arm64".to_string()],
+            }.to_string(),
+            "skipping sbuild i386: not in architectures amd64, arm64"
+        );
+    }
+
+    fn sbuild_config<'a>(arch: &'a Arch, dir: &'a Path, options: &'a BuildOptions) -> Config<'a> {
+        Config {
+            arch,
+            dist: "jammy",
+            version: "1.4.8+dfsg-3build1",
+            dir,
             rebuild: false,
             retry: false,
+            options,
+        }
+    }
+
+    fn append_flags(arch: &Arch) -> SbuildFlags {
+        SbuildFlags {
+            mode: "APPEND",
+            cflags: arch.cflags(),
+            cxxflags: arch.cxxflags(),
+        }
+    }
+
+    fn command_args(command: &process::Command) -> Vec<String> {
+        command.get_args().map(|x| x.to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn sbuild_command_profile() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let options = BuildOptions {
+            sbuild_profile: Some(PathBuf::from("/srv/opt/sbuild/site.conf")),
+            ..BuildOptions::default()
         };
+        let dir = Path::new("/srv/opt/build/sbuild-amd64.partial");
+        let (command, sbuild_conf) = pkg.sbuild_command(
+            Path::new("zstd.dsc"),
+            "amd64",
+            true,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        assert_eq!(command.get_program(), "sbuild");
+        assert_eq!(command.get_current_dir(), Some(dir));
+        // sbuild reads the generated configuration, which loads the profile first
+        let sbuild_config_env = command.get_envs()
+            .find(|(key, _)| *key == "SBUILD_CONFIG")
+            .and_then(|(_, value)| value);
+        assert_eq!(sbuild_config_env, Some(dir.join("sbuild.conf").as_os_str()));
+        assert!(sbuild_conf.starts_with("do '/srv/opt/sbuild/site.conf';\ndie $@ if $@;\n"), "{}", sbuild_conf);
+        let profile = sbuild_conf.find("do '").unwrap();
+        let environment = sbuild_conf.find("$build_environment = {").unwrap();
+        assert!(profile < environment);
+        assert!(sbuild_conf.contains("    'POP_OPT_ARCH' => 'haswell',\n"));
+        assert!(sbuild_conf.contains("    'DEB_CFLAGS_APPEND' => '"));
 
-        let source_dsc = self.source(&config)?;
+        let args = command_args(&command);
+        assert_eq!(&args[..6], &[
+            "--arch-all",
+            "--no-apt-distupgrade",
+            "--quiet",
+            "--chroot=jammy-amd64-popopt",
+            "--dist=jammy",
+            "--arch=amd64",
+        ]);
+        assert_eq!(args.last().unwrap(), "zstd.dsc");
+    }
 
-        let mut threads = Vec::new();
-        for sbuild_arch in sbuild_archs {
-            println!("    - sbuild {}", sbuild_arch);
-            threads.push(self.sbuild_thread(&source_dsc, sbuild_arch, &config)?);
+    #[test]
+    fn sbuild_command_without_profile() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let options = BuildOptions::default();
+        let dir = Path::new("/srv/opt/build/sbuild-i386.partial");
+        let (command, sbuild_conf) = pkg.sbuild_command(
+            Path::new("zstd.dsc"),
+            "i386",
+            false,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        assert!(sbuild_conf.starts_with("$build_environment = {\n"), "{}", sbuild_conf);
+        assert_eq!(command_args(&command)[0], "--no-arch-all");
+    }
+
+    #[test]
+    fn sbuild_command_extra_build_depends() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str(r#"
+            name = "firefox"
+            extra_build_depends = ["clang-14", "lld-14"]
+        "#).unwrap();
+        let options = BuildOptions::default();
+        let dir = Path::new("/srv/opt/build/sbuild-amd64.partial");
+        let (command, _) = pkg.sbuild_command(
+            Path::new("firefox.dsc"),
+            "amd64",
+            true,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        let args = command_args(&command);
+        let depends: Vec<&str> = args.iter()
+            .filter(|x| x.starts_with("--add-depends="))
+            .map(|x| x.as_str())
+            .collect();
+        assert_eq!(depends, vec!["--add-depends=clang-14", "--add-depends=lld-14"]);
+    }
+
+    #[test]
+    fn sbuild_command_network() {
+        let arch = haswell();
+        let options = BuildOptions::default();
+        let dir = Path::new("/srv/opt/build/sbuild-amd64.partial");
+        let config = sbuild_config(&arch, dir, &options);
+        let args = |pkg: &Pkg| command_args(&pkg.sbuild_command(
+            Path::new("rustc.dsc"),
+            "amd64",
+            true,
+            &config,
+            dir,
+            &append_flags(&arch)
+        ).0);
+
+        let pkg: Pkg = toml::from_str("name = \"rustc\"\nnetwork = true").unwrap();
+        assert_eq!(args(&pkg).iter().filter(|x| *x == "--enable-network").count(), 1);
+
+        let pkg: Pkg = toml::from_str("name = \"rustc\"").unwrap();
+        assert!(! args(&pkg).iter().any(|x| x == "--enable-network"));
+    }
+
+    #[test]
+    fn sbuild_command_rustup_toolchain() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"rustc\"").unwrap();
+        let dir = Path::new("/srv/opt/build/sbuild-amd64.partial");
+
+        let options = BuildOptions {
+            rustup_toolchain: Some("1.70.0".to_string()),
+            ..BuildOptions::default()
+        };
+        let (command, sbuild_conf) = pkg.sbuild_command(
+            Path::new("rustc.dsc"),
+            "amd64",
+            true,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        assert!(sbuild_conf.contains("    'RUSTUP_TOOLCHAIN' => '1.70.0',\n"), "{}", sbuild_conf);
+        let args = command_args(&command);
+        assert!(args.iter().any(|x| x == "--add-depends=rustup"));
+        // The source stays the last argument
+        assert_eq!(args.last().unwrap(), "rustc.dsc");
+
+        let options = BuildOptions::default();
+        let (command, sbuild_conf) = pkg.sbuild_command(
+            Path::new("rustc.dsc"),
+            "amd64",
+            true,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        assert!(! sbuild_conf.contains("RUSTUP_TOOLCHAIN"));
+        assert!(! command_args(&command).iter().any(|x| x == "--add-depends=rustup"));
+    }
+
+    #[test]
+    fn sbuild_command_chroot_setup_commands() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let options = BuildOptions {
+            chroot_setup_commands: vec![
+                "apt-get install --yes ccache".to_string(),
+                "echo done".to_string(),
+            ],
+            ..BuildOptions::default()
+        };
+        let dir = Path::new("/srv/opt/build/sbuild-amd64.partial");
+        let (command, _) = pkg.sbuild_command(
+            Path::new("zstd.dsc"),
+            "amd64",
+            true,
+            &sbuild_config(&arch, dir, &options),
+            dir,
+            &append_flags(&arch)
+        );
+        let args = command_args(&command);
+        let setup: Vec<&str> = args.iter()
+            .filter(|x| x.starts_with("--chroot-setup-commands="))
+            .map(|x| x.as_str())
+            .collect();
+        // Each command is its own argument, in order, so it is not split by a shell
+        assert_eq!(setup, vec![
+            "--chroot-setup-commands=apt-get install --yes ccache",
+            "--chroot-setup-commands=echo done",
+        ]);
+    }
+
+    #[test]
+    fn prepare_sbuild_dump_conf() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let source_dsc = tmp.path().join("zstd_1.4.8+dfsg-3popopt4.dsc");
+        fs::write(&source_dsc, DSC).unwrap();
+        let dir = tmp.path().join("sbuild-amd64.partial");
+        fs::create_dir(&dir).unwrap();
+
+        let options = BuildOptions {
+            dump_sbuild_conf: true,
+            ..BuildOptions::default()
+        };
+        let mut events = Vec::new();
+        let (_, expected) = pkg.prepare_sbuild(&source_dsc, "amd64", true, &sbuild_config(&arch, tmp.path(), &options), &dir, &mut events).unwrap();
+        assert_eq!(expected, vec!["libzstd-dev", "libzstd-doc", "libzstd1", "zstd"]);
+        // The dumped configuration is the one sbuild reads
+        assert_eq!(events, vec![BuildEvent::SbuildConf {
+            sbuild_arch: "amd64".to_string(),
+            conf: fs::read_to_string(dir.join("sbuild.conf")).unwrap(),
+        }]);
+        assert!(fs::read_to_string(dir.join("sbuild.conf")).unwrap().contains("'POP_OPT_ARCH' => 'haswell'"));
+
+        let options = BuildOptions::default();
+        let mut events = Vec::new();
+        pkg.prepare_sbuild(&source_dsc, "amd64", true, &sbuild_config(&arch, tmp.path(), &options), &dir, &mut events).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn prepare_sbuild_shared_source() {
+        use std::os::unix::fs::MetadataExt;
+
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let source_dir = tmp.path().join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let files = ["zstd_1.4.8+dfsg.orig.tar.xz", "zstd_1.4.8+dfsg-3popopt4.debian.tar.xz"];
+        let mut dsc = DSC.to_string();
+        dsc.push_str("Files:\n");
+        for file in files.iter() {
+            fs::write(source_dir.join(file), file).unwrap();
+            dsc.push_str(&format!(" 0123456789abcdef 1024 {}\n", file));
+        }
+        let source_dsc = source_dir.join("zstd_1.4.8+dfsg-3popopt4.dsc");
+        fs::write(&source_dsc, &dsc).unwrap();
+
+        let options = BuildOptions {
+            shared_source: true,
+            ..BuildOptions::default()
+        };
+        let config = sbuild_config(&arch, tmp.path(), &options);
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        for sbuild_arch in ["amd64", "i386"] {
+            let dir = tmp.path().join(format!("sbuild-{}.partial", sbuild_arch));
+            fs::create_dir(&dir).unwrap();
+            let (command, _) = pkg.prepare_sbuild(&source_dsc, sbuild_arch, sbuild_arch == "amd64", &config, &dir, &mut Vec::new()).unwrap();
+            // Every sbuild directory links the same files, and builds from its own dsc
+            for file in files.iter().chain(["zstd_1.4.8+dfsg-3popopt4.dsc"].iter()) {
+                assert_eq!(inode(&dir.join(file)), inode(&source_dir.join(file)), "{}", file);
+            }
+            assert_eq!(
+                command_args(&command).last().unwrap(),
+                &dir.join("zstd_1.4.8+dfsg-3popopt4.dsc").display().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn apt_source_command_auth() {
+        let arch = haswell();
+        let pkg: Pkg = toml::from_str("name = \"zstd\"").unwrap();
+        let dir = Path::new("/srv/opt/build/haswell/jammy/zstd/1.4.8+dfsg-3build1");
+        let share_name = "haswell_jammy_zstd_1.4.8+dfsg-3build1";
+
+        let options = BuildOptions::default();
+        let args = command_args(&pkg.apt_source_command(&sbuild_config(&arch, dir, &options), share_name));
+        assert_eq!(&args[5..], &["apt-get", "source", "--only-source", "--download-only", "zstd=1.4.8+dfsg-3build1"]);
+
+        // apt in the chroot reads the credentials copied into the share directory
+        let options = BuildOptions {
+            apt_auth: Some(PathBuf::from("/home/user/auth.conf")),
+            ..BuildOptions::default()
+        };
+        let command = pkg.apt_source_command(&sbuild_config(&arch, dir, &options), share_name);
+        assert_eq!(command.get_program(), "schroot");
+        assert_eq!(command_args(&command)[5..8], [
+            "apt-get",
+            "-o",
+            "Dir::Etc::netrc=/build/haswell_jammy_zstd_1.4.8+dfsg-3build1/auth.conf",
+        ]);
+    }
+
+    #[test]
+    fn load_all_sequential_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let pkg_dir = dir.path().join("pkg");
+        // Enough files for every parsing thread to get several, in groups and out of name order
+        for i in 0..64 {
+            let group = pkg_dir.join(format!("group-{}", i % 5));
+            fs::create_dir_all(&group).unwrap();
+            fs::write(group.join(format!("{:02}.toml", 63 - i)), format!("name = \"pkg-{}\"\n", i)).unwrap();
         }
 
-        Ok(threads)
+        let sequential: Vec<String> = definition_paths(&pkg_dir, "toml", true).unwrap()
+            .iter()
+            .map(|x| Pkg::load(x).unwrap().name)
+            .collect();
+        for _ in 0..4 {
+            let pkgs = Pkg::load_all(&pkg_dir).unwrap();
+            assert_eq!(pkgs.iter().map(|x| x.name.clone()).collect::<Vec<_>>(), sequential);
+        }
     }
 }
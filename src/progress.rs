@@ -0,0 +1,180 @@
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Whether the progress line is drawn on the terminal, where log lines would be appended to it
+static LINE_DRAWN: AtomicBool = AtomicBool::new(false);
+
+// Clears a drawn progress line, so a log line written next starts at the beginning of the line.
+// The progress line is drawn again on its next update
+pub fn clear_line<W: Write>(writer: &mut W) -> io::Result<()> {
+    if LINE_DRAWN.swap(false, Ordering::SeqCst) {
+        write!(writer, "\r\x1b[K")?;
+    }
+    Ok(())
+}
+
+// Tracks builds started and finished, rendering on a single line when stderr is a terminal
+pub struct Progress {
+    pub total: usize,
+    pub started: usize,
+    pub completed: usize,
+    pub failed: usize,
+    interactive: bool,
+    quiet: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Self {
+        Self {
+            total,
+            started: 0,
+            completed: 0,
+            failed: 0,
+            interactive: unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            quiet,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.started - self.completed
+    }
+
+    pub fn start(&mut self, count: usize) {
+        self.started += count;
+        self.render();
+    }
+
+    pub fn finish(&mut self, success: bool) {
+        self.completed += 1;
+        if ! success {
+            self.failed += 1;
+        }
+        self.render();
+    }
+
+    pub fn line(&self) -> String {
+        let width = 30;
+        let filled = (width * self.completed).checked_div(self.total).unwrap_or(width).min(width);
+        format!(
+            "[{}{}] {}/{} builds, {} in flight, {} failed",
+            "=".repeat(filled),
+            " ".repeat(width - filled),
+            self.completed,
+            self.total,
+            self.in_flight(),
+            self.failed
+        )
+    }
+
+    fn render(&self) {
+        if self.quiet {
+            return;
+        }
+
+        let _ = if self.interactive {
+            LINE_DRAWN.store(true, Ordering::SeqCst);
+            self.render_to(&mut io::stderr())
+        } else {
+            self.render_to(&mut io::stdout())
+        };
+    }
+
+    // Redraws the line in place on a terminal, or appends a line otherwise
+    fn render_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.interactive {
+            write!(writer, "\r\x1b[K{}", self.line())?;
+        } else {
+            writeln!(writer, "- progress: {}", self.line())?;
+        }
+        writer.flush()
+    }
+
+    pub fn done(&self) {
+        if self.interactive && ! self.quiet {
+            LINE_DRAWN.store(false, Ordering::SeqCst);
+            eprintln!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(total: usize, interactive: bool) -> Progress {
+        Progress {
+            interactive,
+            ..Progress::new(total, true)
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut progress = progress(4, false);
+        progress.start(3);
+        progress.finish(true);
+        progress.finish(false);
+        assert_eq!((progress.started, progress.completed, progress.failed), (3, 2, 1));
+        assert_eq!(progress.in_flight(), 1);
+    }
+
+    #[test]
+    fn line() {
+        let mut progress = progress(4, false);
+        assert_eq!(progress.line(), format!("[{}] 0/4 builds, 0 in flight, 0 failed", " ".repeat(30)));
+        progress.start(2);
+        progress.finish(true);
+        assert_eq!(
+            progress.line(),
+            format!("[{}{}] 1/4 builds, 1 in flight, 0 failed", "=".repeat(7), " ".repeat(23))
+        );
+        progress.start(2);
+        progress.finish(true);
+        progress.finish(false);
+        progress.finish(true);
+        assert_eq!(progress.line(), format!("[{}] 4/4 builds, 0 in flight, 1 failed", "=".repeat(30)));
+    }
+
+    #[test]
+    fn line_without_builds() {
+        assert_eq!(progress(0, false).line(), format!("[{}] 0/0 builds, 0 in flight, 0 failed", "=".repeat(30)));
+    }
+
+    #[test]
+    fn render_lines() {
+        let mut progress = progress(2, false);
+        progress.start(1);
+        let mut buffer = Vec::new();
+        progress.render_to(&mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            format!("- progress: [{}] 0/2 builds, 1 in flight, 0 failed\n", " ".repeat(30))
+        );
+    }
+
+    #[test]
+    fn render_terminal() {
+        let progress = progress(2, true);
+        let mut buffer = Vec::new();
+        progress.render_to(&mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            format!("\r\x1b[K[{}] 0/2 builds, 0 in flight, 0 failed", " ".repeat(30))
+        );
+    }
+
+    #[test]
+    fn clear_drawn_line() {
+        LINE_DRAWN.store(true, Ordering::SeqCst);
+        let mut buffer = Vec::new();
+        clear_line(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "\r\x1b[K");
+
+        // Nothing is drawn until the next update
+        let mut buffer = Vec::new();
+        clear_line(&mut buffer).unwrap();
+        assert!(buffer.is_empty());
+    }
+}
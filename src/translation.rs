@@ -0,0 +1,157 @@
+use crate::{
+    control,
+    ensure_dir,
+    status_err,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as FmtWrite,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+// Description as it appears in the index, which apt hashes to match translations
+fn raw_description(description: &str) -> String {
+    let mut raw = String::new();
+    for (i, line) in description.lines().enumerate() {
+        if i > 0 {
+            raw.push_str("\n ");
+        }
+        raw.push_str(line);
+    }
+    raw.push('\n');
+    raw
+}
+
+fn md5(data: &str) -> io::Result<String> {
+    let mut child = process::Command::new("md5sum")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(data.as_bytes())?;
+    let output = child.wait_with_output()?;
+    status_err("md5sum", output.status)?;
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(|x| x.to_string())
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            "md5sum produced no output"
+        ))
+}
+
+// Creates Translation-en contents from Packages indices, one entry per package and description
+pub fn translation(indices: &[String]) -> io::Result<String> {
+    let mut descriptions = BTreeMap::new();
+    for index in indices.iter() {
+        for paragraph in control::parse(index) {
+            let (package, description) = match (paragraph.get("Package"), paragraph.get("Description")) {
+                (Some(package), Some(description)) => (package, description),
+                _ => continue,
+            };
+            let raw = raw_description(description);
+            let md5 = match paragraph.get("Description-md5") {
+                Some(md5) => md5.to_string(),
+                None => md5(&raw)?,
+            };
+            descriptions.insert((package.to_string(), md5), raw);
+        }
+    }
+
+    let mut translation = String::new();
+    for ((package, md5), raw) in descriptions.iter() {
+        writeln!(translation, "Package: {}", package).unwrap();
+        writeln!(translation, "Description-md5: {}", md5).unwrap();
+        writeln!(translation, "Description-en: {}", raw).unwrap();
+    }
+    Ok(translation)
+}
+
+// Writes main/i18n/Translation-en from the binary Packages indices of a component
+pub fn write(comp_dir: &Path, packages_files: &[PathBuf]) -> io::Result<PathBuf> {
+    let mut indices = Vec::new();
+    for packages_file in packages_files.iter() {
+        indices.push(fs::read_to_string(packages_file)?);
+    }
+
+    let i18n_dir = ensure_dir(comp_dir.join("i18n"))?;
+    let translation_file = i18n_dir.join("Translation-en");
+    fs::write(&translation_file, translation(&indices)?)?;
+    Ok(translation_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMD64: &str = "\
+Package: zstd
+Architecture: amd64
+Version: 1.4.8+dfsg-3build1popopt4
+Description: fast lossless compression algorithm
+ Zstd, short for Zstandard, is a fast lossless compression algorithm.
+ .
+ This package contains the zstd command line tool.
+Description-md5: 3074197bbee2b1d1edec0603875f95e1
+
+Package: libzstd1
+Architecture: amd64
+Version: 1.4.8+dfsg-3build1popopt4
+Description: single line
+
+Package: libzstd-udeb
+Architecture: amd64
+Version: 1.4.8+dfsg-3build1popopt4
+";
+
+    const I386: &str = "\
+Package: libzstd1
+Architecture: i386
+Version: 1.4.8+dfsg-3build1popopt4
+Description: single line
+";
+
+    #[test]
+    fn raw_description_lines() {
+        assert_eq!(raw_description("single line"), "single line\n");
+        assert_eq!(raw_description("short\nlong\n.\nmore"), "short\n long\n .\n more\n");
+    }
+
+    #[test]
+    fn translation_known_md5() {
+        // Description-md5 from the index is used as is
+        let translation = translation(&[AMD64.to_string()]).unwrap();
+        assert!(translation.starts_with("\
+Package: libzstd1
+Description-md5: a17d45d7381ce24c5b1005bd27791671
+Description-en: single line
+
+Package: zstd
+Description-md5: 3074197bbee2b1d1edec0603875f95e1
+Description-en: fast lossless compression algorithm
+ Zstd, short for Zstandard, is a fast lossless compression algorithm.
+ .
+ This package contains the zstd command line tool.
+
+"), "{}", translation);
+    }
+
+    #[test]
+    fn translation_computed_md5() {
+        // Without Description-md5, the hash is computed as apt does
+        let index = AMD64.replace("Description-md5: 3074197bbee2b1d1edec0603875f95e1\n", "");
+        assert_eq!(translation(&[index]).unwrap(), translation(&[AMD64.to_string()]).unwrap());
+    }
+
+    #[test]
+    fn translation_deduplicated() {
+        // Packages of every arch share one entry, and ones without a description have none
+        let translation = translation(&[AMD64.to_string(), I386.to_string()]).unwrap();
+        assert_eq!(translation.matches("Package: libzstd1\n").count(), 1);
+        assert!(! translation.contains("libzstd-udeb"));
+        assert_eq!(super::translation(&[]).unwrap(), "");
+    }
+}
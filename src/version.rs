@@ -0,0 +1,250 @@
+// Debian version handling, following the Debian policy manual's version format
+
+use std::{
+    cmp::Ordering,
+    sync::atomic::{self, AtomicBool},
+};
+
+// Checks the [epoch:]upstream_version[-debian_revision] format
+pub fn is_valid(version: &str) -> bool {
+    let (epoch, rest) = match version.find(':') {
+        Some(colon) => (Some(&version[..colon]), &version[colon + 1..]),
+        None => (None, version),
+    };
+    if let Some(epoch) = epoch {
+        if epoch.is_empty() || ! epoch.chars().all(|x| x.is_ascii_digit()) {
+            return false;
+        }
+    }
+
+    let (upstream, revision) = match rest.rfind('-') {
+        Some(hyphen) => (&rest[..hyphen], Some(&rest[hyphen + 1..])),
+        None => (rest, None),
+    };
+    if let Some(revision) = revision {
+        if revision.is_empty() || ! revision.chars().all(|x| x.is_ascii_alphanumeric() || "+.~".contains(x)) {
+            return false;
+        }
+    }
+
+    upstream.starts_with(|x: char| x.is_ascii_digit())
+        && upstream.chars().all(|x| {
+            x.is_ascii_alphanumeric() || "+.~".contains(x) || (x == '-' && revision.is_some())
+        })
+}
+
+// Sort weight of a non-digit character, where ~ sorts before anything, even the end of a part
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+// Compares an upstream version or revision like dpkg's verrevcmp
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while ! a.is_empty() || ! b.is_empty() {
+        // Non-digit prefixes, character by character
+        while a.first().is_some_and(|x| ! x.is_ascii_digit()) || b.first().is_some_and(|x| ! x.is_ascii_digit()) {
+            let ordering = order(a.first().copied()).cmp(&order(b.first().copied()));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = &a[1..];
+            b = &b[1..];
+        }
+
+        // Digit runs numerically, without limits on their length
+        let a_len = a.iter().take_while(|x| x.is_ascii_digit()).count();
+        let b_len = b.iter().take_while(|x| x.is_ascii_digit()).count();
+        let a_digits = trim_zeros(&a[..a_len]);
+        let b_digits = trim_zeros(&b[..b_len]);
+        let ordering = a_digits.len().cmp(&b_digits.len()).then_with(|| a_digits.cmp(b_digits));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        a = &a[a_len..];
+        b = &b[b_len..];
+    }
+    Ordering::Equal
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|x| **x == b'0').count();
+    &digits[zeros..]
+}
+
+// Splits a version into epoch, upstream version, and revision
+fn split(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+// Compares Debian versions without dpkg, with the same ordering as dpkg --compare-versions
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split(a);
+    let (b_epoch, b_upstream, b_revision) = split(b);
+    a_epoch.cmp(&b_epoch)
+        .then_with(|| compare_part(a_upstream, b_upstream))
+        .then_with(|| compare_part(a_revision, b_revision))
+}
+
+// Evaluates a dpkg --compare-versions relation, such as lt or ge
+pub fn relation(a: &str, op: &str, b: &str) -> Option<bool> {
+    let ordering = compare(a, b);
+    match op {
+        "lt" | "<<" => Some(ordering == Ordering::Less),
+        "le" | "<=" => Some(ordering != Ordering::Greater),
+        "eq" | "=" => Some(ordering == Ordering::Equal),
+        "ne" => Some(ordering != Ordering::Equal),
+        "ge" | ">=" => Some(ordering != Ordering::Less),
+        "gt" | ">>" => Some(ordering == Ordering::Greater),
+        _ => None,
+    }
+}
+
+// Compare versions natively instead of with dpkg, such as on hosts without dpkg
+static NATIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_native(native: bool) {
+    NATIVE.store(native, atomic::Ordering::SeqCst);
+}
+
+pub fn native() -> bool {
+    NATIVE.load(atomic::Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        for version in [
+            "1",
+            "1.0",
+            "0:1.0",
+            "1:2.31-0ubuntu9",
+            "2.35-0ubuntu3.1",
+            "1.4.8+dfsg-3build1+b1",
+            "1.0~rc1+dfsg-0ubuntu1",
+            // Hyphens are allowed in the upstream version when there is a revision
+            "1.0-beta-1",
+            "9.4.0-1ubuntu1~20.04.1",
+        ].iter() {
+            assert!(is_valid(version), "{}", version);
+        }
+    }
+
+    #[test]
+    fn invalid_epoch() {
+        for version in ["x:1.0", ":1.0", "1.0:1", "-1:1.0"].iter() {
+            assert!(! is_valid(version), "{}", version);
+        }
+    }
+
+    #[test]
+    fn invalid_upstream() {
+        for version in ["", "a1.0", "~1.0", "1:a1.0", "-1", "1.0_1", "1.0 1", "1.0/1"].iter() {
+            assert!(! is_valid(version), "{}", version);
+        }
+    }
+
+    #[test]
+    fn invalid_revision() {
+        for version in ["1.0-", "1.0-@", "1.0-1_2", "1.0-1:2"].iter() {
+            assert!(! is_valid(version), "{}", version);
+        }
+    }
+
+    // Results of dpkg --compare-versions for each pair
+    const DPKG_RESULTS: &[(&str, &str, Ordering)] = &[
+        // Epochs, where a missing epoch is 0
+        ("1:0", "2", Ordering::Greater),
+        ("1:1.0", "1.0", Ordering::Greater),
+        ("0:1.0", "1.0", Ordering::Equal),
+        ("2:0.1", "1:9.9", Ordering::Greater),
+        // Tilde sorts before anything, even the end of the version
+        ("1.0~rc1", "1.0", Ordering::Less),
+        ("1.0~~", "1.0~", Ordering::Less),
+        ("1.0~~a", "1.0~~", Ordering::Greater),
+        ("1.0~", "1.0", Ordering::Less),
+        ("1.0~rc1", "1.0~rc2", Ordering::Less),
+        ("1.0-1~bpo1", "1.0-1", Ordering::Less),
+        ("9.4.0-1ubuntu1~20.04.1", "9.4.0-1ubuntu1~20.04.2", Ordering::Less),
+        // Binary rebuilds and opt suffixes
+        ("1.0-1", "1.0-1+b1", Ordering::Less),
+        ("1.0-1+b1", "1.0-1+b2", Ordering::Less),
+        ("1.0-1+b1", "1.0-2", Ordering::Less),
+        ("2.35-0ubuntu3.1", "2.35-0ubuntu3.1popopt4", Ordering::Less),
+        ("2.35-0ubuntu3.1popopt4", "2.35-0ubuntu3.2", Ordering::Less),
+        // Digit runs compare numerically, ignoring leading zeros
+        ("1.01", "1.1", Ordering::Equal),
+        ("1.001", "1.1", Ordering::Equal),
+        ("1.0010", "1.10", Ordering::Equal),
+        ("01", "1", Ordering::Equal),
+        ("2.0", "10.0", Ordering::Less),
+        ("1.10", "1.9", Ordering::Greater),
+        ("1.0.0", "1.0", Ordering::Greater),
+        ("1.2.3", "1.2.3", Ordering::Equal),
+        // Letters sort before non-letters, and anything but tilde after the end
+        ("1.0a", "1.0+", Ordering::Less),
+        ("1.0a", "1.0.", Ordering::Less),
+        ("1.0Z", "1.0a", Ordering::Less),
+        ("1.0a", "1.0", Ordering::Greater),
+        ("1.0+", "1.0", Ordering::Greater),
+        // A missing revision is equal to 0 and lower than any other
+        ("1.0", "1.0-0", Ordering::Equal),
+        ("1.0-0", "1.0-1", Ordering::Less),
+        ("1.0", "1.0-1", Ordering::Less),
+        ("1.0-0ubuntu1", "1.0", Ordering::Greater),
+    ];
+
+    #[test]
+    fn compare_dpkg_results() {
+        for (a, b, ordering) in DPKG_RESULTS.iter() {
+            assert_eq!(compare(a, b), *ordering, "{} {}", a, b);
+            assert_eq!(compare(b, a), ordering.reverse(), "{} {}", b, a);
+        }
+    }
+
+    #[test]
+    fn compare_installed_dpkg() {
+        // Checks the recorded results where dpkg is available
+        for (a, b, ordering) in DPKG_RESULTS.iter() {
+            let op = match ordering {
+                Ordering::Less => "lt",
+                Ordering::Equal => "eq",
+                Ordering::Greater => "gt",
+            };
+            match std::process::Command::new("dpkg").arg("--compare-versions").arg(a).arg(op).arg(b).status() {
+                Ok(status) => assert!(status.success(), "dpkg: {} {} {}", a, op, b),
+                Err(_) => return,
+            }
+        }
+    }
+
+    #[test]
+    fn relation_ops() {
+        for op in ["lt", "<<", "le", "<=", "ne"].iter() {
+            assert_eq!(relation("1.0~rc1", op, "1.0"), Some(true), "{}", op);
+        }
+        for op in ["eq", "=", "ge", ">=", "gt", ">>"].iter() {
+            assert_eq!(relation("1.0~rc1", op, "1.0"), Some(false), "{}", op);
+        }
+        assert_eq!(relation("1.01", "eq", "1.1"), Some(true));
+        assert_eq!(relation("1.01", "le", "1.1"), Some(true));
+        assert_eq!(relation("1.01", "ge", "1.1"), Some(true));
+        assert_eq!(relation("1.0", "lt-nl", "1.1"), None);
+    }
+}